@@ -1,249 +1,1153 @@
 //logging
-use log::{debug, error, info, trace, warn, LevelFilter, SetLoggerError};
-use std::future::Future;
+use log::{debug, error, warn};
+use std::{error::Error, fmt, fs, future::Future, path::{Path, PathBuf}, sync::Arc, time::Duration};
 
-use serde::{Deserialize,Serialize};
+use rand::Rng;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::app::{Message, MessageType};
+use crate::app::{Message, MessageType, SYSTEM_SENDER};
+use crate::cassette::{Cassette, RecordedRequest};
+use crate::config::Config;
+use crate::params::Params;
+use crate::provider::{self, Completion, CompletionRequest, ModelInfo, Provider};
 
 
-#[derive(Deserialize, Debug)]
-pub struct Choices {
-    text: String,
-    // index: i32,
-    // logprobs: Option<String>,
-    // finish_reason: String
+///Controls how failed requests are retried
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    ///How many times a request is retried before giving up
+    pub max_retries: u32,
+    ///Delay before the first retry, in milliseconds
+    pub initial_backoff_ms: u64,
+    ///Upper bound for a single delay, in milliseconds
+    pub max_backoff_ms: u64,
+    ///Factor applied to the delay after every failed attempt
+    pub multiplier: f64,
+    ///Randomize delays so clients don't retry in lockstep
+    pub jitter: bool,
 }
-#[derive(Deserialize, Debug)]
-pub struct Usage {
-    // prompt_tokens : i32,
-    // completion_tokens: i32,
-    // total_tokens: i32
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
 }
-#[derive(Deserialize,Debug)]
-pub struct ApiResponse {
-    // id: String,
-    // object: String,
-    // created: i32,
-    model: String,
-    choices: Vec<Choices>,
-    // usage: Usage,
+
+impl RetryPolicy {
+    ///Delay to wait before retry number `attempt` (starting at 0)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff_ms as f64
+            * self.multiplier.powi(attempt as i32);
+        let capped = exponential.min(self.max_backoff_ms as f64);
+
+        let millis = if self.jitter {
+            rand::thread_rng().gen_range(capped / 2.0..=capped)
+        } else {
+            capped
+        };
+
+        return Duration::from_millis(millis as u64)
+    }
+
+    ///Longest a single retry waits, whatever the server asks for
+    pub fn max_delay(&self) -> Duration {
+        return Duration::from_millis(self.max_backoff_ms)
+    }
 }
 
 
-#[derive(Serialize,Deserialize,Debug)]
-pub struct ApiCall {
-    model: String,
-    prompt: String,
-    temperature: u8,
-    max_tokens: i32,
+///Limits on how long a request may take, 0 disables a limit
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Timeouts {
+    ///Time allowed to establish the connection, in milliseconds
+    pub connect_ms: u64,
+    ///Time allowed between two chunks of the response body, in milliseconds
+    pub read_ms: u64,
+    ///Time allowed for the whole request, in milliseconds
+    pub total_ms: u64,
 }
 
-impl ApiCall {
-    fn from(
-        model: String,
-        prompt: String,
-        temperature: u8,
-        max_tokens: i32
-    ) -> ApiCall {
-        return ApiCall {
-            model,
-            prompt,
-            temperature,
-            max_tokens
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect_ms: 10_000,
+            read_ms: 60_000,
+            total_ms: 300_000,
         }
-        
     }
+}
 
-    fn get_prompt(&self) -> String {
-        return self.prompt.clone()
+impl Timeouts {
+    fn limit(millis: u64) -> Option<Duration> {
+        if millis == 0 {
+            return None
+        }
+        return Some(Duration::from_millis(millis))
     }
-    fn get_model(&self) -> String {
-        return self.model.clone();
+
+    pub fn connect(&self) -> Option<Duration> {
+        return Timeouts::limit(self.connect_ms)
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        return Timeouts::limit(self.read_ms)
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        return Timeouts::limit(self.total_ms)
     }
 }
 
-pub struct ApiHandler {
-    client: reqwest::Client,
-    token: String,
-    pub call: Option<ApiCall>,
-    pub response: Option<ApiResponse>
+
+///Proxy and TLS settings for networks that need them
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Network {
+    ///Proxy used for every request, HTTPS_PROXY/HTTP_PROXY apply when unset
+    pub proxy: Option<String>,
+    ///Comma separated hosts that skip `proxy`, NO_PROXY applies when unset
+    pub no_proxy: Option<String>,
+    ///PEM file with extra root certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    ///Whether the system root certificates are trusted as well
+    pub builtin_roots: bool,
+    ///PEM certificate presented to the server, needs `client_key`
+    pub client_cert: Option<PathBuf>,
+    ///PKCS#8 PEM key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    ///Oldest TLS version accepted, "1.0" to "1.3"
+    pub min_tls_version: Option<String>,
 }
-impl ApiHandler {
-    pub fn new(token:String) -> ApiHandler {
-        return ApiHandler {
-            client: reqwest::Client::new(),
-            token,
-            call: None,
-            response: None
+
+impl Default for Network {
+    fn default() -> Network {
+        Network {
+            proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
+            builtin_roots: true,
+            client_cert: None,
+            client_key: None,
+            min_tls_version: None,
         }
     }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(contents) => return Ok(contents),
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err).into()),
+    }
+}
 
+///Every certificate of a PEM bundle, reqwest only parses the first one
+fn read_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>, Box<dyn Error>> {
+    let pem = String::from_utf8(read_file(path)?)?;
+    let begin = "-----BEGIN CERTIFICATE-----";
 
-    pub(crate) fn update_call(&mut self,call: ApiCall) {
-        self.call = Some(call);
+    let mut certificates = Vec::new();
+    for block in pem.split_inclusive("-----END CERTIFICATE-----") {
+        if let Some(start) = block.find(begin) {
+            certificates.push(reqwest::Certificate::from_pem(&block.as_bytes()[start..])?);
+        }
     }
 
-    async fn send_dummy_api_reqwest(
-        &self,
-        call_params: &ApiCall,
+    if certificates.is_empty() {
+        return Err(format!("no certificate found in {}", path.display()).into())
+    }
+    return Ok(certificates)
+}
 
-    ) -> Result<ApiResponse, reqwest::Error> {
-        let res = self.client.get(
-            format!("http://127.0.0.1:7878/{}",call_params.get_prompt())
-        )
-            .send()
-            .await;
-        
-        match res {
-            Ok(okay) => {
-                let debug_json = format!("{:#?}",okay);
-                let res = okay
-                    .json::<ApiResponse>();
-
-                let json_res = res.await;
-
-                match json_res {
-                    Ok(okay) => return Ok(okay),
-                    Err(err) => {
-                        error!("Couldn't parse received request");
-                        debug!("RECEIVED: {}",debug_json);
-                        return Err(err)
-                    }
-                    
-                }
+fn parse_tls_version(version: &str) -> Result<reqwest::tls::Version, Box<dyn Error>> {
+    match version.trim() {
+        "1.0" => return Ok(reqwest::tls::Version::TLS_1_0),
+        "1.1" => return Ok(reqwest::tls::Version::TLS_1_1),
+        "1.2" => return Ok(reqwest::tls::Version::TLS_1_2),
+        "1.3" => return Ok(reqwest::tls::Version::TLS_1_3),
+        other => return Err(format!("unknown TLS version {}", other).into()),
+    }
+}
+
+///Builds the http client from the timeouts and network settings
+fn build_client(config: &Config) -> Result<reqwest::Client, Box<dyn Error>> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(connect) = config.timeouts.connect() {
+        builder = builder.connect_timeout(connect);
+    }
+    if let Some(total) = config.timeouts.total() {
+        builder = builder.timeout(total);
+    }
+
+    let network = &config.network;
+
+    //without an explicit proxy reqwest picks up the environment variables
+    if let Some(url) = &network.proxy {
+        let no_proxy = match &network.no_proxy {
+            Some(hosts) => reqwest::NoProxy::from_string(hosts),
+            None => reqwest::NoProxy::from_env(),
+        };
+        builder = builder.proxy(reqwest::Proxy::all(url.as_str())?.no_proxy(no_proxy));
+    }
+
+    if let Some(path) = &network.ca_bundle {
+        for certificate in read_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder = builder.tls_built_in_root_certs(network.builtin_roots);
+
+    match (&network.client_cert, &network.client_key) {
+        (Some(cert), Some(key)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(&read_file(cert)?, &read_file(key)?)?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err("client_cert and client_key have to be set together".into()),
+    }
+
+    if let Some(version) = &network.min_tls_version {
+        builder = builder.min_tls_version(parse_tls_version(version)?);
+    }
+
+    return Ok(builder.build()?)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutKind {
+    Connect,
+    Read,
+    Total,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeoutKind::Connect => write!(f, "connecting"),
+            TimeoutKind::Read => write!(f, "waiting for data"),
+            TimeoutKind::Total => write!(f, "waiting for the answer"),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ApiError {
+    ///The request didn't get a response at all
+    Request(reqwest::Error),
+    ///One of the configured timeouts ran out
+    Timeout {
+        kind: TimeoutKind,
+        after: Option<Duration>,
+    },
+    ///The server answered with a non-success status code
+    Status {
+        status: reqwest::StatusCode,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    ///The response body couldn't be parsed
+    Parse(serde_json::Error),
+    ///The provider has no way to do what was asked
+    Unsupported(&'static str),
+    ///Recording or replaying went wrong
+    Cassette(String),
+}
+
+impl ApiError {
+    ///Whether sending the same request again might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Request(err) => err.is_connect(),
+            //the whole budget was spent, trying again won't be faster
+            ApiError::Timeout { kind, .. } => *kind != TimeoutKind::Total,
+            ApiError::Status { status, .. } => {
+                status.as_u16() == 429
+                    || status.as_u16() == 408
+                    || status.is_server_error()
             }
-            Err(err) => {
-                error!("Couldn't get api response");
-                return Err(err)
+            ApiError::Parse(_) | ApiError::Unsupported(_) | ApiError::Cassette(_) => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Request(err) => write!(f, "request failed: {}", err),
+            ApiError::Timeout { kind, after: Some(after) } => {
+                write!(f, "timed out {} after {}s", kind, after.as_secs_f32())
+            }
+            ApiError::Timeout { kind, after: None } => write!(f, "timed out {}", kind),
+            ApiError::Status { status, body, .. } => {
+                write!(f, "server answered {}: {}", status, body.trim())
             }
+            ApiError::Parse(err) => write!(f, "couldn't parse response: {}", err),
+            ApiError::Unsupported(what) => write!(f, "{} isn't supported by this provider", what),
+            ApiError::Cassette(err) => write!(f, "cassette: {}", err),
         }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+
+///Progress notifications sent while a request is in flight
+#[derive(Debug)]
+pub enum ApiEvent {
+    Retrying {
+        attempt: u32,
+        max_retries: u32,
+        delay: Duration,
+        reason: String,
+    },
+    ///A piece of a streamed answer
+    Chunk(String),
+}
+
+impl fmt::Display for ApiEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiEvent::Retrying { attempt, max_retries, delay, reason } => write!(
+                f,
+                "{} - retrying in {:.1}s ({}/{})",
+                reason,
+                delay.as_secs_f32(),
+                attempt,
+                max_retries
+            ),
+            ApiEvent::Chunk(_) => write!(f, "receiving answer..."),
+        }
+    }
+}
+
+///Sorts out timeouts from other reqwest errors
+fn request_error(err: reqwest::Error, timeouts: &Timeouts, total: Option<Duration>) -> ApiError {
+    if !err.is_timeout() {
+        return ApiError::Request(err)
+    }
+
+    if err.is_connect() {
+        return ApiError::Timeout { kind: TimeoutKind::Connect, after: timeouts.connect() }
+    }
+    return ApiError::Timeout { kind: TimeoutKind::Total, after: total }
+}
+
+///Reads a `Retry-After` header, given either in seconds or as an HTTP date
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds))
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    return Some(
+        date.duration_since(std::time::SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    )
+}
+
+
+///Http client shared by the providers, retrying transient failures
+///and enforcing the read timeout
+pub struct Transport {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    events: Option<UnboundedSender<ApiEvent>>,
+    ///Records or replays requests, see `[cassette]`
+    cassette: Option<Cassette>,
+}
 
+impl Transport {
+    pub fn new(config: &Config) -> Result<Transport, Box<dyn Error>> {
+        return Ok(Transport {
+            client: build_client(config)?,
+            retry: config.retry.clone(),
+            timeouts: config.timeouts.clone(),
+            events: None,
+            cassette: Cassette::open(&config.cassette)?,
+        })
     }
-    //this function works, that's very nice
-    async fn send_api_reqwest(
+
+    pub fn client(&self) -> &reqwest::Client {
+        return &self.client
+    }
+
+    ///Total timeout of a request, `timeout` overrides the configured one
+    pub fn total_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        return timeout.or(self.timeouts.total())
+    }
+
+    ///Where progress notifications (retries, streamed text...) are sent
+    pub fn set_event_sender(&mut self, events: UnboundedSender<ApiEvent>) {
+        self.events = Some(events);
+    }
+
+    pub fn notify(&self, event: ApiEvent) {
+        if let Some(events) = &self.events {
+            //nobody listening is fine
+            let _ = events.send(event);
+        }
+    }
+
+    ///Next piece of the body, None once it is complete.
+    ///Gives up when the server stays silent for longer than the read timeout
+    pub async fn next_chunk(
         &self,
-        call_params: &ApiCall,
+        response: &mut reqwest::Response,
+        total: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, ApiError> {
+        let chunk = match self.timeouts.read() {
+            Some(limit) => match tokio::time::timeout(limit, response.chunk()).await {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    return Err(ApiError::Timeout { kind: TimeoutKind::Read, after: Some(limit) })
+                }
+            },
+            None => response.chunk().await,
+        };
 
-    ) -> Result<ApiResponse, reqwest::Error> {
+        match chunk {
+            Ok(bytes) => return Ok(bytes.map(|bytes| bytes.to_vec())),
+            Err(err) => return Err(request_error(err, &self.timeouts, total)),
+        }
+    }
 
+    async fn read_body(
+        &self,
+        mut response: reqwest::Response,
+        total: Option<Duration>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let mut body = Vec::new();
+        while let Some(bytes) = self.next_chunk(&mut response, total).await? {
+            body.extend_from_slice(&bytes);
+        }
+        return Ok(body)
+    }
 
-        let call = ApiCall {
-            model: "text-davinci-003".to_string(),
-            prompt: "Say this is a test".to_string(),
-            temperature: 0,
-            max_tokens: 7    
+    ///Sends a request once, or answers it from the cassette
+    async fn fetch(
+        &self,
+        request: reqwest::RequestBuilder,
+        total: Option<Duration>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let request = match request.build() {
+            Ok(request) => request,
+            Err(err) => return Err(ApiError::Request(err)),
         };
-        // curl https://api.openai.com/v1/completions \
-        // -H "Content-Type: application/json" \
-        // -H "Authorization: Bearer YOUR_API_KEY" \
-        // -d '{"model": "text-davinci-003", "prompt": "Say this is a test", "temperature": 0, "max_tokens": 7}'
-        let res = self.client.post(
-            "https://api.openai.com/v1/completions"
-        )
-        .bearer_auth(&self.token)
-        .header("Content-Type", "application/json")
-        .json(call_params)
-        .send()
-        .await;
-
-        match res {
-            Ok(okay) => {
-                let debug_json = format!("{:#?}",okay);
-                let res = okay
-                    .json::<ApiResponse>();
-
-                let json_res = res.await;
-
-                match json_res {
-                    Ok(res) => return Ok(res),
-                    Err(err) => {
-                        error!("Couldn't parse received request");
-                        debug!("RECEIVED: {}",debug_json);
-                        return Err(err)
-                    }
-                    
-                }
-            }
+
+        let cassette = match &self.cassette {
+            Some(cassette) if cassette.replaying() => return cassette.replay(&request),
+            cassette => cassette,
+        };
+        let recorded = cassette.as_ref().map(|_| RecordedRequest::from(&request));
+
+        let response = match self.client.execute(request).await {
+            Ok(response) => response,
             Err(err) => {
                 error!("Couldn't get api response");
-                return Err(err)
+                return Err(request_error(err, &self.timeouts, total))
             }
+        };
+
+        match (cassette, recorded) {
+            (Some(cassette), Some(recorded)) => {
+                let status = response.status();
+                let headers = response.headers().clone();
+                let body = self.read_body(response, total).await?;
+                return cassette.record(recorded, status, &headers, &body)
+            }
+            _ => return Ok(response),
         }
     }
 
+    ///Sends a request once, non-success answers become errors
+    async fn open(
+        &self,
+        request: reqwest::RequestBuilder,
+        total: Option<Duration>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let okay = self.fetch(request, total).await?;
 
+        let status = okay.status();
+        if !status.is_success() {
+            let retry_after = parse_retry_after(okay.headers());
+            let body = self.read_body(okay, total).await?;
+            let body = String::from_utf8_lossy(&body).to_string();
+            error!("API answered with status {}", status);
+            debug!("RECEIVED: {}", body);
+            return Err(ApiError::Status { status, retry_after, body })
+        }
+        return Ok(okay)
+    }
 
+    ///Runs `attempt` until it succeeds, fails for good
+    ///or the retry policy runs out
+    pub(crate) async fn retry<T, F, Fut>(&self, attempt: F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Err(err) if err.is_transient() && retries < self.retry.max_retries => {
+                    //a server asking for a day doesn't hold the request that long
+                    let delay = err
+                        .retry_after()
+                        .map(|retry_after| retry_after.min(self.retry.max_delay()))
+                        .unwrap_or_else(|| self.retry.backoff(retries));
+                    retries += 1;
+
+                    warn!("{}, retrying in {:?} ({}/{})",
+                        err, delay, retries, self.retry.max_retries);
+                    self.notify(ApiEvent::Retrying {
+                        attempt: retries,
+                        max_retries: self.retry.max_retries,
+                        delay,
+                        reason: err.to_string(),
+                    });
+
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn with_timeout(
+        request: reqwest::RequestBuilder,
+        timeout: Option<Duration>,
+    ) -> reqwest::RequestBuilder {
+        match timeout {
+            Some(timeout) => return request.timeout(timeout),
+            None => return request,
+        }
+    }
+
+    ///Sends the request built by `build_request` and reads the whole body,
+    ///retrying transient failures according to the retry policy.
+    ///`timeout` replaces the configured total timeout for this request only
+    pub async fn send_with_retry<F>(
+        &self,
+        build_request: F,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, ApiError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let total = self.total_timeout(timeout);
+        let build_request = &build_request;
+        return self.retry(|| async move {
+            let response = self.open(
+                Transport::with_timeout(build_request(), timeout),
+                total
+            ).await?;
+            self.read_body(response, total).await
+        }).await
+    }
+
+    ///Like `send_with_retry` but hands the response over as soon as it
+    ///starts, for bodies read piece by piece with `next_chunk`
+    pub async fn open_with_retry<F>(
+        &self,
+        build_request: F,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Response, ApiError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let total = self.total_timeout(timeout);
+        let build_request = &build_request;
+        return self.retry(|| async move {
+            self.open(Transport::with_timeout(build_request(), timeout), total).await
+        }).await
+    }
+}
+
+
+pub struct ApiHandler {
+    transport: Transport,
+    provider: Arc<dyn Provider>,
+    ///Name the provider was picked by
+    provider_name: String,
+    ///Whether answers are streamed
+    stream: bool,
+    pub request: Option<CompletionRequest>,
+    pub response: Option<Completion>
+}
+impl ApiHandler {
+    pub fn new(token:String, config: &Config) -> Result<ApiHandler, Box<dyn Error>> {
+        let endpoint = match config.endpoint_named(&config.provider) {
+            Some(endpoint) => endpoint,
+            None => return Err(format!("unknown provider {}", config.provider).into()),
+        };
+
+        return Ok(ApiHandler {
+            transport: Transport::new(config)?,
+            provider: Arc::from(provider::from_endpoint(endpoint, &token)?),
+            provider_name: config.provider.clone(),
+            stream: endpoint.stream,
+            request: None,
+            response: None
+        })
+    }
+
+    ///Where progress notifications (retries, streamed text...) are sent
+    pub fn set_event_sender(&mut self, events: UnboundedSender<ApiEvent>) {
+        self.transport.set_event_sender(events);
+    }
+
+    pub fn set_provider(&mut self, name: &str, provider: Box<dyn Provider>, stream: bool) {
+        self.provider = Arc::from(provider);
+        self.provider_name = name.to_string();
+        self.stream = stream;
+    }
+
+    pub fn provider_name(&self) -> &str {
+        return &self.provider_name
+    }
+
+    ///The provider, kept to count tokens while the handler is away answering
+    pub fn provider(&self) -> Arc<dyn Provider> {
+        return Arc::clone(&self.provider)
+    }
+
+    pub(crate) fn update_request(&mut self, request: CompletionRequest) {
+        self.request = Some(request);
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, ApiError> {
+        return self.provider.list_models(&self.transport).await
+    }
+
+    ///Sends a side request (a summary...), never streamed nor kept as the last call
+    pub async fn complete(&self, request: &CompletionRequest) -> Result<Completion, ApiError> {
+        return self.provider.send(&self.transport, request).await
+    }
+
+    ///Asks `query` on its own, without any conversation before it
+    #[allow(dead_code)] //used by tests
     pub async fn answer_from(
         &mut self,
         model: String,
         query: String,
-        temperature: u8,
-        max_tokens: i32
+        params: Params,
+        timeout: Option<Duration>,
         ) -> crate::app::Message {
 
-        self.update_call(ApiCall::from(model,query,temperature,max_tokens));
-        let call = match &self.call  {
-            Some(call) => call,
+        return self.answer(CompletionRequest {
+            model,
+            system: None,
+            prompt: query.clone(),
+            query,
+            params,
+            timeout,
+            tools: Vec::new(),
+            tool_rounds: Vec::new(),
+        }).await
+    }
+
+    pub async fn answer(&mut self, request: CompletionRequest) -> Message {
+        self.update_request(request);
+        let request = match &self.request  {
+            Some(request) => request,
             None => {
-                panic!("Should be able to build apicall")
+                panic!("Should be able to build request")
             }
         };
-        let answer = self.send_dummy_api_reqwest(
-        // let answer = self.send_api_reqwest(
-            &call
-        ).await;
-
-        self.response = match answer {
-            Ok(ans) => {
-                Some(ans)
-            }
-            Err(_) => {
-                error!("Couldn't get answer");
-                error!("ERROR: {:#?}", answer);
-                None
-            }
+        let answer = match self.stream {
+            true => self.provider.stream(&self.transport, request).await,
+            false => self.provider.send(&self.transport, request).await,
         };
 
-        self.message_from_answer(self.response.as_ref())
+        if let Err(err) = &answer {
+            error!("Couldn't get answer");
+            error!("ERROR: {:#?}", err);
+        }
+
+        let message = self.message_from_answer(answer.as_ref());
+        self.response = answer.ok();
+        message
     }
 
-    fn message_from_answer(&self, answer: Option<&ApiResponse>) -> Message {
+    fn message_from_answer(&self, answer: Result<&Completion, &ApiError>) -> Message {
 
         let sender: String;
         let body: String;
+        let mut usage = None;
         match answer {
-            Some(answer) => {
-                sender = answer.get_model();
-                body = answer.choices()[0].get_answer();
+            Ok(answer) => {
+                sender = answer.model.clone();
+                body = answer.text.clone();
+                usage = answer.usage;
             }
-            None => {
-                sender = "YAS - your average system".to_string();
-                body = "something went wrong in the request (probably problem
-                parsing the response), try again".to_string();
+            Err(err @ ApiError::Timeout { .. }) => {
+                sender = SYSTEM_SENDER.to_string();
+                body = format!("request {}, try again or allow more time with :timeout <seconds>", err);
+            }
+            Err(err) => {
+                sender = SYSTEM_SENDER.to_string();
+                body = format!("something went wrong in the request ({}), try again", err);
             }
         }
 
-        return Message::from(sender,body,MessageType::Answer) 
+        return Message::from(sender,body,MessageType::Answer).with_usage(usage)
     }
 }
 
 
-impl ApiResponse {
-    pub fn choices(&self) -> &Vec<Choices> {
-        return &self.choices;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock_server;
+
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_transport(max_retries: u32) -> Transport {
+        return transport_with(max_retries, Timeouts::default())
     }
-    pub fn get_model(&self) -> String {
-        return String::from(&self.model);
+
+    fn transport_with(max_retries: u32, timeouts: Timeouts) -> Transport {
+        let config = Config {
+            retry: RetryPolicy {
+                max_retries,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 10,
+                multiplier: 2.0,
+                jitter: false,
+            },
+            timeouts,
+            ..Config::default()
+        };
+        return Transport::new(&config).unwrap()
     }
-}
 
+    fn completion() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model": "test-model",
+            "choices": [{"text": "hello"}]
+        }))
+    }
 
-impl Choices {
-    pub fn get_answer(&self) -> String {
-        return String::from(&self.text);
+    async fn send(transport: &Transport, server: &MockServer) -> Result<Vec<u8>, ApiError> {
+        let url = format!("{}/v1/completions", server.uri());
+        transport.send_with_retry(|| transport.client().post(url.as_str()), None).await
     }
-}
 
+    fn answer_text(body: &[u8]) -> String {
+        let json = serde_json::from_slice::<serde_json::Value>(body).unwrap();
+        return json["choices"][0]["text"].as_str().unwrap().to_string()
+    }
+
+    async fn received(server: &MockServer) -> usize {
+        return server.received_requests().await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limits_until_success() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let transport = test_transport(3);
+        let body = send(&transport, &server).await.unwrap();
+
+        assert_eq!(answer_text(&body), "hello");
+        assert_eq!(received(&server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_capped_at_the_longest_backoff() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "86400"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut transport = test_transport(3);
+        transport.set_event_sender(sender);
+        let body = tokio::time::timeout(Duration::from_secs(5), send(&transport, &server)).await.unwrap().unwrap();
+
+        assert_eq!(answer_text(&body), "hello");
+        match receiver.try_recv().unwrap() {
+            ApiEvent::Retrying { delay, .. } => assert_eq!(delay, Duration::from_millis(10)),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let transport = test_transport(2);
+        let err = send(&transport, &server).await.unwrap_err();
+
+        assert!(matches!(err, ApiError::Status { status, .. } if status.as_u16() == 503));
+        assert_eq!(received(&server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("bad request"))
+            .mount(&server)
+            .await;
+
+        let transport = test_transport(3);
+        let err = send(&transport, &server).await.unwrap_err();
+
+        assert!(!err.is_transient());
+        assert_eq!(received(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn reports_retries_as_events() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut transport = test_transport(3);
+        transport.set_event_sender(sender);
+
+        send(&transport, &server).await.unwrap();
+
+        match receiver.try_recv().unwrap() {
+            ApiEvent::Retrying { attempt, max_retries, .. } => {
+                assert_eq!(attempt, 1);
+                assert_eq!(max_retries, 3);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn total_timeout_is_reported_and_not_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion().set_delay(Duration::from_millis(500)))
+            .mount(&server)
+            .await;
 
+        let timeouts = Timeouts { total_ms: 50, ..Timeouts::default() };
+        let transport = transport_with(3, timeouts);
+        let err = send(&transport, &server).await.unwrap_err();
 
+        assert!(matches!(
+            err,
+            ApiError::Timeout { kind: TimeoutKind::Total, after: Some(after) }
+                if after == Duration::from_millis(50)
+        ));
+        assert_eq!(received(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn timeout_can_be_raised_per_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion().set_delay(Duration::from_millis(200)))
+            .mount(&server)
+            .await;
+
+        let timeouts = Timeouts { total_ms: 50, ..Timeouts::default() };
+        let transport = transport_with(0, timeouts);
+        let url = format!("{}/v1/completions", server.uri());
+        let response = transport.send_with_retry(
+            || transport.client().post(url.as_str()),
+            Some(Duration::from_secs(5)),
+        ).await;
+
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stalled_body_hits_read_timeout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        //headers arrive, then the server goes quiet halfway through the body
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = socket.read(&mut buffer).await;
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let timeouts = Timeouts { read_ms: 50, ..Timeouts::default() };
+        let transport = transport_with(0, timeouts);
+        let url = format!("http://{}/v1/completions", address);
+        let err = transport.send_with_retry(|| transport.client().post(url.as_str()), None)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ApiError::Timeout { kind: TimeoutKind::Read, .. }));
+        assert!(err.is_transient());
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        return Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    fn transport_on(network: Network) -> Result<Transport, Box<dyn Error>> {
+        let config = Config { network, ..Config::default() };
+        return Transport::new(&config)
+    }
+
+    #[tokio::test]
+    async fn configured_proxy_carries_requests() {
+        //plain http through a proxy is sent in absolute form,
+        //so a mock server can stand in for the proxy
+        let proxy = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(completion())
+            .mount(&proxy)
+            .await;
+
+        let transport = transport_on(Network {
+            proxy: Some(proxy.uri()),
+            no_proxy: Some("localhost".to_string()),
+            ..Network::default()
+        }).unwrap();
+
+        let body = transport.send_with_retry(
+            || transport.client().post("http://api.example.invalid/v1/completions"),
+            None,
+        ).await.unwrap();
+
+        assert_eq!(answer_text(&body), "hello");
+        let requests = proxy.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url.host_str(), Some("api.example.invalid"));
+    }
+
+    #[tokio::test]
+    async fn no_proxy_hosts_skip_the_proxy() {
+        let proxy = MockServer::start().await;
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let transport = transport_on(Network {
+            proxy: Some(proxy.uri()),
+            no_proxy: Some("127.0.0.1".to_string()),
+            ..Network::default()
+        }).unwrap();
+
+        send(&transport, &server).await.unwrap();
+
+        assert_eq!(received(&server).await, 1);
+        assert_eq!(received(&proxy).await, 0);
+    }
+
+    #[test]
+    fn every_certificate_of_a_bundle_is_read() {
+        let certificates = read_certificates(&fixture("tls/ca-bundle.pem")).unwrap();
+        assert_eq!(certificates.len(), 2);
+
+        assert!(read_certificates(&fixture("tls/client.key")).is_err());
+    }
+
+    #[test]
+    fn client_builds_with_tls_settings() {
+        let transport = transport_on(Network {
+            ca_bundle: Some(fixture("tls/ca-bundle.pem")),
+            builtin_roots: false,
+            client_cert: Some(fixture("tls/client.pem")),
+            client_key: Some(fixture("tls/client.key")),
+            min_tls_version: Some("1.2".to_string()),
+            ..Network::default()
+        });
+
+        assert!(transport.is_ok());
+    }
+
+    #[test]
+    fn bad_tls_settings_are_reported() {
+        let missing = transport_on(Network {
+            ca_bundle: Some(fixture("tls/missing.pem")),
+            ..Network::default()
+        });
+        let err = missing.err().unwrap().to_string();
+        assert!(err.contains("missing.pem"), "{}", err);
+
+        let half_identity = transport_on(Network {
+            client_cert: Some(fixture("tls/client.pem")),
+            ..Network::default()
+        });
+        assert!(half_identity.is_err());
+
+        let version = transport_on(Network {
+            min_tls_version: Some("2.0".to_string()),
+            ..Network::default()
+        });
+        assert!(version.is_err());
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_date() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+
+        let past = httpdate::fmt_http_date(std::time::UNIX_EPOCH);
+        headers.insert(reqwest::header::RETRY_AFTER, past.parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let mut policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            multiplier: 2.0,
+            jitter: false,
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10), Duration::from_millis(1_000));
+
+        policy.jitter = true;
+        for attempt in 0..5 {
+            let delay = policy.backoff(attempt);
+            let upper = std::cmp::min(100 * 2u64.pow(attempt), 1_000);
+            assert!(delay >= Duration::from_millis(upper / 2));
+            assert!(delay <= Duration::from_millis(upper));
+        }
+    }
+
+    fn handler_on(base_url: String, stream: bool, max_retries: u32) -> ApiHandler {
+        let config = Config {
+            endpoint: crate::provider::Endpoint {
+                base_url: Some(base_url),
+                stream,
+                ..crate::provider::Endpoint::default()
+            },
+            retry: RetryPolicy { max_retries, initial_backoff_ms: 1, jitter: false, ..RetryPolicy::default() },
+            ..Config::default()
+        };
+        return ApiHandler::new(String::new(), &config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn mock_server_failures_are_retried() {
+        let base_url = mock_server::spawn(mock_server::Settings { fail_first: 2, ..Default::default() });
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler_on(base_url, false, 3);
+        handler.set_event_sender(sender);
+
+        let answer = handler.answer_from("mock-completion".to_string(), "hi".to_string(), Params { max_tokens: 7, ..Params::default() }, None).await;
+
+        assert_eq!(answer.get_body(), "echo: hi");
+        let mut retries = 0;
+        while let Ok(ApiEvent::Retrying { .. }) = receiver.try_recv() {
+            retries += 1;
+        }
+        assert_eq!(retries, 2);
+    }
+
+    #[tokio::test]
+    async fn mock_server_failures_reach_the_transcript_once_retries_run_out() {
+        let base_url = mock_server::spawn(mock_server::Settings { fail_every: 1, ..Default::default() });
+        let mut handler = handler_on(base_url, false, 1);
+
+        let answer = handler.answer_from("mock-completion".to_string(), "hi".to_string(), Params { max_tokens: 7, ..Params::default() }, None).await;
+
+        assert!(answer.get_body().contains("503"));
+    }
+
+    #[tokio::test]
+    async fn mock_server_answers_are_streamed() {
+        let base_url = mock_server::spawn(mock_server::Settings { chunk_delay_ms: 5, ..Default::default() });
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler_on(base_url, true, 0);
+        handler.set_event_sender(sender);
+
+        let answer = handler.answer_from("mock-completion".to_string(), "hello there".to_string(), Params { max_tokens: 7, ..Params::default() }, None).await;
+
+        assert_eq!(answer.get_body(), "echo: hello there");
+        let mut chunks = Vec::new();
+        while let Ok(ApiEvent::Chunk(text)) = receiver.try_recv() {
+            chunks.push(text);
+        }
+        assert_eq!(chunks, mock_server::answer_to("hello there"));
+    }
+
+    #[tokio::test]
+    async fn mock_server_latency_runs_into_the_timeout() {
+        let base_url = mock_server::spawn(mock_server::Settings { latency_ms: 500, ..Default::default() });
+        let mut handler = handler_on(base_url, false, 0);
+
+        let answer = handler.answer_from(
+            "mock-completion".to_string(), "hi".to_string(), Params { max_tokens: 7, ..Params::default() }, Some(Duration::from_millis(50))
+        ).await;
+
+        assert!(answer.get_body().contains(":timeout"));
+    }
+
+    #[tokio::test]
+    async fn mock_server_models_are_listed() {
+        let handler = handler_on(mock_server::spawn(Default::default()), false, 0);
+        let models = handler.list_models().await.unwrap();
+
+        let ids: Vec<&str> = models.iter().map(|model| model.id.as_str()).collect();
+        assert_eq!(ids, mock_server::MODELS);
+    }
+}
@@ -1,25 +1,55 @@
 //logging
-use log::{debug, error, info, trace, warn, LevelFilter, SetLoggerError};
+use log::{debug, error, warn};
 
-use futures::join;
-use std::future::Future;
+use futures::FutureExt;
+
+use std::{cell::RefCell, collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::{sync::mpsc::{self, UnboundedReceiver}, task::JoinHandle};
 
 use tui::{style::{Style, Modifier, Color}, 
-    text::{Text, Span,Spans}};
+    text::{Span,Spans}};
 
 use terminal_size::{self,Width,Height};
 
 
-use crate::api::{ApiHandler, ApiCall};
-use crate::api::ApiResponse;
+use crate::api::{ApiHandler, ApiEvent};
+use crate::attach::{self, Attachment};
+use crate::blocks::{self, CodeBlock, FileWrite};
+use crate::compare::{self, Column, Comparison, Target};
+use crate::config::Config;
+use crate::context::{self, Budget, Entry, Overflow, Strategy, Summary};
+use crate::index::{Finder, SearchIndex};
+use crate::models::{self, ModelCache, ModelPicker};
+use crate::params::{Params, ParamsPanel, Profile};
+use crate::pricing;
+use crate::search::{Match, Search};
+use crate::provider::{self, Completion, CompletionRequest, ModelInfo, Provider, ToolCall, ToolRound, Usage};
+use crate::session::{self, Session};
+use crate::shell;
+use crate::template::{self, Template};
+use crate::tokenizer;
+use crate::tools;
+
+///Sender of messages from gpterm itself, never sent to the model
+pub const SYSTEM_SENDER: &str = "YAS - your average system";
+
+///Characters of the first question a tab is named after
+const TAB_LABEL_CHARS: usize = 16;
+
+///Lines left above a match or a message jumped to
+const JUMP_CONTEXT: usize = 3;
 
 fn get_terminal_sizes() -> (u16, u16) {
     let size = terminal_size::terminal_size();
     if let Some((Width(w), Height(h))) = size {
-        return (w - 2, h); //tui borders
+        return (w.saturating_sub(2), h); //tui borders
     } else {
-        error!("Couldn't get terminal size!");
-        panic!();
+        //not a terminal, e.g. under test
+        warn!("Couldn't get terminal size, assuming 80x24");
+        return (78, 24);
     }
 }
 
@@ -31,19 +61,40 @@ fn get_terminal_sizes() -> (u16, u16) {
 pub enum InputMode{
     Normal,
     Insert, 
-    Command
+    Command,
+    ///Typing what `/` looks for in the transcript
+    Search
 }
 
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
 pub enum MessageType{
     Query,
-    Answer
+    Answer,
+    ///Output of a command run with `:!` or `!`
+    Shell { command: String, code: Option<i32> },
+    ///What a tool call the model asked for returned
+    Tool { call: ToolCall },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     sender: String,
     body: String,
     message_type: MessageType,
+    ///Tokens the answer took, when the server said
+    #[serde(default)]
+    usage: Option<Usage>,
+    ///Always sent along, whatever the context strategy leaves out
+    #[serde(default)]
+    pinned: bool,
+    ///What the user typed when files were inlined in the body, `@` references and all
+    #[serde(default)]
+    typed: Option<String>,
+    ///Model the message was last counted for and its tokens as a turn
+    #[serde(skip)]
+    tokens: RefCell<Option<(String, usize)>>,
 }
 
 
@@ -52,13 +103,54 @@ impl Message {
     pub fn from(sender: String, body: String, message_type: MessageType)
         -> Message {
         return Message{
-            sender, body, message_type
+            sender, body, message_type, usage: None, pinned: false, typed: None, tokens: RefCell::new(None)
+        }
+    }
+
+    ///The text typed for the message, before files were inlined
+    fn typed(&self) -> &str {
+        return self.typed.as_deref().unwrap_or(&self.body)
+    }
+
+    ///The message as a turn of the prompt
+    fn as_turn(&self) -> String {
+        match &self.message_type {
+            MessageType::Shell { command, code } => {
+                return App::turn(&self.sender, &format!("$ {}\n{}\n({})", command, self.body, exit_note(*code)))
+            }
+            MessageType::Tool { call } => {
+                return App::turn(&self.sender, &format!("{}\n{}", tools::describe(call), self.body))
+            }
+            _ => return App::turn(&self.sender, &self.body),
+        }
+    }
+
+    ///Tokens the message takes as a turn of the prompt, counted once per model with `count`
+    fn tokens(&self, model: &str, count: impl Fn(&str) -> usize) -> usize {
+        let mut counted = self.tokens.borrow_mut();
+        if let Some((counted_for, tokens)) = &*counted {
+            if counted_for == model {
+                return *tokens
+            }
         }
+        let tokens = count(&self.as_turn());
+        *counted = Some((model.to_string(), tokens));
+        return tokens
+    }
+
+    pub fn with_usage(mut self, usage: Option<Usage>) -> Message {
+        self.usage = usage;
+        return self
     }
     pub fn get_body(&self) -> &String {
         return &self.body;
     }
 
+    ///Whether gpterm said it rather than the model, errors included
+    pub fn is_system(&self) -> bool {
+        return self.sender == SYSTEM_SENDER
+    }
+
     fn get_body_lines(&self, width: u16) -> usize {
         // let normal_line_count: usize = self.body.chars().filter(|x| x == '\n').count();
         let normal_line_count: usize = self.body.lines().count();
@@ -81,6 +173,7 @@ pub struct App {
 
     username: String,
     ///current value of the input box
+    #[allow(dead_code)]
     should_end: bool,
 
     ///current value displayed on the input box
@@ -99,13 +192,78 @@ pub struct App {
     command_status: CommandStatus,
     ///Terminal size
     pub size: (u16, u16),
-    ///Client to communicate with API
+    ///Client to communicate with API, taken by the request in flight
     api_handler: Option<ApiHandler>,
-    ///
-    max_tokens: i32,
-    ///
-    temperature: u8, //dunno what it means yet
-    ///
+    ///Provider of the client, counting tokens while the client is away
+    counter: Option<Arc<dyn Provider>>,
+    ///Request in flight, hands the client back once answered,
+    ///with the summary it wrote if it had to
+    pending: Option<JoinHandle<(ApiHandler, Message, Option<Summary>)>>,
+    ///Progress reported by the client while a request is in flight
+    api_events: Option<UnboundedReceiver<ApiEvent>>,
+    ///Text shown on the status line
+    status: String,
+    ///Total timeout for the next request only, set with `:timeout`
+    next_timeout: Option<Duration>,
+    ///Settings the client was built from, to switch providers
+    config: Config,
+    ///Api token from the token file
+    token: String,
+    ///Provider queries are sent to, see `:provider`
+    provider_name: String,
+    ///Index in `content` of the answer being streamed
+    streaming: Option<usize>,
+    ///Last summary of the turns the context strategy left out
+    summary: Option<Summary>,
+    ///Instructions sent ahead of the conversation
+    system: Option<String>,
+    ///Persona `system` comes from, None when set by hand
+    persona: Option<String>,
+    ///Name the session is saved under, it is saved after every answer once set
+    session_name: Option<String>,
+    ///Shell command running, see `:!`
+    running: Option<JoinHandle<Message>>,
+    ///Action waiting for y or n
+    confirm: Option<Confirm>,
+    ///Models the providers serve, see `:model`
+    models: ModelCache,
+    ///Popup choosing the model
+    picker: Option<ModelPicker>,
+    ///Models being fetched
+    discovery: Option<Discovery>,
+    ///Tool calls of the answer being worked out
+    tool_loop: Option<ToolLoop>,
+    ///Command line a command asks to be completed, `:write` without a path
+    command_prompt: Option<String>,
+    ///Template waiting for the values of its variables
+    template_fill: Option<TemplateFill>,
+    ///Tokens of attached files by model, path and size, they are counted every frame otherwise
+    attachment_tokens: RefCell<BTreeMap<(String, PathBuf, u64), usize>>,
+    ///Parameter profile chosen with `:profile`, by default the model's
+    profile: Option<String>,
+    ///Parameters set by hand, over the profile
+    overrides: Profile,
+    ///Popup editing the parameters
+    params_panel: Option<ParamsPanel>,
+    ///Models or profiles every query goes to while comparing, see `:compare`
+    compare_targets: Vec<Target>,
+    ///Last query compared and its answers, shown instead of the transcript
+    comparison: Option<Comparison>,
+    ///Open conversations, the one at `active` is a placeholder: its state is the app's own
+    tabs: Vec<Conversation>,
+    ///Index in `tabs` of the conversation shown
+    active: usize,
+    ///What `/` looks for, its matches stay highlighted until esc
+    search: Option<Search>,
+    ///Popup listing the saved messages `:find` found
+    finder: Option<Finder>,
+    ///Index in `content` of the message selected with `J` and `K`
+    cursor: Option<usize>,
+    ///Index in `content` of the message being edited in the input box
+    editing: Option<usize>,
+    ///Text copied, for the terminal to put in the clipboard
+    clipboard: Option<String>,
+    ///Model queries are sent to
     selected_model: String
 }
 
@@ -114,305 +272,2775 @@ impl App {
 
 
     pub fn command_active(&self) -> bool {
-        if self.command == String::from("") {
+        if self.command.is_empty() {
             return false
         }
         return true
     }
 
-    pub fn get_call(&self) -> &ApiCall {
-        return self.api_handler.as_ref().unwrap().call.as_ref().unwrap()
+    pub fn get_call(&self) -> Option<&CompletionRequest> {
+        return self.api_handler.as_ref()?.request.as_ref()
     }
 
-    pub fn get_response(&self) -> &ApiResponse {
-        return self.api_handler.as_ref().unwrap().response.as_ref().unwrap()
-    }
-    pub fn set_handler(&mut self, token: String) {
-        self.api_handler = Some(ApiHandler::new(token))
+    pub fn get_response(&self) -> Option<&Completion> {
+        return self.api_handler.as_ref()?.response.as_ref()
     }
+    pub fn set_handler(&mut self, token: String, config: &Config)
+        -> Result<(), Box<dyn std::error::Error>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut handler = ApiHandler::new(token.clone(), config)?;
+        handler.set_event_sender(sender);
+
+        self.provider_name = handler.provider_name().to_string();
+        self.counter = Some(handler.provider());
+        self.api_handler = Some(handler);
+        self.api_events = Some(receiver);
+        self.config = config.clone();
+        self.token = token;
+
+        if let Some(model) = &config.model {
+            self.selected_model = model.clone();
+        }
+        if let Some(path) = &config.models.cache_file {
+            self.models = ModelCache::load(path);
+            self.learn_windows();
+        }
 
-    pub fn update_input(&mut self) {
-        self.internal_input = String::from(&self.display_input);
-        self.display_input = String::new();
+        if let Some(persona) = &config.persona {
+            if let Err(err) = self.use_persona(persona) {
+                warn!("Not starting with persona {}: {}", persona, err);
+            }
+        }
+        Ok(())
     }
-    pub async fn answer(&mut self) {
 
-        let query = self.get_input();
-        let output = self.api_handler.as_mut().unwrap().answer_from(
-            self.selected_model.clone(),
-            query,
-            self.temperature,
-            self.max_tokens,
-        ).await;
-
-        self.push_answer(output);
+    ///One turn of the conversation as the model sees it
+    fn turn(sender: &str, body: &str) -> String {
+        return format!("{}: {}\n\n", sender, body)
     }
 
-    pub fn get_max_offset(&mut self) -> usize {
-        //nice
-        let body_count : usize= self.content
+    ///Indices in `content` of the conversation, without what gpterm said itself.
+    ///Shell output only goes along with the question after it, unless pinned.
+    ///Tool results went back with the answer they were for, they only go again pinned
+    fn conversation(&self) -> Vec<usize> {
+        let last_query = self.content
             .iter()
-            .map(|message| {
-                message.get_body_lines(self.size.0)
+            .rposition(|message| matches!(message.message_type, MessageType::Query));
+        return (0..self.content.len())
+            .filter(|&index| {
+                let message = &self.content[index];
+                match message.message_type {
+                    MessageType::Shell { .. } => {
+                        message.pinned
+                            || (self.config.shell.context && last_query.is_none_or(|query| index > query))
+                    }
+                    MessageType::Tool { .. } => message.pinned,
+                    _ => message.sender != SYSTEM_SENDER,
+                }
             })
-            .sum();
+            .collect()
+    }
 
-         let max = std::cmp::max(
-            //account for lines and widget height
-            (body_count + 2*self.content.len()) as i32 - 28, 0
-        ) as usize; 
+    fn turns(&self, indices: &[usize]) -> String {
+        return indices
+            .iter()
+            .map(|&index| self.content[index].as_turn())
+            .collect()
+    }
 
-        self.max_offset = max;
-        max
+    ///Tokens `text` takes for `model`, as the provider of the tab counts them
+    fn count_tokens(&self, model: &str, text: &str) -> usize {
+        match &self.counter {
+            Some(provider) => return provider.count_tokens(model, text),
+            None => return tokenizer::count_tokens(model, text),
+        }
     }
 
-    pub fn scroll_to_bottom(&mut self) {
-        let scroll = self.get_max_offset(); //widget height
-        if scroll > 0{
-            self.scroll = scroll as usize;
-            return;
+    ///Tokens the system prompt takes ahead of the conversation for `model`
+    fn system_tokens(&self, model: &str) -> usize {
+        match &self.system {
+            Some(system) => return self.count_tokens(model, &format!("{}\n\n", system)),
+            None => return 0,
         }
-        self.scroll = 0
     }
-    pub fn input_mode(&self) -> InputMode{ 
-        return self.input_mode;
+
+    ///What follows the conversation in the prompt for `query`, `model` answering
+    fn rest(&self, query: &str, model: &str) -> String {
+        return format!("{}{}:", App::turn(&self.username, query), model)
     }
 
-    pub fn set_input_mode(&mut self, mode: InputMode) {
-        match mode {
-            InputMode::Command => {
-                self.command_status = CommandStatus::Okay;
-                self.command = String::from(':');
-            }
-            _ => {}
+    ///The summary standing in for `dropped`, if the last one covers exactly them
+    fn summary_of(&self, dropped: &[usize]) -> Option<&Summary> {
+        if !self.config.context.summarize || dropped.is_empty() {
+            return None
         }
-        self.input_mode = mode;
+        return self.summary.as_ref().filter(|summary| summary.covers == dropped)
     }
 
-    pub fn scroll_down (&mut self) { 
-        if self.scroll != 0 {
-            self.scroll -= 1;
+    ///Splits the conversation in the messages sent along to `model` and the ones left out,
+    ///`rest` is what the prompt takes after the conversation and `max_tokens` the answer
+    fn plan(&self, model: &str, rest: usize, max_tokens: usize) -> (Vec<usize>, Vec<usize>) {
+        let conversation = self.conversation();
+        let entries: Vec<Entry> = conversation
+            .iter()
+            .map(|&index| {
+                let message = &self.content[index];
+                Entry {
+                    tokens: message.tokens(model, |text| self.count_tokens(model, text)),
+                    pinned: message.pinned,
+                    query: matches!(message.message_type, MessageType::Query),
+                }
+            })
+            .collect();
+
+        let summary = match (&self.summary, self.config.context.summarize) {
+            (Some(summary), true) => self.count_tokens(model, &context::summary_turn(&summary.text)),
+            _ => 0,
+        };
+        let room = self.config.context.window(model)
+            .saturating_sub(max_tokens + self.system_tokens(model) + rest + summary);
+
+        let kept = self.config.context.select(&entries, room);
+        let mut sent = Vec::new();
+        let mut dropped = Vec::new();
+        for (index, kept) in conversation.into_iter().zip(kept) {
+            match kept {
+                true => sent.push(index),
+                false => dropped.push(index),
+            }
         }
+        return (sent, dropped)
     }
 
-    pub fn get_scroll(&self) -> usize {
-        return self.scroll;
+    ///Context window use of the request the input box would make
+    pub fn budget(&self) -> Budget {
+        return self.budget_for(&self.display_input, &self.selected_model, self.max_tokens())
     }
 
-    pub fn scroll_up (&mut self) { 
-        if self.scroll < self.max_offset {
-            self.scroll += 1;
+    ///Context window use of the request asking `typed` to `model`, answering in up to `max_tokens`
+    fn budget_for(&self, typed: &str, model: &str, max_tokens: usize) -> Budget {
+        //messages are counted apart, they are split at line breaks
+        let attached = match self.attachments_of(typed) {
+            Ok(attachments) => self.count_attached(&attachments, model),
+            Err(_) => 0,
+        };
+        let rest = self.count_tokens(model, &self.rest(typed, model)) + attached;
+        let (sent, dropped) = self.plan(model, rest, max_tokens);
+        let summary = match self.summary_of(&dropped) {
+            Some(summary) => self.count_tokens(model, &context::summary_turn(&summary.text)),
+            None => 0,
+        };
+        let history: usize = sent
+            .iter()
+            .map(|&index| self.content[index].tokens(model, |text| self.count_tokens(model, text)))
+            .sum();
+        return Budget {
+            prompt: self.system_tokens(model) + summary + history + rest,
+            max_tokens,
+            window: self.config.context.window(model),
         }
-        
-    }
-    
-    pub fn get_display_input(&self) -> String {
-        return self.display_input.clone()
     }
 
-    pub fn get_input(&self) -> String{
-        return self.internal_input.clone();
+    ///Whether asking `typed` to `model` may go, the error saying why not.
+    ///Requests over the window only go when overflowing is allowed
+    fn check_budget(&self, typed: &str, model: &str, max_tokens: usize) -> Result<(), String> {
+        let budget = self.budget_for(typed, model, max_tokens);
+        if budget.fits() {
+            return Ok(())
+        }
+        let overflow = format!(
+            "request needs {}+{} tokens, {} allows {}",
+            budget.prompt, budget.max_tokens, model, budget.window
+        );
+        if self.config.context.overflow == Overflow::Block {
+            warn!("{}, not sending it", overflow);
+            return Err("over the context window, not sent".to_string())
+        }
+        warn!("{}, sending anyway", overflow);
+        return Ok(())
     }
 
-    pub fn push_input(&mut self, char: char) {
-        self.display_input.push(char);
+    ///Files the `@` references of the input box point at
+    fn attachments(&self) -> Result<Vec<Attachment>, String> {
+        return self.attachments_of(&self.display_input)
     }
-    
-    pub fn pop_input(&mut self) {
-        self.display_input.pop();
+
+    ///Files the `@` references of `typed` point at
+    fn attachments_of(&self, typed: &str) -> Result<Vec<Attachment>, String> {
+        return attach::resolve(typed, &self.config.attachments)
     }
 
-    pub fn push_answer(&mut self,message: Message) {
-        self.content.push(message);
-        self.internal_input = String::new();
-   }
+    ///What the input box attaches, None when it references no file
+    pub fn get_attachments(&self) -> Option<Result<Vec<Attachment>, String>> {
+        if attach::references(&self.display_input).is_empty() {
+            return None
+        }
+        return Some(self.attachments())
+    }
 
+    ///Tokens `attachments` add to the query for `model` once inlined
+    fn count_attached(&self, attachments: &[Attachment], model: &str) -> usize {
+        let mut counted = self.attachment_tokens.borrow_mut();
+        let mut tokens = 0;
+        for attachment in attachments {
+            let key = (model.to_string(), attachment.path.clone(), attachment.bytes);
+            tokens += match counted.get(&key) {
+                Some(tokens) => *tokens,
+                None => {
+                    let inlined = attach::inline("", std::slice::from_ref(attachment)).unwrap_or_default();
+                    let count = self.count_tokens(model, &inlined);
+                    counted.insert(key, count);
+                    count
+                }
+            };
+        }
+        return tokens
+    }
 
-    pub fn push_content(&mut self,
-        sender: String,
-        message_type: MessageType,
-        message: String,
-    ) {
-        let message = Message {
-            sender,
-            body: message,
-            message_type,
+    ///Completes the `@path` being typed, listing the candidates when there are several
+    pub fn complete_input(&mut self) {
+        let word = match self.display_input.split_whitespace().last() {
+            Some(word) if self.display_input.ends_with(word) => word.to_string(),
+            _ => return,
+        };
+        let partial = match word.strip_prefix('@') {
+            Some(partial) => partial,
+            None => return,
         };
 
-        self.content.push(message);
-   }
-
-
-
-    pub fn get_content(&self) -> Vec<Spans>{
-
-        let mut span_vec: Vec<Spans> = Vec::new();
-        for message in &self.content{
-            let sender_spans = self.sender_from(&message);
-            let body_spans = self.body_from(&message);
-
-            let line = self.line_from(&message.message_type);
-
-            span_vec.push(line);
-            span_vec.push(sender_spans);
-            for spans in body_spans {
-                span_vec.push(spans);
+        let candidates = attach::complete(partial);
+        let completed = match candidates.as_slice() {
+            [] => {
+                self.status = format!("nothing matches {}", partial);
+                return;
             }
-        }
-
-
+            [only] => only.clone(),
+            candidates => {
+                self.status = candidates
+                    .iter()
+                    .map(|candidate| candidate.rsplit_terminator('/').next().unwrap_or(candidate))
+                    .collect::<Vec<&str>>()
+                    .join("  ");
+                common_prefix(candidates)
+            }
+        };
 
-        return span_vec;
+        let start = self.display_input.len() - word.len();
+        self.display_input.truncate(start);
+        self.display_input.push_str(&format!("@{}", completed));
     }
 
-    pub fn update_size(&mut self) {
-        self.size = get_terminal_sizes();
+    ///How the summary of `dropped` is had, None when there is nothing to summarize
+    fn summary_job(&self, dropped: Vec<usize>) -> Option<SummaryJob> {
+        if !self.config.context.summarize || dropped.is_empty() {
+            return None
+        }
+        match &self.summary {
+            Some(summary) if summary.covers == dropped => {
+                return Some(SummaryJob::Cached(summary.text.clone()))
+            }
+            //newer turns fell out since, the summary is carried on with them
+            Some(summary) if dropped.starts_with(&summary.covers) => {
+                let turns = self.turns(&dropped[summary.covers.len()..]);
+                return Some(SummaryJob::Write {
+                    prompt: context::summary_prompt(Some(&summary.text), &turns),
+                    covers: dropped,
+                })
+            }
+            _ => {
+                return Some(SummaryJob::Write {
+                    prompt: context::summary_prompt(None, &self.turns(&dropped)),
+                    covers: dropped,
+                })
+            }
+        }
     }
 
-    fn body_from <'a> (&self, message: &'a Message) -> Vec<Spans<'a>> {
-
-        match message.message_type {
-            MessageType::Query => {
-                vec![
-                    Spans::from(vec![Span::raw(message.body.clone())])
-                ]
+    ///Sends what was typed, unless the request is too big and `[context]` says to block it.
+    ///While filling in a template what was typed is the value asked for
+    pub fn send_input(&mut self) {
+        if self.template_fill.is_some() {
+            self.fill_template();
+            return;
+        }
+        if self.tool_loop.is_some() {
+            self.status = "the model is still calling tools".to_string();
+            return;
+        }
+        match self.editing.take() {
+            //an edited question is asked again, what came after it goes once it is sent
+            Some(index) if matches!(self.content[index].message_type, MessageType::Query) => {
+                let typed = self.get_display_input();
+                match self.resend_from(index, typed) {
+                    Ok(()) => {
+                        self.display_input = String::new();
+                        self.cursor = None;
+                    }
+                    Err(status) => {
+                        self.status = status;
+                        self.editing = Some(index);
+                    }
+                }
+                return;
             }
-            MessageType::Answer => {
-                self.parse_answer(&message.body)
+            Some(index) => {
+                self.content[index].body = std::mem::take(&mut self.display_input);
+                *self.content[index].tokens.borrow_mut() = None;
+                self.status = format!("edited message {}", index + 1);
+                self.set_input_mode(InputMode::Normal);
+                self.autosave();
+                return;
+            }
+            None => {}
+        }
+        if let Some(command) = self.display_input.strip_prefix('!') {
+            let command = command.trim().to_string();
+            let result = self.run_shell(&command);
+            if result.is_ok() {
+                self.display_input = String::new();
             }
+            self.feedback(result);
+            return;
+        }
+        if !self.compare_targets.is_empty() {
+            let result = self.send_comparison();
+            self.feedback(result);
+            return;
+        }
+
+        let typed = self.get_display_input();
+        match self.send_query(typed) {
+            Ok(()) => self.display_input = String::new(),
+            Err(status) => self.status = status,
         }
     }
-    fn sender_from<'a>(&self, message: &'a Message) -> Spans<'a> {
 
-        match message.message_type {
+    ///Sends `typed` in place of the messages from `index` on, which stay when it isn't sent
+    fn resend_from(&mut self, index: usize, typed: String) -> Result<(), String> {
+        let after = self.content.split_off(index);
+        //the summary may cover messages going
+        let summary = self.summary.take();
+        let sent = self.send_query(typed);
+        if sent.is_err() {
+            self.content.extend(after);
+            self.summary = summary;
+        }
+        return sent
+    }
 
-            MessageType::Query => {
-                Spans::from(vec![
-                    Span::styled(
-                        &message.sender,
-                        Style::default()
-                            .add_modifier(Modifier::BOLD)
-                            .fg(Color::Blue)
-                    ),
-                    Span::raw(":"),
-                ])
+    ///Asks `typed` with the tab's model, the transcript only changes once the request is sent.
+    ///The error is the status saying why it wasn't
+    fn send_query(&mut self, typed: String) -> Result<(), String> {
+        let params = match self.params() {
+            Ok(params) => params,
+            Err(err) => {
+                warn!("Not sending, {}", err);
+                return Err(format!("not sent: {}", err))
             }
-            MessageType::Answer => {
-                Spans::from(vec![
-                    Span::styled(
-                        &message.sender,
-                        Style::default()
-                            .add_modifier(Modifier::BOLD)
-                            .fg(Color::Magenta)
-                    ),
-                    Span::raw(":"),
-                ])
+        };
+        self.check_budget(&typed, &self.selected_model, params.max_tokens as usize)?;
+
+        let query = match self.attachments_of(&typed).and_then(|attachments| attach::inline(&typed, &attachments)) {
+            Ok(query) => query,
+            Err(err) => {
+                warn!("Not sending, {}", err);
+                return Err(format!("not sent: {}", err))
             }
+        };
+        let model = &self.selected_model;
+        let rest = self.rest(&query, model);
+        let (sent, dropped) = self.plan(model, self.count_tokens(model, &rest), params.max_tokens as usize);
+        if !dropped.is_empty() {
+            debug!("Leaving {} messages out of the request", dropped.len());
         }
+        let prompt = format!("{}{}", self.turns(&sent), rest);
+        let summary = self.summary_job(dropped);
+
+        self.push_content(self.get_username(), MessageType::Query, query.clone());
+        if let Some(message) = self.content.last_mut().filter(|_| typed != query) {
+            message.typed = Some(typed);
+        }
+        self.internal_input = query;
+        self.answer(prompt, summary, params);
+        return Ok(())
     }
 
-    fn line_from<'a>(&self, message_type:  &MessageType) -> Spans<'a>{
-        match message_type {
-            MessageType::Query => {
-                Spans::from (
-                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
-                        Style::default().fg(Color::Blue))]
-                )
-            }
-            MessageType::Answer => {
-                Spans::from (
-                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
-                        Style::default().fg(Color::Magenta))]
-                )
+    ///Sends `prompt` in the background, after the summary going before it. See `poll_answer`
+    fn answer(&mut self, prompt: String, summary: Option<SummaryJob>, params: Params) {
+        let tools = match tools::specs(&self.config.tools) {
+            Ok(tools) => tools,
+            Err(err) => {
+                warn!("Not offering tools, {}", err);
+                Vec::new()
             }
-        }
+        };
+        let request = CompletionRequest {
+            model: self.selected_model.clone(),
+            system: self.system.clone(),
+            prompt,
+            query: self.get_input(),
+            params,
+            timeout: self.next_timeout.take(),
+            tools,
+            tool_rounds: Vec::new(),
+        };
+        self.send_request(request, summary);
     }
 
-    fn parse_answer<'a>(&self, answer: &'a str) -> Vec<Spans<'a>> {
-        let partial = answer.split('\n').collect::<Vec<&str>>();
+    ///Sends `request` in the background, the summary going before its prompt
+    fn send_request(&mut self, mut request: CompletionRequest, summary: Option<SummaryJob>) {
 
-        let mut span_vec: Vec<Spans> = Vec::new();
+        let mut handler = match self.api_handler.take() {
+            Some(handler) => handler,
+            None => {
+                error!("Tried to answer while a request is in flight");
+                return;
+            }
+        };
 
-        for line in partial {
-            span_vec.push(
-                Spans::from(vec![Span::raw(line)])
-            );
-        }
+        self.streaming = None;
+        self.status = format!("waiting for {}...", request.model);
+        self.pending = Some(tokio::spawn(async move {
+            let (summary, written) = match summary {
+                None => (None, None),
+                Some(SummaryJob::Cached(text)) => (Some(text), None),
+                Some(SummaryJob::Write { covers, prompt }) => {
+                    let summarize = CompletionRequest {
+                        system: None,
+                        prompt,
+                        params: Params { temperature: 0.0, max_tokens: context::SUMMARY_MAX_TOKENS, ..Params::default() },
+                        tools: Vec::new(),
+                        tool_rounds: Vec::new(),
+                        ..request.clone()
+                    };
+                    match handler.complete(&summarize).await {
+                        Ok(completion) => {
+                            let text = completion.text.trim().to_string();
+                            (Some(text.clone()), Some(Summary { covers, text }))
+                        }
+                        Err(err) => {
+                            warn!("Couldn't summarize the earlier conversation, leaving it out: {}", err);
+                            (None, None)
+                        }
+                    }
+                }
+            };
+            if let Some(summary) = summary {
+                request.prompt = format!("{}{}", context::summary_turn(&summary), request.prompt);
+            }
 
-        return span_vec;
-    }
-    pub fn set_username(&mut self, name: String) {
-        self.username = name;
+            let output = handler.answer(request).await;
+            (handler, output, written)
+        }));
     }
-    pub fn get_username(&self) -> String {
-        self.username.clone()
+
+    pub fn is_waiting(&self) -> bool {
+        let comparing = self.comparison.as_ref().is_some_and(|comparison| comparison.is_waiting());
+        return self.pending.is_some() || self.discovery.is_some() || comparing
     }
 
-    pub fn get_command(&self) -> String {
-        return self.command.clone()
+    ///Whether a shell command is running
+    pub fn is_running(&self) -> bool {
+        return self.running.is_some()
     }
 
-    pub fn send_command(&mut self) {
-        let commands: Vec<String> = Vec::new();
+    ///Whether the conversation shown would change under a new one: an answer,
+    ///a command or tool calls are on their way
+    fn is_busy(&self) -> bool {
+        return self.is_waiting() || self.is_running() || self.tool_loop.is_some()
+    }
 
-        if commands.contains(&self.command) {
-            // self.set_input_mode(InputMode::Normal);
-        } else { 
-            self.command = "Error: Command not found".to_string();
-            self.set_input_mode(InputMode::Normal);
-            self.command_status = CommandStatus::Error;
+    ///`:!command`, runs it in the background, see `poll_answer`
+    fn run_shell(&mut self, command: &str) -> Result<String, String> {
+        if command.is_empty() {
+            return Err("usage: :!<command>".to_string())
+        }
+        if self.is_running() {
+            return Err("a command is already running".to_string())
         }
-        
-    }
 
-    pub fn push_command(&mut self, c: char) {
-        self.command.push(c)
+        self.spawn_shell(command.to_string(), None);
+        return Ok(String::new())
     }
 
-    pub fn pop_command(&mut self){
-        self.command.pop();
+    ///Runs `command` in the background, or a script with its interpreter removing it once done
+    fn spawn_shell(&mut self, command: String, script: Option<(String, PathBuf)>) {
+        let program = self.config.shell.program();
+        let limit = self.config.shell.max_output_bytes;
+        self.status = format!("running {}...", command.lines().next().unwrap_or(""));
+        self.running = Some(tokio::spawn(async move {
+            let output = match &script {
+                Some((interpreter, script)) => shell::run_script(interpreter, script, limit).await,
+                None => shell::run(&program, &command, limit).await,
+            };
+            if let Some((_, script)) = script {
+                if let Err(err) = tokio::fs::remove_file(&script).await {
+                    warn!("Couldn't remove {}: {}", script.display(), err);
+                }
+            }
+            Message::from(
+                "shell".to_string(),
+                output.text,
+                MessageType::Shell { command, code: output.code }
+            )
+        }));
     }
 
-    pub fn reset_command(&mut self){
-        self.command = String::from("");
-        self.command_status = CommandStatus::Okay;
+    ///Code blocks of the answer the message cursor is on, or of the last answer,
+    ///with how that answer is called in messages
+    fn answer_blocks(&self) -> Result<(String, Vec<CodeBlock>), String> {
+        let is_answer = |message: &Message| matches!(message.message_type, MessageType::Answer) && !message.is_system();
+        let (name, answer) = match self.selected_message() {
+            Some(index) if is_answer(&self.content[index]) => (format!("message {}", index + 1), &self.content[index]),
+            Some(index) => return Err(format!("message {} isn't an answer", index + 1)),
+            None => match self.content.iter().rev().find(|message| is_answer(message)) {
+                Some(answer) => ("the last answer".to_string(), answer),
+                None => return Err("no answer yet".to_string()),
+            },
+        };
+        let blocks = blocks::extract(&answer.body);
+        if blocks.is_empty() {
+            return Err(format!("{} has no code blocks", name))
+        }
+        return Ok((name, blocks))
     }
 
-    pub fn command_status(&self) -> &CommandStatus {
-        return &self.command_status
+    ///Block `n` (from 1) of the answer selected, its last block by default
+    fn code_block(&self, n: Option<&str>) -> Result<CodeBlock, String> {
+        let (name, mut blocks) = self.answer_blocks()?;
+        let count = blocks.len();
+        match n.map(|n| n.parse::<usize>()) {
+            None => return Ok(blocks.remove(count - 1)),
+            Some(Ok(n)) if n >= 1 && n <= count => return Ok(blocks.remove(n - 1)),
+            _ => return Err(format!("{} has blocks 1 to {}", name, count)),
+        }
     }
 
-}
+    ///`:blocks`, lists the code blocks of the answer selected
+    fn list_blocks(&self) -> Result<String, String> {
+        let (name, blocks) = self.answer_blocks()?;
+        let listed: Vec<String> = blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| format!("{}: {}", index + 1, block.describe()))
+            .collect();
+        return Ok(format!("in {}: {}", name, listed.join(" · ")))
+    }
 
+    ///`:run [n]`, previews a code block of the answer selected and runs it once confirmed
+    fn prepare_run(&mut self, n: Option<&str>) -> Result<String, String> {
+        if self.is_running() {
+            return Err("a command is already running".to_string())
+        }
+        let block = self.code_block(n)?;
+        let (block, interpreter) = match block.shell_code() {
+            Some(code) => (CodeBlock { code, ..block }, None),
+            None => match self.config.run.interpreters.get(&block.language) {
+                Some(interpreter) => (block, Some(interpreter.clone())),
+                None => {
+                    return Err(format!(
+                        "nothing runs {} blocks, add it under [run.interpreters]",
+                        block.language_name()
+                    ))
+                }
+            },
+        };
 
-impl Default for App {
-    fn default() -> App {
-        App {
-            should_end: false,
-            scroll: 0,
+        let program = interpreter.clone().unwrap_or_else(|| self.config.shell.program());
+        self.confirm = Some(Confirm {
+            title: format!("run {} with {}?", block.describe(), program),
+            preview: block.code.clone(),
+            diff: false,
+            action: Action::Run { block, interpreter },
+        });
+        return Ok("y runs it, n doesn't".to_string())
+    }
 
-            username: String::new(),
+    ///`:write [n] [path]`, shows what writing a code block of the answer selected to a file
+    ///changes and writes it once confirmed. Patches are applied to the file they name
+    fn prepare_write(&mut self, args: Vec<&str>) -> Result<String, String> {
+        let (n, path) = match args.as_slice() {
+            [] => (None, None),
+            [n] if n.parse::<usize>().is_ok() => (Some(*n), None),
+            [path] => (None, Some(path.to_string())),
+            [n, path] => (Some(*n), Some(path.to_string())),
+            _ => return Err("usage: :write [n] [path]".to_string()),
+        };
+        let block = self.code_block(n)?;
+
+        let path = match path.or_else(|| block.target()) {
+            Some(path) => path,
+            None => {
+                //asks for the path on the command line
+                self.command_prompt = match n {
+                    Some(n) => Some(format!(":write {} ", n)),
+                    None => Some(":write ".to_string()),
+                };
+                return Ok(String::new())
+            }
+        };
+        let write = blocks::plan_write(&block, &path)?;
 
-            display_input: String::new(),
-            internal_input: String::new(),
-            input_mode: InputMode::Normal, 
+        let verb = match (block.is_patch(), write.path.exists()) {
+            (true, _) => "patch",
+            (false, true) => "overwrite",
+            (false, false) => "create",
+        };
+        self.confirm = Some(Confirm {
+            title: format!("{} {}?", verb, path),
+            preview: write.diff.clone(),
+            diff: true,
+            action: Action::Write(write),
+        });
+        return Ok("y writes it, n doesn't".to_string())
+    }
 
-            content: Vec::new(),
+    ///What waits for y or n, if anything
+    pub fn get_confirm(&self) -> Option<&Confirm> {
+        return self.confirm.as_ref()
+    }
 
-            command: String::from(""),
+    pub fn cancel_confirm(&mut self) {
+        match self.confirm.take() {
+            //the model is told, it may do without
+            Some(Confirm { action: Action::Tool(call), .. }) => {
+                self.tool_result(Message::from(
+                    "tool".to_string(),
+                    "the user didn't allow this call".to_string(),
+                    MessageType::Tool { call }
+                ));
+            }
+            Some(_) => self.feedback(Ok("cancelled".to_string())),
+            None => {}
+        }
+    }
+
+    ///Does what was waiting for a yes
+    pub fn accept_confirm(&mut self) {
+        let confirm = match self.confirm.take() {
+            Some(confirm) => confirm,
+            None => return,
+        };
+        let result = match confirm.action {
+            Action::Run { block, interpreter: None } => {
+                self.spawn_shell(block.code, None);
+                Ok(String::new())
+            }
+            Action::Run { block, interpreter: Some(interpreter) } => {
+                let script = std::env::temp_dir().join(format!(
+                    "gpterm-block-{}-{}.{}",
+                    std::process::id(), self.content.len(), block.language
+                ));
+                match std::fs::write(&script, &block.code) {
+                    Ok(()) => {
+                        let command = format!("{} {}", interpreter, script.display());
+                        self.spawn_shell(command, Some((interpreter, script)));
+                        Ok(String::new())
+                    }
+                    Err(err) => Err(format!("couldn't write {}: {}", script.display(), err)),
+                }
+            }
+            Action::Tool(call) => {
+                self.spawn_tool(call);
+                Ok(String::new())
+            }
+            Action::Delete(index) => {
+                self.content.remove(index);
+                //the indices the summary covers moved
+                self.summary = None;
+                self.cursor = self.content.len().checked_sub(1).map(|last| index.min(last));
+                self.autosave();
+                Ok(format!("deleted message {}", index + 1))
+            }
+            Action::Write(write) => {
+                let path = write.path.display();
+                let created = match write.path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+                    _ => Ok(()),
+                };
+                match created.and_then(|_| std::fs::write(&write.path, &write.contents)) {
+                    Ok(()) => Ok(format!("wrote {}", path)),
+                    Err(err) => Err(format!("couldn't write {}: {}", path, err)),
+                }
+            }
+        };
+        self.feedback(result);
+    }
+
+    ///Starts on the calls an answer asked for, unless it went on for too many rounds
+    fn start_tools(&mut self, calls: Vec<ToolCall>) {
+        let request = match self.get_call() {
+            Some(request) => request.clone(),
+            None => return,
+        };
+        let max_rounds = self.config.tools.max_rounds;
+        if request.tool_rounds.len() >= max_rounds {
+            warn!("{} asked for tools again after {} rounds, stopping", request.model, max_rounds);
+            self.push_answer(Message::from(
+                SYSTEM_SENDER.to_string(),
+                format!("stopped after {} rounds of tool calls, [tools] max_rounds allows more", max_rounds),
+                MessageType::Answer
+            ));
+            return;
+        }
+        debug!("{} asked for {} tool calls", request.model, calls.len());
+        self.tool_loop = Some(ToolLoop { request, calls, results: Vec::new() });
+        self.next_tool_call();
+    }
+
+    ///Asks about the next call, or sends the results back once all are done
+    fn next_tool_call(&mut self) {
+        let tool_loop = match self.tool_loop.take() {
+            Some(tool_loop) => tool_loop,
+            None => return,
+        };
+        let call = match tool_loop.calls.get(tool_loop.results.len()) {
+            Some(call) => call.clone(),
+            None => {
+                let mut request = tool_loop.request;
+                request.tool_rounds.push(ToolRound { calls: tool_loop.calls, results: tool_loop.results });
+                self.send_request(request, None);
+                return;
+            }
+        };
+
+        let preview = match serde_json::from_str::<serde_json::Value>(&call.arguments) {
+            Ok(arguments) => serde_json::to_string_pretty(&arguments).unwrap_or_default(),
+            Err(_) => call.arguments.clone(),
+        };
+        self.confirm = Some(Confirm {
+            title: format!("let {} call {}?", tool_loop.request.model, call.name),
+            preview,
+            diff: false,
+            action: Action::Tool(call),
+        });
+        self.tool_loop = Some(tool_loop);
+    }
+
+    ///Runs an allowed call in the background, see `poll_answer`
+    fn spawn_tool(&mut self, call: ToolCall) {
+        let config = self.config.tools.clone();
+        self.status = format!("calling {}...", tools::describe(&call));
+        self.running = Some(tokio::task::spawn_blocking(move || {
+            let result = tools::run(&call, &config);
+            Message::from("tool".to_string(), result, MessageType::Tool { call })
+        }));
+    }
+
+    ///Keeps what the call asked about returned and moves on to the next
+    fn tool_result(&mut self, message: Message) {
+        if let Some(tool_loop) = self.tool_loop.as_mut() {
+            tool_loop.results.push(message.body.clone());
+        }
+        self.content.push(message);
+        self.next_tool_call();
+    }
+
+    ///Key the models of the current provider are cached under, its base url
+    fn models_key(&self) -> String {
+        match self.config.endpoint_named(&self.provider_name).map(|endpoint| endpoint.base_url()) {
+            Some(Ok(base_url)) if !base_url.is_empty() => return base_url,
+            _ => return self.provider_name.clone(),
+        }
+    }
+
+    ///Takes the context lengths the current provider reported as the models' windows
+    fn learn_windows(&mut self) {
+        let models = match self.models.get(&self.models_key()) {
+            Some(models) => models.clone(),
+            None => return,
+        };
+        for model in models {
+            if let Some(length) = model.context_length {
+                self.config.context.reported.insert(model.id, length);
+            }
+        }
+    }
+
+    ///Asks the current provider for its models in the background, see `poll_answer`
+    fn fetch_models(&mut self, open: bool) -> Result<String, String> {
+        let handler = match self.api_handler.take() {
+            Some(handler) => handler,
+            None => return Err("wait for the current request to finish".to_string()),
+        };
+
+        self.status = format!("asking {} for its models...", self.provider_name);
+        let task = tokio::spawn(async move {
+            let models = handler.list_models().await.map_err(|err| err.to_string());
+            (handler, models)
+        });
+        self.discovery = Some(Discovery { task, open });
+        return Ok(String::new())
+    }
+
+    ///Caches the models the current provider listed, on disk too
+    fn remember_models(&mut self, models: Vec<ModelInfo>) {
+        debug!("{} serves {} models", self.provider_name, models.len());
+        self.models.insert(&self.models_key(), models);
+        if let Some(path) = &self.config.models.cache_file {
+            if let Err(err) = self.models.save(path) {
+                warn!("Couldn't save the model cache to {}: {}", path.display(), err);
+            }
+        }
+        self.learn_windows();
+    }
+
+    ///`:model [name]`, switches model or opens the picker, fetching the models first
+    ///when the cached ones are too old
+    fn switch_model(&mut self, name: Option<&str>) -> Result<String, String> {
+        if let Some(name) = name {
+            return self.set_model(name)
+        }
+        let key = self.models_key();
+        if self.models.fresh(&key, self.config.models.max_age()).is_some() {
+            self.open_picker();
+            return Ok(String::new())
+        }
+        return self.fetch_models(true)
+    }
+
+    fn set_model(&mut self, name: &str) -> Result<String, String> {
+        let listed = self.models.get(&self.models_key());
+        if listed.is_some_and(|models| !models.iter().any(|model| model.id == name)) {
+            warn!("{} doesn't list {}, using it anyway", self.provider_name, name);
+        }
+        self.selected_model = name.to_string();
+        return Ok(format!("using {}, {} tokens of context", name, self.context_window(name)))
+    }
+
+    ///Tokens `model` takes in, as the budget counts them
+    pub fn context_window(&self, model: &str) -> usize {
+        return self.config.context.window(model)
+    }
+
+    fn open_picker(&mut self) {
+        let models = self.models.get(&self.models_key()).cloned().unwrap_or_default();
+        self.picker = Some(ModelPicker::new(models, &self.selected_model));
+    }
+
+    pub fn get_picker(&self) -> Option<&ModelPicker> {
+        return self.picker.as_ref()
+    }
+
+    pub fn picker_mut(&mut self) -> Option<&mut ModelPicker> {
+        return self.picker.as_mut()
+    }
+
+    pub fn close_picker(&mut self) {
+        self.picker = None;
+    }
+
+    ///Switches to the model selected in the picker
+    pub fn choose_model(&mut self) {
+        let choice = match self.picker.take().and_then(|picker| picker.choice().cloned()) {
+            Some(choice) => choice,
+            None => return,
+        };
+        let result = self.set_model(&choice.id);
+        self.feedback(result);
+    }
+
+    ///Fetches the models again, for the picker showing them
+    pub fn refresh_models(&mut self) {
+        self.picker = None;
+        let result = self.fetch_models(true);
+        self.feedback(result);
+    }
+
+    ///Completes the model name of `:model` from the cached models, fetching them when there are none
+    pub fn complete_command(&mut self) {
+        let partial = match self.command.trim_start_matches(':').strip_prefix("model ") {
+            Some(partial) => partial.trim_start().to_string(),
+            None => return,
+        };
+        let candidates = match self.models.get(&self.models_key()) {
+            Some(models) => models::complete(models, &partial),
+            None => {
+                if self.fetch_models(false).is_ok() {
+                    self.status = "fetching the models, Tab again once they are in".to_string();
+                }
+                return;
+            }
+        };
+        let completed = match candidates.as_slice() {
+            [] => return,
+            [only] => only.clone(),
+            candidates => common_prefix(candidates),
+        };
+        self.command = format!("model {}", completed);
+    }
+
+    ///Lists the models of the current provider in the transcript
+    fn list_models(&mut self) -> Result<String, String> {
+        let handler = match self.api_handler.take() {
+            Some(handler) => handler,
+            None => return Err("wait for the current request to finish".to_string()),
+        };
+
+        self.streaming = None;
+        self.status = format!("asking {} for its models...", self.provider_name);
+        self.pending = Some(tokio::spawn(async move {
+            let body = match handler.list_models().await {
+                Ok(models) => models
+                    .iter()
+                    .map(|model| match &model.owned_by {
+                        Some(owner) => format!("{} ({})", model.id, owner),
+                        None => model.id.clone(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => format!("couldn't list models: {}", err),
+            };
+            let output = Message::from(
+                SYSTEM_SENDER.to_string(),
+                body,
+                MessageType::Answer
+            );
+            (handler, output, None)
+        }));
+
+        return Ok(String::new())
+    }
+
+    ///`:provider [name]`, lists providers or switches to one
+    fn switch_provider(&mut self, name: Option<&str>) -> Result<String, String> {
+        let name = match name {
+            Some(name) => name,
+            None => {
+                return Ok(format!("providers: {}", self.config.provider_names().join(", ")))
+            }
+        };
+
+        let endpoint = match self.config.endpoint_named(name) {
+            Some(endpoint) => endpoint,
+            None => return Err(format!("unknown provider {}", name)),
+        };
+        let provider = provider::from_endpoint(endpoint, &self.token)
+            .map_err(|err| err.to_string())?;
+
+        match self.api_handler.as_mut() {
+            Some(handler) => {
+                handler.set_provider(name, provider, endpoint.stream);
+                self.counter = Some(handler.provider());
+            }
+            None => return Err("wait for the current request to finish".to_string()),
+        }
+        //the new provider may count differently
+        for message in &self.content {
+            *message.tokens.borrow_mut() = None;
+        }
+        self.attachment_tokens.borrow_mut().clear();
+
+        self.provider_name = name.to_string();
+        self.learn_windows();
+        return Ok(format!("using provider {}", name))
+    }
+
+    ///Appends a streamed piece of the answer to the transcript
+    fn push_chunk(&mut self, text: String) {
+        let index = match self.streaming {
+            Some(index) => index,
+            None => {
+                self.content.push(Message::from(
+                    self.selected_model.clone(),
+                    String::new(),
+                    MessageType::Answer
+                ));
+                self.content.len() - 1
+            }
+        };
+
+        self.content[index].body.push_str(&text);
+        *self.content[index].tokens.borrow_mut() = None;
+        self.streaming = Some(index);
+    }
+
+    ///Picks up progress of every tab, the ones in the background too,
+    ///returns true when the transcript shown changed
+    pub fn poll_answer(&mut self) -> bool {
+        let changed = self.poll_tab(true);
+        for index in 0..self.tabs.len() {
+            if index == self.active || !self.tabs[index].is_busy() {
+                continue;
+            }
+            self.swap_tab(index);
+            self.poll_tab(false);
+            self.swap_tab(index);
+        }
+        return changed
+    }
+
+    ///Picks up progress of the request in flight, popups only open for the tab shown.
+    ///Returns true when the transcript changed
+    fn poll_tab(&mut self, foreground: bool) -> bool {
+        let mut changed = false;
+        if let Some(running) = self.running.as_mut() {
+            match running.now_or_never() {
+                Some(Ok(output)) => {
+                    self.running = None;
+                    self.status = String::new();
+                    match output.message_type {
+                        MessageType::Tool { .. } => self.tool_result(output),
+                        _ => self.content.push(output),
+                    }
+                    self.autosave();
+                    changed = true;
+                }
+                Some(Err(err)) => {
+                    self.running = None;
+                    error!("Shell task failed: {}", err);
+                    self.status = "command failed to run".to_string();
+                    self.tool_loop = None;
+                }
+                None => {}
+            }
+        }
+        if let Some(discovery) = self.discovery.as_mut() {
+            let open = discovery.open;
+            match (&mut discovery.task).now_or_never() {
+                Some(Ok((handler, models))) => {
+                    self.discovery = None;
+                    self.api_handler = Some(handler);
+                    self.status = String::new();
+                    match models {
+                        Ok(models) => {
+                            self.remember_models(models);
+                            //the picker would change the model of the tab shown
+                            match (open, foreground) {
+                                (true, true) => self.open_picker(),
+                                (true, false) => self.status = "models listed, :model picks one".to_string(),
+                                (false, _) => {}
+                            }
+                        }
+                        Err(err) => {
+                            warn!("Couldn't list models: {}", err);
+                            self.status = format!("couldn't list models: {}", err);
+                        }
+                    }
+                    changed = true;
+                }
+                Some(Err(err)) => {
+                    self.discovery = None;
+                    error!("Model list task failed: {}", err);
+                    self.status = "listing models failed, client lost".to_string();
+                }
+                None => {}
+            }
+        }
+        if let Some(comparison) = self.comparison.as_mut() {
+            if comparison.poll() {
+                if !comparison.is_waiting() {
+                    self.status = "all answers in, :keep <n> keeps one".to_string();
+                }
+                changed = true;
+            }
+        }
+        if let Some(events) = self.api_events.as_mut() {
+            let events: Vec<ApiEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+            for event in events {
+                self.status = event.to_string();
+                if let ApiEvent::Chunk(text) = event {
+                    self.push_chunk(text);
+                    changed = true;
+                }
+            }
+        }
+
+        let finished = match self.pending.as_mut() {
+            Some(pending) => pending.now_or_never(),
+            None => return changed,
+        };
+
+        match finished {
+            Some(Ok((handler, output, summary))) => {
+                self.pending = None;
+                self.api_handler = Some(handler);
+                if summary.is_some() {
+                    self.summary = summary;
+                }
+                self.status = String::new();
+                debug!("CALL: {:#?}", self.get_call());
+                debug!("RESPONSE: {:#?}", self.get_response());
+
+                let calls = match output.is_system() {
+                    true => Vec::new(),
+                    false => self.get_response().map(|response| response.tool_calls.clone()).unwrap_or_default(),
+                };
+
+                //the final answer replaces what was streamed
+                match self.streaming.take() {
+                    Some(index) => {
+                        self.content[index] = output;
+                        self.internal_input = String::new();
+                    }
+                    //answers made only of calls show as the calls
+                    None if !calls.is_empty() && output.body.trim().is_empty() => {}
+                    None => self.push_answer(output),
+                }
+                if !calls.is_empty() {
+                    self.start_tools(calls);
+                }
+                self.autosave();
+                return true
+            }
+            Some(Err(err)) => {
+                self.pending = None;
+                error!("Request task failed: {}", err);
+                self.status = "request failed, client lost".to_string();
+                return changed
+            }
+            None => return changed,
+        }
+    }
+
+    pub fn get_provider_name(&self) -> &String {
+        return &self.provider_name
+    }
+
+    pub fn get_selected_model(&self) -> &String {
+        return &self.selected_model
+    }
+
+    pub fn get_status(&self) -> &String {
+        return &self.status
+    }
+
+    pub fn get_max_offset(&mut self) -> usize {
+        //nice
+        let body_count : usize= self.content
+            .iter()
+            .map(|message| {
+                message.get_body_lines(self.size.0)
+            })
+            .sum();
+
+         let max = std::cmp::max(
+            //account for lines and widget height
+            (body_count + 2*self.content.len()) as i32 - 28, 0
+        ) as usize; 
+
+        self.max_offset = max;
+        max
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        let scroll = self.get_max_offset(); //widget height
+        if scroll > 0{
+            self.scroll = scroll;
+            return;
+        }
+        self.scroll = 0
+    }
+    pub fn input_mode(&self) -> InputMode{ 
+        return self.input_mode;
+    }
+
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        match mode {
+            InputMode::Command => {
+                self.command_status = CommandStatus::Okay;
+                self.command = String::from(':');
+            }
+            //leaving the input box gives up on the template being filled in, or the edit
+            InputMode::Normal => {
+                self.template_fill = None;
+                if self.editing.take().is_some() {
+                    self.display_input = String::new();
+                }
+            }
+            InputMode::Insert | InputMode::Search => {}
+        }
+        self.input_mode = mode;
+    }
+
+    pub fn scroll_down (&mut self) { 
+        if self.scroll != 0 {
+            self.scroll -= 1;
+        }
+    }
+
+    pub fn get_scroll(&self) -> usize {
+        return self.scroll;
+    }
+
+    pub fn scroll_up (&mut self) { 
+        if self.scroll < self.max_offset {
+            self.scroll += 1;
+        }
+        
+    }
+    
+    pub fn get_display_input(&self) -> String {
+        return self.display_input.clone()
+    }
+
+    pub fn get_input(&self) -> String{
+        return self.internal_input.clone();
+    }
+
+    pub fn set_display_input(&mut self, text: String) {
+        self.display_input = text;
+    }
+
+    ///Last message of the transcript, the answer once a request is done
+    pub fn last_message(&self) -> Option<&Message> {
+        return self.content.last()
+    }
+
+    pub fn push_input(&mut self, char: char) {
+        self.display_input.push(char);
+    }
+    
+    pub fn pop_input(&mut self) {
+        self.display_input.pop();
+    }
+
+    pub fn push_answer(&mut self,message: Message) {
+        self.content.push(message);
+        self.internal_input = String::new();
+   }
+
+
+    pub fn push_content(&mut self,
+        sender: String,
+        message_type: MessageType,
+        message: String,
+    ) {
+        self.content.push(Message::from(sender, message, message_type));
+   }
+
+
+
+    pub fn get_content(&self) -> Vec<Spans<'_>>{
+
+        let matches = self.search_matches();
+        let current = self.search.as_ref().and_then(|search| matches.get(search.current));
+        let mut span_vec: Vec<Spans> = Vec::new();
+        for (index, message) in self.content.iter().enumerate() {
+            let mut sender_spans = self.sender_from(message);
+            let found: Vec<&Match> = matches.iter().filter(|found| found.message == index).collect();
+            let mut body_spans = match found.is_empty() {
+                true => self.body_from(message),
+                false => highlighted(&message.body, &found, current),
+            };
+            if self.selected_message() == Some(index) {
+                let style = Style::default().bg(Color::DarkGray);
+                sender_spans.0.insert(0, Span::styled("▶ ", Style::default().fg(Color::Yellow)));
+                for spans in std::iter::once(&mut sender_spans).chain(body_spans.iter_mut()) {
+                    spans.0.iter_mut().for_each(|span| span.style = span.style.patch(style));
+                }
+            }
+
+            let line = self.line_from(&message.message_type);
+
+            span_vec.push(line);
+            span_vec.push(sender_spans);
+            for spans in body_spans {
+                span_vec.push(spans);
+            }
+        }
+
+
+
+        return span_vec;
+    }
+
+    ///`/`, starts looking for what is typed next
+    pub fn start_search(&mut self) {
+        self.search = Some(Search::new(self.scroll));
+        self.set_input_mode(InputMode::Search);
+    }
+
+    pub fn get_search(&self) -> Option<&Search> {
+        return self.search.as_ref()
+    }
+
+    ///Matches of the search in the transcript, in order
+    pub fn search_matches(&self) -> Vec<Match> {
+        match &self.search {
+            Some(search) => return search.find(self.content.iter().map(|message| message.body.as_str())),
+            None => return Vec::new(),
+        }
+    }
+
+    ///Adds to the pattern and jumps to the first match from where the search started
+    pub fn push_search(&mut self, c: char) {
+        if let Some(search) = self.search.as_mut() {
+            search.push(c);
+        }
+        self.jump_from_origin();
+    }
+
+    pub fn pop_search(&mut self) {
+        if let Some(search) = self.search.as_mut() {
+            search.pop();
+        }
+        self.jump_from_origin();
+    }
+
+    fn jump_from_origin(&mut self) {
+        let origin = match &self.search {
+            Some(search) => search.origin,
+            None => return,
+        };
+        let matches = self.search_matches();
+        let first = matches.iter().position(|found| self.line_offset(found.message, found.line) >= origin);
+        match (first, matches.is_empty()) {
+            (_, true) => self.scroll = origin,
+            (Some(index), false) => self.jump_to(index, &matches),
+            //only matches above, the search wraps around
+            (None, false) => self.jump_to(0, &matches),
+        }
+    }
+
+    ///Enter, keeps the matches highlighted for `n` and `N`
+    pub fn finish_search(&mut self) {
+        let count = self.search_matches().len();
+        match &self.search {
+            Some(search) if search.pattern.is_empty() => self.search = None,
+            Some(search) if count == 0 => self.status = format!("no match for {}", search.pattern),
+            Some(_) => self.status = format!("{} matches, n and N go through them", count),
+            None => {}
+        }
+        self.set_input_mode(InputMode::Normal);
+    }
+
+    ///Esc while typing, back to where the search started
+    pub fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.scroll = search.origin;
+        }
+        self.set_input_mode(InputMode::Normal);
+    }
+
+    ///Esc in normal mode, the matches aren't highlighted anymore
+    pub fn clear_search(&mut self) {
+        self.search = None;
+    }
+
+    ///`n`, or `N` backwards, jumps to the next match, wrapping around
+    pub fn next_match(&mut self, forward: bool) {
+        let matches = self.search_matches();
+        let current = match &self.search {
+            Some(_) if matches.is_empty() => {
+                self.status = "no match".to_string();
+                return;
+            }
+            Some(search) => search.current.min(matches.len() - 1),
+            None => return,
+        };
+        let next = match forward {
+            true => (current + 1) % matches.len(),
+            false => (current + matches.len() - 1) % matches.len(),
+        };
+        self.jump_to(next, &matches);
+        self.status = format!("match {} of {}", next + 1, matches.len());
+    }
+
+    ///Scrolls so the match is in view, a few lines below the top
+    fn jump_to(&mut self, index: usize, matches: &[Match]) {
+        let offset = self.line_offset(matches[index].message, matches[index].line);
+        if let Some(search) = self.search.as_mut() {
+            search.current = index;
+        }
+        self.scroll_to_line(offset);
+    }
+
+    ///Scrolls so `offset` is in view, a few lines below the top
+    fn scroll_to_line(&mut self, offset: usize) {
+        self.scroll = offset.saturating_sub(JUMP_CONTEXT).min(self.get_max_offset());
+    }
+
+    ///Line of the transcript a line of a message's body is on, counted like `get_max_offset` does
+    fn line_offset(&self, message: usize, line: usize) -> usize {
+        let width = self.size.0.max(1) as usize;
+        let before: usize = self.content[..message]
+            .iter()
+            .map(|message| message.get_body_lines(self.size.0) + 2)
+            .sum();
+        let within: usize = self.content[message].body
+            .split('\n')
+            .take(line)
+            .map(|line| 1 + line.chars().count() / width)
+            .sum();
+        //the separator and the sender come before the body
+        return before + 2 + within
+    }
+
+    pub fn update_size(&mut self) {
+        self.size = get_terminal_sizes();
+    }
+
+    fn body_from <'a> (&self, message: &'a Message) -> Vec<Spans<'a>> {
+
+        match message.message_type {
+            MessageType::Query => {
+                vec![
+                    Spans::from(vec![Span::raw(message.body.clone())])
+                ]
+            }
+            MessageType::Answer => {
+                self.parse_answer(&message.body)
+            }
+            MessageType::Shell { .. } | MessageType::Tool { .. } => {
+                message.body.lines().map(|line| Spans::from(Span::raw(line))).collect()
+            }
+        }
+    }
+    fn sender_from<'a>(&self, message: &'a Message) -> Spans<'a> {
+
+        match &message.message_type {
+
+            MessageType::Query => {
+                Spans::from(vec![
+                    Span::styled(
+                        &message.sender,
+                        Style::default()
+                            .add_modifier(Modifier::BOLD)
+                            .fg(Color::Blue)
+                    ),
+                    Span::raw(":"),
+                    pinned_note(message),
+                ])
+            }
+            MessageType::Answer => {
+                let mut spans = vec![
+                    Span::styled(
+                        &message.sender,
+                        Style::default()
+                            .add_modifier(Modifier::BOLD)
+                            .fg(Color::Magenta)
+                    ),
+                    Span::raw(":"),
+                    pinned_note(message),
+                ];
+                if let Some(usage) = &message.usage {
+                    spans.push(Span::styled(
+                        format!(" {}", usage_note(usage, self.message_cost(message))),
+                        Style::default().fg(Color::DarkGray)
+                    ));
+                }
+                Spans::from(spans)
+            }
+            MessageType::Shell { command, code } => {
+                let exit_style = match code {
+                    Some(0) => Style::default().fg(Color::DarkGray),
+                    _ => Style::default().fg(Color::Red),
+                };
+                //a block run as it is can take many lines
+                let mut lines = command.lines();
+                let more = match lines.clone().count() > 1 {
+                    true => " …",
+                    false => "",
+                };
+                Spans::from(vec![
+                    Span::styled(
+                        format!("$ {}{}", lines.next().unwrap_or(""), more),
+                        Style::default()
+                            .add_modifier(Modifier::BOLD)
+                            .fg(Color::Green)
+                    ),
+                    Span::styled(format!(" ({})", exit_note(*code)), exit_style),
+                    pinned_note(message),
+                ])
+            }
+            MessageType::Tool { call } => {
+                Spans::from(vec![
+                    Span::styled(
+                        format!("tool {}", tools::describe(call)),
+                        Style::default()
+                            .add_modifier(Modifier::BOLD)
+                            .fg(Color::Yellow)
+                    ),
+                    pinned_note(message),
+                ])
+            }
+        }
+    }
+
+    fn line_from<'a>(&self, message_type:  &MessageType) -> Spans<'a>{
+        match message_type {
+            MessageType::Query => {
+                Spans::from (
+                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
+                        Style::default().fg(Color::Blue))]
+                )
+            }
+            MessageType::Answer => {
+                Spans::from (
+                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
+                        Style::default().fg(Color::Magenta))]
+                )
+            }
+            MessageType::Shell { .. } => {
+                Spans::from (
+                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
+                        Style::default().fg(Color::Green))]
+                )
+            }
+            MessageType::Tool { .. } => {
+                Spans::from (
+                    vec![Span::styled(format!("{:─>width$}","",width=self.size.0 as usize ),
+                        Style::default().fg(Color::Yellow))]
+                )
+            }
+        }
+    }
+
+    fn parse_answer<'a>(&self, answer: &'a str) -> Vec<Spans<'a>> {
+        let partial = answer.split('\n').collect::<Vec<&str>>();
+
+        let mut span_vec: Vec<Spans> = Vec::new();
+
+        for line in partial {
+            span_vec.push(
+                Spans::from(vec![Span::raw(line)])
+            );
+        }
+
+        return span_vec;
+    }
+    pub fn set_username(&mut self, name: String) {
+        self.username = name;
+    }
+    pub fn get_username(&self) -> String {
+        self.username.clone()
+    }
+
+    pub fn get_command(&self) -> String {
+        return self.command.clone()
+    }
+
+    pub fn send_command(&mut self) {
+        let command = self.command.trim_start_matches(':').to_string();
+        if let Some(shell) = command.strip_prefix('!') {
+            let result = self.run_shell(shell.trim());
+            self.set_input_mode(InputMode::Normal);
+            self.feedback(result);
+            return;
+        }
+        let mut args = command.split_whitespace();
+        let name = args.next();
+
+        let result = match name {
+            Some("timeout") => self.set_next_timeout(args.next()),
+            Some("provider") => self.switch_provider(args.next()),
+            Some("models") => self.list_models(),
+            Some("model") => self.switch_model(args.next()),
+            Some("profile") => self.switch_profile(args.next()),
+            Some("params") => self.open_params(),
+            Some("set") => match args.next() {
+                Some(field) => self.set_param(field, &args.collect::<Vec<&str>>().join(" ")),
+                None => Err("usage: :set <parameter> [value]".to_string()),
+            },
+            Some("export") => self.export(args.next()),
+            Some("context") => self.set_context(args.collect()),
+            Some("persona") => self.switch_persona(args.next()),
+            Some("system") => self.set_system(args.collect::<Vec<&str>>().join(" ")),
+            Some("save") => self.save_session(args.next()),
+            Some("open") => self.open_session(args.next()),
+            Some("sessions") => self.list_sessions(),
+            Some("find") => self.find_sessions(args.collect()),
+            Some("pin") => self.set_pinned(args.next(), true),
+            Some("unpin") => self.set_pinned(args.next(), false),
+            Some("template") => self.use_template(args.collect()),
+            Some("blocks") => self.list_blocks(),
+            Some("run") => self.prepare_run(args.next()),
+            Some("write") => self.prepare_write(args.collect()),
+            Some("compare") => self.set_compare(args.collect()),
+            Some("keep") => self.keep_answer(args.next()),
+            Some("tabnew") => self.new_tab(),
+            Some("tabclose") => self.close_tab(),
+            Some("tab") => self.goto_tab(args.next()),
+            _ => Err("Command not found".to_string()),
+        };
+
+        //templates carry on in the input box
+        match (name, &result) {
+            (Some("template"), Ok(_)) if self.template_fill.is_some() || !self.display_input.is_empty() => {
+                self.set_input_mode(InputMode::Insert)
+            }
+            _ => self.set_input_mode(InputMode::Normal),
+        }
+        self.feedback(result);
+
+        if let Some(prompt) = self.command_prompt.take() {
+            self.set_input_mode(InputMode::Command);
+            self.command = prompt;
+        }
+    }
+
+    ///`:compare <target> <target>...`, sends the next queries to every target at once.
+    ///A target is a model, `model@profile`, or `@profile` for the current model.
+    ///`:compare off` goes back to one answer, without targets the current ones are listed
+    fn set_compare(&mut self, args: Vec<&str>) -> Result<String, String> {
+        let describe = |targets: &[Target]| {
+            targets.iter().map(|target| target.describe()).collect::<Vec<String>>().join(", ")
+        };
+        match args.as_slice() {
+            [] if self.compare_targets.is_empty() => return Ok("not comparing, :compare <model> <model>...".to_string()),
+            [] => return Ok(format!("comparing {}", describe(&self.compare_targets))),
+            ["off"] => {
+                self.compare_targets.clear();
+                self.comparison = None;
+                return Ok("back to one answer".to_string())
+            }
+            [_] => return Err("compare at least two models or profiles".to_string()),
+            args if args.len() > compare::MAX_TARGETS => {
+                return Err(format!("at most {} answers fit side by side", compare::MAX_TARGETS))
+            }
+            args => {
+                let targets = args
+                    .iter()
+                    .map(|arg| Target::parse(arg, &self.selected_model))
+                    .collect::<Result<Vec<Target>, String>>()?;
+                //unknown profiles are caught now rather than on sending
+                for target in &targets {
+                    self.config.params.resolve(&target.model, target.profile.as_deref())?;
+                }
+                self.compare_targets = targets;
+                return Ok(format!("comparing {}, ask away", describe(&self.compare_targets)))
+            }
+        }
+    }
+
+    ///Sends what was typed to every target at once, each with its own client.
+    ///The query only joins the transcript with the answer kept, see `:keep`
+    fn send_comparison(&mut self) -> Result<String, String> {
+        if self.comparison.as_ref().is_some_and(|comparison| comparison.is_waiting()) {
+            return Err("wait for the answers being compared".to_string())
+        }
+        let typed = self.get_display_input();
+        let query = self.attachments_of(&typed).and_then(|attachments| attach::inline(&typed, &attachments))?;
+
+        //everything is checked before anything is sent
+        let mut requests = Vec::new();
+        for target in &self.compare_targets {
+            let mut params = self.config.params
+                .resolve(&target.model, target.profile.as_deref())
+                .map_err(|err| format!("{}: {}", target.describe(), err))?;
+            self.overrides.apply(&mut params);
+            params.validate().map_err(|err| format!("{}: {}", target.describe(), err))?;
+            self.check_budget(&typed, &target.model, params.max_tokens as usize)
+                .map_err(|err| format!("{}: {}", target.describe(), err))?;
+
+            //each model gets what fits its own window
+            let model = &target.model;
+            let rest = self.rest(&query, model);
+            let (sent, dropped) = self.plan(model, self.count_tokens(model, &rest), params.max_tokens as usize);
+            //a summary is only used when there is one already, comparing doesn't write any
+            let summary = match self.summary_job(dropped) {
+                Some(SummaryJob::Cached(text)) => context::summary_turn(&text),
+                _ => String::new(),
+            };
+            let request = CompletionRequest {
+                model: target.model.clone(),
+                system: self.system.clone(),
+                prompt: format!("{}{}{}", summary, self.turns(&sent), rest),
+                query: query.clone(),
+                params,
+                timeout: self.next_timeout,
+                tools: Vec::new(),
+                tool_rounds: Vec::new(),
+            };
+            requests.push((target.clone(), request));
+        }
+        let config = Config { provider: self.provider_name.clone(), ..self.config.clone() };
+        let mut columns = Vec::new();
+        for (target, request) in requests {
+            let handler = ApiHandler::new(self.token.clone(), &config).map_err(|err| err.to_string())?;
+            columns.push(Column::spawn(target, handler, request));
+        }
+
+        self.next_timeout = None;
+        self.display_input = String::new();
+        self.comparison = Some(Comparison { query, columns });
+        return Ok(format!("waiting for {} answers...", self.compare_targets.len()))
+    }
+
+    pub fn get_comparison(&self) -> Option<&Comparison> {
+        return self.comparison.as_ref()
+    }
+
+    ///Back to the transcript, the answers compared are dropped
+    pub fn close_comparison(&mut self) {
+        if self.comparison.as_ref().is_some_and(|comparison| !comparison.is_waiting()) {
+            self.comparison = None;
+        }
+    }
+
+    ///`:keep <n>`, puts the query compared and its nth answer in the transcript
+    fn keep_answer(&mut self, n: Option<&str>) -> Result<String, String> {
+        let comparison = match &self.comparison {
+            Some(comparison) if comparison.is_waiting() => return Err("wait for every answer first".to_string()),
+            Some(comparison) => comparison,
+            None => return Err("no answers to keep, see :compare".to_string()),
+        };
+        let index = match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) if (1..=comparison.columns.len()).contains(&n) => n - 1,
+            Some(_) => return Err(format!("pick an answer from 1 to {}", comparison.columns.len())),
+            None => return Err("usage: :keep <n>".to_string()),
+        };
+        let column = &comparison.columns[index];
+        let answer = match &column.answer {
+            Some(answer) if !answer.is_system() => answer.clone(),
+            _ => return Err(format!("{} didn't answer", column.target.describe())),
+        };
+        let target = column.target.describe();
+        let query = comparison.query.clone();
+
+        self.comparison = None;
+        self.push_content(self.get_username(), MessageType::Query, query);
+        self.push_answer(answer);
+        self.autosave();
+        return Ok(format!("kept the answer of {}", target))
+    }
+
+    ///Trades the conversation shown for the one parked at `index`
+    fn swap_tab(&mut self, index: usize) {
+        let tab = &mut self.tabs[index];
+        std::mem::swap(&mut self.display_input, &mut tab.display_input);
+        std::mem::swap(&mut self.internal_input, &mut tab.internal_input);
+        std::mem::swap(&mut self.content, &mut tab.content);
+        std::mem::swap(&mut self.scroll, &mut tab.scroll);
+        std::mem::swap(&mut self.max_offset, &mut tab.max_offset);
+        std::mem::swap(&mut self.api_handler, &mut tab.api_handler);
+        std::mem::swap(&mut self.counter, &mut tab.counter);
+        std::mem::swap(&mut self.pending, &mut tab.pending);
+        std::mem::swap(&mut self.api_events, &mut tab.api_events);
+        std::mem::swap(&mut self.status, &mut tab.status);
+        std::mem::swap(&mut self.next_timeout, &mut tab.next_timeout);
+        std::mem::swap(&mut self.provider_name, &mut tab.provider_name);
+        std::mem::swap(&mut self.streaming, &mut tab.streaming);
+        std::mem::swap(&mut self.summary, &mut tab.summary);
+        std::mem::swap(&mut self.system, &mut tab.system);
+        std::mem::swap(&mut self.persona, &mut tab.persona);
+        std::mem::swap(&mut self.session_name, &mut tab.session_name);
+        std::mem::swap(&mut self.running, &mut tab.running);
+        std::mem::swap(&mut self.confirm, &mut tab.confirm);
+        std::mem::swap(&mut self.discovery, &mut tab.discovery);
+        std::mem::swap(&mut self.tool_loop, &mut tab.tool_loop);
+        std::mem::swap(&mut self.template_fill, &mut tab.template_fill);
+        std::mem::swap(&mut self.profile, &mut tab.profile);
+        std::mem::swap(&mut self.overrides, &mut tab.overrides);
+        std::mem::swap(&mut self.compare_targets, &mut tab.compare_targets);
+        std::mem::swap(&mut self.comparison, &mut tab.comparison);
+        std::mem::swap(&mut self.cursor, &mut tab.cursor);
+        std::mem::swap(&mut self.editing, &mut tab.editing);
+        std::mem::swap(&mut self.selected_model, &mut tab.selected_model);
+    }
+
+    ///Shows the tab at `index`, the one shown so far keeps going in the background
+    pub fn switch_tab(&mut self, index: usize) {
+        if index == self.active || index >= self.tabs.len() {
+            return;
+        }
+        self.swap_tab(self.active);
+        self.swap_tab(index);
+        self.active = index;
+    }
+
+    pub fn next_tab(&mut self) {
+        self.switch_tab((self.active + 1) % self.tabs.len());
+    }
+
+    pub fn previous_tab(&mut self) {
+        self.switch_tab((self.active + self.tabs.len() - 1) % self.tabs.len());
+    }
+
+    ///`:tabnew`, an empty conversation with the provider, model, profile and persona of this one
+    fn new_tab(&mut self) -> Result<String, String> {
+        let config = Config { provider: self.provider_name.clone(), ..self.config.clone() };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut handler = ApiHandler::new(self.token.clone(), &config).map_err(|err| err.to_string())?;
+        handler.set_event_sender(sender);
+
+        self.tabs.push(Conversation {
+            counter: Some(handler.provider()),
+            api_handler: Some(handler),
+            api_events: Some(receiver),
+            provider_name: self.provider_name.clone(),
+            system: self.system.clone(),
+            persona: self.persona.clone(),
+            profile: self.profile.clone(),
+            selected_model: self.selected_model.clone(),
+            ..Conversation::default()
+        });
+        self.switch_tab(self.tabs.len() - 1);
+        return Ok(format!("tab {} of {}", self.active + 1, self.tabs.len()))
+    }
+
+    ///`:tabclose`, drops the conversation shown, unless it is the last one or busy
+    fn close_tab(&mut self) -> Result<String, String> {
+        if self.tabs.len() == 1 {
+            return Err("this is the last tab, q quits".to_string())
+        }
+        if self.is_busy() {
+            return Err("wait for the current request to finish".to_string())
+        }
+        let closed = self.active;
+        let next = match closed + 1 < self.tabs.len() {
+            true => closed + 1,
+            false => closed - 1,
+        };
+        self.switch_tab(next);
+        self.tabs.remove(closed);
+        if self.active > closed {
+            self.active -= 1;
+        }
+        return Ok(format!("closed tab {}", closed + 1))
+    }
+
+    ///`:tab [n]`, shows the nth tab, or lists them
+    fn goto_tab(&mut self, n: Option<&str>) -> Result<String, String> {
+        let index = match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) if (1..=self.tabs.len()).contains(&n) => n - 1,
+            Some(_) => return Err(format!("pick a tab from 1 to {}", self.tabs.len())),
+            None => {
+                let labels: Vec<String> = self.get_tabs()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (label, _))| format!("{} {}", index + 1, label))
+                    .collect();
+                return Ok(labels.join(", "))
+            }
+        };
+        self.switch_tab(index);
+        return Ok(String::new())
+    }
+
+    ///Label of every tab and whether something of it is on its way
+    pub fn get_tabs(&self) -> Vec<(String, bool)> {
+        return self.tabs
+            .iter()
+            .enumerate()
+            .map(|(index, tab)| match index == self.active {
+                true => (tab_label(self.session_name.as_ref(), &self.content), self.is_waiting() || self.is_running()),
+                false => (tab_label(tab.session_name.as_ref(), &tab.content), tab.is_busy()),
+            })
+            .collect()
+    }
+
+    pub fn active_tab(&self) -> usize {
+        return self.active
+    }
+
+    ///Parameters of the next request: the profile, then what was set by hand
+    pub fn params(&self) -> Result<Params, String> {
+        let mut params = self.config.params.resolve(&self.selected_model, self.profile.as_deref())?;
+        self.overrides.apply(&mut params);
+        params.validate()?;
+        return Ok(params)
+    }
+
+    ///Tokens an answer may take, as the budget counts them
+    fn max_tokens(&self) -> usize {
+        match self.params() {
+            Ok(params) => return params.max_tokens as usize,
+            Err(_) => return Params::default().max_tokens as usize,
+        }
+    }
+
+    ///Profile the parameters come from, None when there is none
+    pub fn get_profile(&self) -> Option<&str> {
+        return self.profile.as_deref().or(self.config.params.profile_for(&self.selected_model))
+    }
+
+    ///Whether `field` was set by hand
+    pub fn is_overridden(&self, field: &str) -> bool {
+        return self.overrides.sets(field)
+    }
+
+    ///`:profile [name|none]`, lists the profiles or switches to one
+    fn switch_profile(&mut self, name: Option<&str>) -> Result<String, String> {
+        let names: Vec<&String> = self.config.params.profiles.keys().collect();
+        match name {
+            None if names.is_empty() => return Err("no profiles, add some under [params.profiles]".to_string()),
+            None => {
+                let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+                return Ok(format!("profiles: {}", names.join(", ")))
+            }
+            //back to the model's
+            Some("none") => {
+                self.profile = None;
+                return Ok("using the model's profile".to_string())
+            }
+            Some(name) if !self.config.params.profiles.contains_key(name) => {
+                return Err(format!("no profile {}", name))
+            }
+            Some(name) => {
+                let previous = self.profile.replace(name.to_string());
+                if let Err(err) = self.params() {
+                    self.profile = previous;
+                    return Err(err)
+                }
+                return Ok(format!("using profile {}", name))
+            }
+        }
+    }
+
+    ///`:set <parameter> [value]`, sets a parameter by hand, no value goes back to the profile's
+    fn set_param(&mut self, field: &str, value: &str) -> Result<String, String> {
+        let mut overrides = self.overrides.clone();
+        overrides.set(field, value)?;
+        let previous = std::mem::replace(&mut self.overrides, overrides);
+        match self.params() {
+            Ok(params) => return Ok(format!("{} = {}", field, params.get(field))),
+            Err(err) => {
+                self.overrides = previous;
+                return Err(err)
+            }
+        }
+    }
+
+    ///`:params`, opens the panel editing the parameters
+    fn open_params(&mut self) -> Result<String, String> {
+        self.params_panel = Some(ParamsPanel::default());
+        return Ok(String::new())
+    }
+
+    pub fn get_params_panel(&self) -> Option<&ParamsPanel> {
+        return self.params_panel.as_ref()
+    }
+
+    pub fn params_panel_mut(&mut self) -> Option<&mut ParamsPanel> {
+        return self.params_panel.as_mut()
+    }
+
+    pub fn close_params(&mut self) {
+        self.params_panel = None;
+    }
+
+    ///Starts editing the selected parameter, from its value
+    pub fn edit_param(&mut self) {
+        let params = self.params().unwrap_or_default();
+        if let Some(panel) = self.params_panel.as_mut() {
+            let value = match params.get(panel.field()).as_str() {
+                "-" => String::new(),
+                value => value.to_string(),
+            };
+            panel.editing = Some(value);
+            panel.error = None;
+        }
+    }
+
+    ///Takes what was typed for the selected parameter, unless it isn't valid
+    pub fn apply_param(&mut self) {
+        let (field, value) = match &self.params_panel {
+            Some(panel) => match &panel.editing {
+                Some(value) => (panel.field(), value.clone()),
+                None => return,
+            },
+            None => return,
+        };
+        let result = self.set_param(field, &value);
+        if let Some(panel) = self.params_panel.as_mut() {
+            match result {
+                Ok(_) => panel.editing = None,
+                Err(err) => panel.error = Some(err),
+            }
+        }
+    }
+
+    ///Shows what a command did on the status line
+    fn feedback(&mut self, result: Result<String, String>) {
+        match result {
+            Ok(feedback) => {
+                self.command = feedback;
+                self.command_status = CommandStatus::Okay;
+            }
+            Err(err) => {
+                self.command = format!("Error: {}", err);
+                self.command_status = CommandStatus::Error;
+            }
+        }
+    }
+
+    ///"12+34 tokens, $0.0009" for an answer that reported its usage
+    pub fn answer_note(&self, message: &Message) -> Option<String> {
+        let usage = message.usage.as_ref()?;
+        return Some(usage_note(usage, self.message_cost(message)))
+    }
+
+    ///Estimated price of an answer, None without usage or a known price
+    fn message_cost(&self, message: &Message) -> Option<f64> {
+        let usage = message.usage.as_ref()?;
+        let price = pricing::price_for(&self.config.prices, &message.sender)?;
+        return Some(price.cost(usage))
+    }
+
+    ///Tokens used by the session so far, None before any answer reported some
+    pub fn session_usage(&self) -> Option<Usage> {
+        let mut total: Option<Usage> = None;
+        for usage in self.content.iter().filter_map(|message| message.usage.as_ref()) {
+            total.get_or_insert_with(Usage::default).add(usage);
+        }
+        return total
+    }
+
+    ///Estimated price of the session, counting answers of priced models only
+    pub fn session_cost(&self) -> Option<f64> {
+        let costs: Vec<f64> = self.content
+            .iter()
+            .filter_map(|message| self.message_cost(message))
+            .collect();
+        if costs.is_empty() {
+            return None
+        }
+        return Some(costs.iter().sum())
+    }
+
+    ///`:export <path>`, writes the transcript as markdown
+    fn export(&self, path: Option<&str>) -> Result<String, String> {
+        let path = match path {
+            Some(path) => path,
+            None => return Err("usage: :export <path>".to_string()),
+        };
+
+        let mut markdown = String::from("# gpTerm session\n");
+        for message in &self.content {
+            if let MessageType::Shell { command, code } = &message.message_type {
+                markdown.push_str(&format!(
+                    "\n**$ {}** _({})_\n\n```\n{}\n```\n",
+                    command, exit_note(*code), message.body
+                ));
+                continue;
+            }
+            markdown.push_str(&format!("\n**{}**", message.sender));
+            if let Some(usage) = &message.usage {
+                markdown.push_str(&format!(" _({})_", usage_note(usage, self.message_cost(message))));
+            }
+            markdown.push_str(&format!("\n\n{}\n", message.body));
+        }
+
+        if let Some(usage) = self.session_usage() {
+            markdown.push_str(&format!(
+                "\n---\n\nsession: {} prompt + {} completion = {} tokens",
+                usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+            ));
+            if let Some(cost) = self.session_cost() {
+                markdown.push_str(&format!(", about ${:.4}", cost));
+            }
+            markdown.push('\n');
+        }
+
+        match std::fs::write(path, markdown) {
+            Ok(()) => return Ok(format!("exported to {}", path)),
+            Err(err) => return Err(format!("couldn't write {}: {}", path, err)),
+        }
+    }
+
+    ///`:context [full | window <questions> | budget [tokens] | summarize on|off]`
+    fn set_context(&mut self, args: Vec<&str>) -> Result<String, String> {
+        let context = &mut self.config.context;
+        let number = |arg: Option<&&str>| arg.map(|arg| arg.parse::<usize>());
+        match args.as_slice() {
+            [] => {}
+            ["full"] => context.strategy = Strategy::Full,
+            ["window", rest @ ..] => match number(rest.first()) {
+                None => context.strategy = Strategy::Window,
+                Some(Ok(turns)) if turns > 0 => {
+                    context.strategy = Strategy::Window;
+                    context.turns = turns;
+                }
+                _ => return Err("usage: :context window <questions>".to_string()),
+            },
+            ["budget", rest @ ..] => match number(rest.first()) {
+                None => {
+                    context.strategy = Strategy::Budget;
+                    context.budget = None;
+                }
+                Some(Ok(tokens)) if tokens > 0 => {
+                    context.strategy = Strategy::Budget;
+                    context.budget = Some(tokens);
+                }
+                _ => return Err("usage: :context budget [tokens]".to_string()),
+            },
+            ["summarize", "on"] => context.summarize = true,
+            ["summarize", "off"] => context.summarize = false,
+            ["shell", "on"] => self.config.shell.context = true,
+            ["shell", "off"] => self.config.shell.context = false,
+            _ => {
+                return Err("usage: :context [full | window <n> | budget [tokens] | summarize on|off | shell on|off]".to_string())
+            }
+        }
+        let shell = match self.config.shell.context {
+            true => "shell output sent",
+            false => "shell output kept out",
+        };
+        return Ok(format!("context: {}, {}", self.config.context.describe(), shell))
+    }
+
+    ///Title of the transcript, with the session and its persona
+    pub fn get_title(&self) -> String {
+        let mut title = "gpTerm".to_string();
+        if let Some(name) = &self.session_name {
+            title.push_str(&format!(" · {}", name));
+        }
+        match (&self.persona, &self.system) {
+            (Some(persona), _) => title.push_str(&format!(" · {}", persona)),
+            (None, Some(_)) => title.push_str(" · custom system prompt"),
+            (None, None) => {}
+        }
+        return title
+    }
+
+    fn use_persona(&mut self, name: &str) -> Result<(), String> {
+        let persona = match self.config.personas.get(name) {
+            Some(persona) => persona,
+            None => return Err(format!("unknown persona {}", name)),
+        };
+        self.system = Some(persona.system.clone());
+        self.persona = Some(name.to_string());
+        return Ok(())
+    }
+
+    ///`:persona [name | none]`, lists personas or switches to one
+    fn switch_persona(&mut self, name: Option<&str>) -> Result<String, String> {
+        match name {
+            None => {
+                let names: Vec<&String> = self.config.personas.keys().collect();
+                if names.is_empty() {
+                    return Ok("no personas, add some under [personas] in gpterm.toml".to_string())
+                }
+                return Ok(format!("personas: {}", names.iter().map(|name| name.as_str()).collect::<Vec<&str>>().join(", ")))
+            }
+            Some("none") => {
+                self.system = None;
+                self.persona = None;
+                return Ok("no system prompt".to_string())
+            }
+            Some(name) => {
+                self.use_persona(name)?;
+                return Ok(format!("using persona {}", name))
+            }
+        }
+    }
+
+    ///`:system [text]`, shows the system prompt or replaces it
+    fn set_system(&mut self, text: String) -> Result<String, String> {
+        if text.is_empty() {
+            match &self.system {
+                Some(system) => return Ok(format!("system: {}", system)),
+                None => return Ok("no system prompt".to_string()),
+            }
+        }
+        self.system = Some(text);
+        self.persona = None;
+        return Ok("system prompt set".to_string())
+    }
+
+    fn sessions_dir(&self) -> Result<&PathBuf, String> {
+        match &self.config.sessions_dir {
+            Some(dir) => return Ok(dir),
+            None => return Err("no sessions directory configured".to_string()),
+        }
+    }
+
+    fn write_session(&self, name: &str) -> Result<(), String> {
+        let session = Session {
+            persona: self.persona.clone(),
+            system: self.system.clone(),
+            model: self.selected_model.clone(),
+            profile: self.profile.clone(),
+            params: self.overrides.clone(),
+            messages: self.content.clone(),
+        };
+        return session.save(self.sessions_dir()?, name).map_err(|err| err.to_string())
+    }
+
+    ///`:save [name]`, saves the session and keeps saving it after every answer
+    fn save_session(&mut self, name: Option<&str>) -> Result<String, String> {
+        let name = match (name, &self.session_name) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => return Err("usage: :save <name>".to_string()),
+        };
+        self.write_session(&name)?;
+        self.session_name = Some(name.clone());
+        return Ok(format!("saved session {}", name))
+    }
+
+    fn autosave(&self) {
+        if let Some(name) = &self.session_name {
+            if let Err(err) = self.write_session(name) {
+                error!("Couldn't save session {}: {}", name, err);
+            }
+        }
+    }
+
+    ///`:open <name>`, replaces the transcript with a saved session
+    fn open_session(&mut self, name: Option<&str>) -> Result<String, String> {
+        let name = match name {
+            Some(name) => name,
+            None => return Err("usage: :open <name>".to_string()),
+        };
+        //what is on its way would land in the session opened
+        if self.is_busy() {
+            return Err("wait for the current request to finish".to_string())
+        }
+        let session = Session::load(self.sessions_dir()?, name).map_err(|err| err.to_string())?;
+
+        self.content = session.messages;
+        self.system = session.system;
+        self.persona = session.persona;
+        if !session.model.is_empty() {
+            self.selected_model = session.model;
+        }
+        self.profile = session.profile;
+        self.overrides = session.params;
+        self.summary = None;
+        self.cursor = None;
+        self.session_name = Some(name.to_string());
+        self.scroll_to_bottom();
+        return Ok(format!("opened session {}", name))
+    }
+
+    ///`:find <words>`, lists the messages of the saved sessions having every word
+    fn find_sessions(&mut self, words: Vec<&str>) -> Result<String, String> {
+        if words.is_empty() {
+            return Err("usage: :find <words>".to_string())
+        }
+        let dir = self.sessions_dir()?;
+        let query = words.join(" ");
+        let hits = SearchIndex::open(dir).search(dir, &query);
+        if hits.is_empty() {
+            return Err(format!("no saved session matches {}", query))
+        }
+        self.finder = Some(Finder::new(query, hits));
+        return Ok(String::new())
+    }
+
+    pub fn get_finder(&self) -> Option<&Finder> {
+        return self.finder.as_ref()
+    }
+
+    pub fn finder_mut(&mut self) -> Option<&mut Finder> {
+        return self.finder.as_mut()
+    }
+
+    pub fn close_finder(&mut self) {
+        self.finder = None;
+    }
+
+    ///Opens the session of the hit chosen, scrolled to the message with the words highlighted
+    pub fn open_hit(&mut self) {
+        let (query, hit) = match &self.finder {
+            Some(finder) => match finder.choice() {
+                Some(hit) => (finder.query.clone(), hit.clone()),
+                None => return,
+            },
+            None => return,
+        };
+        let result = self.open_session(Some(&hit.session));
+        if result.is_ok() {
+            self.finder = None;
+            self.search = Some(Search::for_words(&query.split_whitespace().collect::<Vec<&str>>(), self.scroll));
+            let matches = self.search_matches();
+            match matches.iter().position(|found| found.message == hit.message) {
+                Some(index) => self.jump_to(index, &matches),
+                //the words only matched the start of longer ones
+                None if hit.message < self.content.len() => {
+                    self.scroll_to_line(self.line_offset(hit.message, 0));
+                }
+                None => {}
+            }
+        }
+        self.feedback(result);
+    }
+
+    ///`:sessions`, lists the saved sessions
+    fn list_sessions(&self) -> Result<String, String> {
+        let names = session::list(self.sessions_dir()?);
+        if names.is_empty() {
+            return Ok("no saved sessions".to_string())
+        }
+        return Ok(format!("sessions: {}", names.join(", ")))
+    }
+
+    fn templates_dir(&self) -> Result<&PathBuf, String> {
+        match &self.config.templates_dir {
+            Some(dir) => return Ok(dir),
+            None => return Err("no templates directory configured".to_string()),
+        }
+    }
+
+    ///`:template [name [name=value...]]`, lists templates or starts filling one in
+    fn use_template(&mut self, args: Vec<&str>) -> Result<String, String> {
+        let dir = self.templates_dir()?;
+        let (name, pairs) = match args.split_first() {
+            Some((name, pairs)) => (*name, pairs),
+            None => {
+                let names = template::list(dir);
+                if names.is_empty() {
+                    return Ok(format!("no templates in {}", dir.display()))
+                }
+                return Ok(format!("templates: {}", names.join(", ")))
+            }
+        };
+
+        let template = Template::load(dir, name).map_err(|err| err.to_string())?;
+        let mut vars = BTreeMap::new();
+        for pair in pairs {
+            let (name, value) = template::parse_var(pair)?;
+            vars.insert(name, value);
+        }
+        let missing = template.missing(&vars);
+        self.template_fill = Some(TemplateFill { template, vars, missing });
+        return self.continue_template()
+    }
+
+    ///Asks for the next missing variable, or puts the filled in template in the input box
+    fn continue_template(&mut self) -> Result<String, String> {
+        let fill = match self.template_fill.take() {
+            Some(fill) => fill,
+            None => return Ok(String::new()),
+        };
+        if let Some(name) = fill.missing.first() {
+            let feedback = format!("{}: type a value for {}", fill.template.name, name);
+            self.template_fill = Some(fill);
+            return Ok(feedback)
+        }
+
+        self.display_input = fill.template.render(&fill.vars)?;
+        return Ok(format!("{} filled in, Enter sends it", fill.template.name))
+    }
+
+    ///Takes what was typed as the value of the variable asked for
+    fn fill_template(&mut self) {
+        if let Some(fill) = self.template_fill.as_mut() {
+            let value = std::mem::take(&mut self.display_input);
+            let name = fill.missing.remove(0);
+            fill.vars.insert(name, value);
+        }
+        let result = self.continue_template();
+        self.feedback(result);
+    }
+
+    ///Title of the input box, the variable asked for while filling in a template
+    pub fn get_input_title(&self) -> String {
+        if let Some(index) = self.editing {
+            return format!("editing message {} (enter keeps it, esc gives up)", index + 1)
+        }
+        match &self.template_fill {
+            Some(fill) => match fill.missing.first() {
+                Some(name) => return format!("{} ({})", name, fill.template.name),
+                None => return "Input".to_string(),
+            },
+            None => return "Input".to_string(),
+        }
+    }
+
+    ///`J`, or `K` backwards, selects the next message, the last one to start with
+    pub fn move_cursor(&mut self, forward: bool) {
+        let last = match self.content.len().checked_sub(1) {
+            Some(last) => last,
+            None => return,
+        };
+        let index = match (self.selected_message(), forward) {
+            (None, _) => last,
+            (Some(index), true) => (index + 1).min(last),
+            (Some(index), false) => index.saturating_sub(1),
+        };
+        self.cursor = Some(index);
+        self.scroll_to_line(self.line_offset(index, 0));
+        self.status = format!(
+            "message {} of {}: y copies, d deletes, e edits, r regenerates, p pins, > quotes",
+            index + 1, self.content.len()
+        );
+    }
+
+    ///Index in the transcript of the message under the cursor
+    pub fn selected_message(&self) -> Option<usize> {
+        return self.cursor.filter(|&index| index < self.content.len())
+    }
+
+    pub fn clear_cursor(&mut self) {
+        self.cursor = None;
+    }
+
+    ///What was copied since the last time, once
+    pub fn take_clipboard(&mut self) -> Option<String> {
+        return self.clipboard.take()
+    }
+
+    ///Does what `key` does to the selected message, false when it does nothing
+    pub fn message_action(&mut self, key: char) -> bool {
+        let index = match self.selected_message() {
+            Some(index) => index,
+            None => return false,
+        };
+        let result = match key {
+            'y' => {
+                self.clipboard = Some(self.content[index].body.clone());
+                Ok(format!("copied message {}", index + 1))
+            }
+            'd' => self.prepare_delete(index),
+            'e' => self.edit_message(index),
+            'r' => self.regenerate(index),
+            'p' => {
+                let pinned = !self.content[index].pinned;
+                self.content[index].pinned = pinned;
+                self.autosave();
+                match pinned {
+                    true => Ok(format!("pinned {}'s message", self.content[index].sender)),
+                    false => Ok(format!("unpinned {}'s message", self.content[index].sender)),
+                }
+            }
+            '>' => {
+                let quote: Vec<String> = self.content[index].body.lines().map(|line| format!("> {}", line)).collect();
+                if !self.display_input.is_empty() {
+                    self.display_input.push('\n');
+                }
+                self.display_input.push_str(&format!("{}\n\n", quote.join("\n")));
+                self.set_input_mode(InputMode::Insert);
+                Ok("quoted, the question goes after it".to_string())
+            }
+            _ => return false,
+        };
+        match result {
+            //regenerating says what it waits for itself
+            Ok(note) if note.is_empty() => {}
+            Ok(note) | Err(note) => self.status = note,
+        }
+        return true
+    }
+
+    ///The transcript can't change under a request or a command
+    fn check_idle(&self) -> Result<(), String> {
+        if self.is_waiting() || self.is_running() || self.tool_loop.is_some() {
+            return Err("wait for the current request to finish".to_string())
+        }
+        return Ok(())
+    }
+
+    fn prepare_delete(&mut self, index: usize) -> Result<String, String> {
+        self.check_idle()?;
+        let message = &self.content[index];
+        self.confirm = Some(Confirm {
+            title: format!("delete {}'s message?", message.sender),
+            preview: message.body.clone(),
+            diff: false,
+            action: Action::Delete(index),
+        });
+        return Ok("y deletes it, n doesn't".to_string())
+    }
+
+    ///Puts the message in the input box, an edited question is asked again once sent
+    fn edit_message(&mut self, index: usize) -> Result<String, String> {
+        self.check_idle()?;
+        self.display_input = self.content[index].typed().to_string();
+        self.set_input_mode(InputMode::Insert);
+        self.editing = Some(index);
+        match self.content[index].message_type {
+            MessageType::Query => return Ok("enter asks it again, what came after it goes".to_string()),
+            _ => return Ok("enter keeps the new text".to_string()),
+        }
+    }
+
+    ///Asks the question the message answers again, what came after it goes
+    fn regenerate(&mut self, index: usize) -> Result<String, String> {
+        self.check_idle()?;
+        let query = match self.content[..=index].iter().rposition(|message| matches!(message.message_type, MessageType::Query)) {
+            Some(query) => query,
+            None => return Err("no question before this message".to_string()),
+        };
+        //asked as typed, the files it references are read again
+        let typed = self.content[query].typed().to_string();
+        self.resend_from(query, typed)?;
+        self.cursor = None;
+        return Ok(String::new())
+    }
+
+    ///`:pin [n]` and `:unpin [n]`, n counts messages back from the last one
+    fn set_pinned(&mut self, n: Option<&str>, pinned: bool) -> Result<String, String> {
+        let n = match n.map(|n| n.parse::<usize>()) {
+            None => 1,
+            Some(Ok(n)) if n > 0 => n,
+            _ => return Err("usage: :pin [n], 1 is the last message".to_string()),
+        };
+        let conversation = self.conversation();
+        let index = match conversation.len().checked_sub(n) {
+            Some(position) => conversation[position],
+            None => return Err(format!("there are only {} messages", conversation.len())),
+        };
+
+        self.content[index].pinned = pinned;
+        self.autosave();
+        match pinned {
+            true => return Ok(format!("pinned {}'s message", self.content[index].sender)),
+            false => return Ok(format!("unpinned {}'s message", self.content[index].sender)),
+        }
+    }
+
+    ///`:timeout <seconds>`, lets the next request run longer than configured
+    fn set_next_timeout(&mut self, seconds: Option<&str>) -> Result<String, String> {
+        let seconds = match seconds.map(|s| s.parse::<u64>()) {
+            Some(Ok(seconds)) if seconds > 0 => seconds,
+            _ => return Err("usage: :timeout <seconds>".to_string()),
+        };
+
+        self.next_timeout = Some(Duration::from_secs(seconds));
+        return Ok(format!("next request may take up to {}s", seconds))
+    }
+
+    pub fn push_command(&mut self, c: char) {
+        self.command.push(c)
+    }
+
+    pub fn pop_command(&mut self){
+        self.command.pop();
+    }
+
+    pub fn reset_command(&mut self){
+        self.command = String::from("");
+        self.command_status = CommandStatus::Okay;
+    }
+
+    pub fn command_status(&self) -> &CommandStatus {
+        return &self.command_status
+    }
+
+}
+
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            should_end: false,
+            scroll: 0,
+
+            username: String::new(),
+
+            display_input: String::new(),
+            internal_input: String::new(),
+            input_mode: InputMode::Normal, 
+
+            content: Vec::new(),
+
+            command: String::from(""),
             command_status: CommandStatus::Error,
 
             size: get_terminal_sizes(),
             max_offset: 0,
 
             api_handler: None,
-
-            //hard coded for now TODO: FIX
-            temperature: 0,
-            max_tokens: 1000, 
+            counter: None,
+            pending: None,
+            api_events: None,
+            status: String::new(),
+            next_timeout: None,
+            config: Config::default(),
+            token: String::new(),
+            provider_name: String::new(),
+            streaming: None,
+            summary: None,
+            system: None,
+            persona: None,
+            session_name: None,
+            running: None,
+            confirm: None,
+            models: ModelCache::default(),
+            picker: None,
+            discovery: None,
+            tool_loop: None,
+            command_prompt: None,
+            template_fill: None,
+            attachment_tokens: RefCell::new(BTreeMap::new()),
+
+            profile: None,
+            overrides: Profile::default(),
+            params_panel: None,
+            compare_targets: Vec::new(),
+            comparison: None,
+            tabs: vec![Conversation::default()],
+            active: 0,
+            search: None,
+            finder: None,
+            cursor: None,
+            editing: None,
+            clipboard: None,
             selected_model: "text-davinci-003".to_string()
         }
     }
 }
 
 
+///An action shown in a popup until the user says y or n
+pub struct Confirm {
+    pub title: String,
+    ///What the action acts on, shown in the popup
+    pub preview: String,
+    ///Whether the preview is a diff, to color it
+    pub diff: bool,
+    action: Action,
+}
+
+enum Action {
+    ///Runs a code block, with the shell when there is no interpreter
+    Run { block: CodeBlock, interpreter: Option<String> },
+    Write(FileWrite),
+    ///Runs a tool for the model, see `tool_loop`
+    Tool(ToolCall),
+    ///Deletes the message at this index of `content`
+    Delete(usize),
+}
+
+///Calls of an answer being made one at a time, their results go back in a new request
+struct ToolLoop {
+    ///Request the answer was for, earlier rounds included
+    request: CompletionRequest,
+    calls: Vec<ToolCall>,
+    ///What the calls returned so far, in order
+    results: Vec<String>,
+}
+
+///A template and the values of its variables typed so far
+struct TemplateFill {
+    template: Template,
+    vars: BTreeMap<String, String>,
+    ///Variables still to ask for, in order
+    missing: Vec<String>,
+}
+
+///Models being fetched, the task hands the client back with them
+struct Discovery {
+    task: JoinHandle<(ApiHandler, Result<Vec<ModelInfo>, String>)>,
+    ///Whether the picker opens once they are in
+    open: bool,
+}
+
+///What a tab has of its own, parked here while another one is shown.
+///The fields are the app's of the same name, see `App::swap_tab`
+#[derive(Default)]
+struct Conversation {
+    display_input: String,
+    internal_input: String,
+    content: Vec<Message>,
+    scroll: usize,
+    max_offset: usize,
+    api_handler: Option<ApiHandler>,
+    counter: Option<Arc<dyn Provider>>,
+    pending: Option<JoinHandle<(ApiHandler, Message, Option<Summary>)>>,
+    api_events: Option<UnboundedReceiver<ApiEvent>>,
+    status: String,
+    next_timeout: Option<Duration>,
+    provider_name: String,
+    streaming: Option<usize>,
+    summary: Option<Summary>,
+    system: Option<String>,
+    persona: Option<String>,
+    session_name: Option<String>,
+    running: Option<JoinHandle<Message>>,
+    confirm: Option<Confirm>,
+    discovery: Option<Discovery>,
+    tool_loop: Option<ToolLoop>,
+    template_fill: Option<TemplateFill>,
+    profile: Option<String>,
+    overrides: Profile,
+    compare_targets: Vec<Target>,
+    comparison: Option<Comparison>,
+    cursor: Option<usize>,
+    editing: Option<usize>,
+    selected_model: String,
+}
+
+impl Conversation {
+    ///Whether something of the tab is on its way or waits on the user: a request,
+    ///a command, models, a confirmation or tool calls
+    fn is_busy(&self) -> bool {
+        let comparing = self.comparison.as_ref().is_some_and(|comparison| comparison.is_waiting());
+        return self.pending.is_some()
+            || self.discovery.is_some()
+            || self.running.is_some()
+            || self.confirm.is_some()
+            || self.tool_loop.is_some()
+            || comparing
+    }
+}
+
+///Lines of `body` with the matches in them highlighted, `current` the most
+fn highlighted<'a>(body: &'a str, found: &[&Match], current: Option<&Match>) -> Vec<Spans<'a>> {
+    let style = Style::default().fg(Color::Black).bg(Color::Yellow);
+    let current_style = Style::default().fg(Color::Black).bg(Color::LightRed).add_modifier(Modifier::BOLD);
+    return body
+        .split('\n')
+        .enumerate()
+        .map(|(number, line)| {
+            let mut spans = Vec::new();
+            let mut end = 0;
+            for found in found.iter().filter(|found| found.line == number) {
+                spans.push(Span::raw(&line[end..found.start]));
+                let style = match current == Some(*found) {
+                    true => current_style,
+                    false => style,
+                };
+                spans.push(Span::styled(&line[found.start..found.end], style));
+                end = found.end;
+            }
+            spans.push(Span::raw(&line[end..]));
+            Spans::from(spans)
+        })
+        .collect()
+}
+
+///Name of a tab: its session, or the start of its first question
+fn tab_label(session_name: Option<&String>, content: &[Message]) -> String {
+    if let Some(name) = session_name {
+        return name.clone()
+    }
+    let first = content
+        .iter()
+        .find(|message| matches!(message.message_type, MessageType::Query))
+        .and_then(|query| query.body.lines().next());
+    match first {
+        Some(line) if line.chars().count() > TAB_LABEL_CHARS => {
+            return format!("{}…", line.chars().take(TAB_LABEL_CHARS).collect::<String>())
+        }
+        Some(line) => return line.to_string(),
+        None => return "new".to_string(),
+    }
+}
+
+///Where the summary going before a request comes from
+enum SummaryJob {
+    ///The last one still covers what is left out
+    Cached(String),
+    ///The model writes it first, it covers these messages
+    Write { covers: Vec<usize>, prompt: String },
+}
+
+///As much of `candidates` as they all agree on
+fn common_prefix(candidates: &[String]) -> String {
+    let mut common = candidates.first().cloned().unwrap_or_default();
+    for candidate in candidates {
+        while !candidate.starts_with(&common) {
+            common.pop();
+        }
+    }
+    return common
+}
+
+///"exit 0", or "killed" without an exit code
+fn exit_note(code: Option<i32>) -> String {
+    match code {
+        Some(code) => return format!("exit {}", code),
+        None => return "killed".to_string(),
+    }
+}
+
+fn pinned_note<'a>(message: &Message) -> Span<'a> {
+    match message.pinned {
+        true => return Span::styled(" [pinned]", Style::default().fg(Color::Yellow)),
+        false => return Span::raw(""),
+    }
+}
+
+///"12+34 tokens, $0.0009" for one answer
+pub fn usage_note(usage: &Usage, cost: Option<f64>) -> String {
+    let tokens = format!("{}+{} tokens", usage.prompt_tokens, usage.completion_tokens);
+    match cost {
+        Some(cost) => return format!("{}, ${:.4}", tokens, cost),
+        None => return tokens,
+    }
+}
@@ -0,0 +1,230 @@
+use log::debug;
+
+use std::{fs, path::{Path, PathBuf}};
+
+use serde::Deserialize;
+
+
+///Settings of the `[attachments]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AttachConfig {
+    ///Biggest file an `@` reference may attach
+    pub max_file_bytes: u64,
+    ///Most a single query may attach, all files together
+    pub max_total_bytes: u64,
+    ///Most files a glob may attach
+    pub max_files: usize,
+}
+
+impl Default for AttachConfig {
+    fn default() -> AttachConfig {
+        AttachConfig { max_file_bytes: 64 * 1024, max_total_bytes: 256 * 1024, max_files: 20 }
+    }
+}
+
+///A file an `@` reference points at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+///Words of `input` starting with `@`, without it
+pub fn references(input: &str) -> Vec<&str> {
+    return input
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|reference| reference.trim_end_matches([',', ';', ':', ')', '!']))
+        .filter(|reference| !reference.is_empty())
+        .collect()
+}
+
+fn is_glob(reference: &str) -> bool {
+    return reference.contains(['*', '?', '['])
+}
+
+///Paths `reference` stands for, several when it is a glob
+fn expand(reference: &str, config: &AttachConfig) -> Result<Vec<PathBuf>, String> {
+    if !is_glob(reference) {
+        let path = PathBuf::from(reference);
+        if path.is_dir() {
+            return Err(format!("{} is a directory, @{}/* attaches what is in it", reference, reference))
+        }
+        return Ok(vec![path])
+    }
+
+    let paths = match glob::glob(reference) {
+        Ok(paths) => paths,
+        Err(err) => return Err(format!("bad pattern {}: {}", reference, err)),
+    };
+    let files: Vec<PathBuf> = paths
+        .filter_map(|path| path.ok())
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        return Err(format!("nothing matches {}", reference))
+    }
+    if files.len() > config.max_files {
+        return Err(format!("{} matches {} files, at most {} can be attached", reference, files.len(), config.max_files))
+    }
+    return Ok(files)
+}
+
+///Files the `@` references of `input` point at, checked against the size limits
+pub fn resolve(input: &str, config: &AttachConfig) -> Result<Vec<Attachment>, String> {
+    let mut attachments: Vec<Attachment> = Vec::new();
+    for reference in references(input) {
+        for path in expand(reference, config)? {
+            if attachments.iter().any(|attachment| attachment.path == path) {
+                continue;
+            }
+            let bytes = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(err) => return Err(format!("can't attach {}: {}", path.display(), err)),
+            };
+            if bytes > config.max_file_bytes {
+                return Err(format!(
+                    "{} is {}, the limit is {}",
+                    path.display(), size(bytes), size(config.max_file_bytes)
+                ))
+            }
+            attachments.push(Attachment { path, bytes });
+        }
+    }
+
+    let total: u64 = attachments.iter().map(|attachment| attachment.bytes).sum();
+    if total > config.max_total_bytes {
+        return Err(format!("attachments take {}, the limit is {}", size(total), size(config.max_total_bytes)))
+    }
+    return Ok(attachments)
+}
+
+///`input` followed by the attached files, each fenced under its path
+pub fn inline(input: &str, attachments: &[Attachment]) -> Result<String, String> {
+    let mut query = input.to_string();
+    for attachment in attachments {
+        let path = attachment.path.display();
+        let contents = match fs::read(&attachment.path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("couldn't read {}: {}", path, err)),
+        };
+        let contents = match String::from_utf8(contents) {
+            Ok(contents) => contents,
+            Err(_) => return Err(format!("{} isn't text", path)),
+        };
+        debug!("Attaching {} ({} bytes)", path, attachment.bytes);
+
+        //a fence longer than any run of backticks in the file
+        let mut fence = "```".to_string();
+        while contents.contains(&fence) {
+            fence.push('`');
+        }
+        let language = attachment.path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+        query.push_str(&format!(
+            "\n\n{}:\n{}{}\n{}\n{}",
+            path, fence, language, contents.trim_end(), fence
+        ));
+    }
+    return Ok(query)
+}
+
+///Paths `partial` may be completed to, directories end with a slash
+pub fn complete(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(slash) => (&partial[..slash + 1], &partial[slash + 1..]),
+        None => ("", partial),
+    };
+    let listed = match dir {
+        "" => Path::new("."),
+        dir => Path::new(dir),
+    };
+    let entries = match fs::read_dir(listed) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            //hidden files only when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None
+            }
+            match entry.path().is_dir() {
+                true => Some(format!("{}{}/", dir, name)),
+                false => Some(format!("{}{}", dir, name)),
+            }
+        })
+        .collect();
+    candidates.sort();
+    return candidates
+}
+
+///"12.3 KB"
+pub fn size(bytes: u64) -> String {
+    match bytes {
+        bytes if bytes < 1024 => return format!("{} B", bytes),
+        bytes if bytes < 1024 * 1024 => return format!("{:.1} KB", bytes as f64 / 1024.0),
+        bytes => return format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gpterm-attach-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.join("b.rs"), "```\nfn b() {}\n").unwrap();
+        fs::write(dir.join("notes.md"), "x".repeat(100)).unwrap();
+        return dir
+    }
+
+    #[test]
+    fn references_and_globs_resolve_to_files() {
+        let dir = scratch("resolve");
+        let input = format!("compare @{0}/*.rs, and @{0}/a.rs please, mail me@example.com", dir.display());
+
+        let attachments = resolve(&input, &AttachConfig::default()).unwrap();
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].path, dir.join("a.rs"));
+
+        let small = AttachConfig { max_file_bytes: 50, ..AttachConfig::default() };
+        assert!(resolve(&format!("@{}/notes.md", dir.display()), &small).unwrap_err().contains("the limit is 50 B"));
+        assert!(resolve(&format!("@{}", dir.display()), &small).unwrap_err().contains("is a directory"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_fenced_under_their_path() {
+        let dir = scratch("inline");
+        let attachments = resolve(&format!("@{}/*.rs", dir.display()), &AttachConfig::default()).unwrap();
+
+        let query = inline("look", &attachments).unwrap();
+        assert!(query.starts_with(&format!("look\n\n{}:\n```rs\nfn a() {{}}\n```", dir.join("a.rs").display())));
+        //b.rs holds a fence itself
+        assert!(query.ends_with("````rs\n```\nfn b() {}\n````"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn paths_complete_from_the_directory_listing() {
+        let dir = scratch("complete");
+        let partial = format!("{}/", dir.display());
+
+        assert_eq!(complete(&format!("{}a", partial)), vec![format!("{}a.rs", partial)]);
+        assert_eq!(complete(&partial).len(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
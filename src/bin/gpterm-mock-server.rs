@@ -0,0 +1,32 @@
+//the codebase prefers explicit returns
+#![allow(clippy::needless_return)]
+
+use std::net::SocketAddr;
+
+use clap::Parser;
+
+#[path = "../mock_server.rs"]
+mod mock_server;
+
+
+///OpenAI compatible server with deterministic answers, for testing gpterm offline
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    ///Address to listen on
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    listen: SocketAddr,
+    #[command(flatten)]
+    settings: mock_server::Settings,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let (addr, server) = mock_server::serve(args.settings, args.listen)?;
+    println!("listening on http://{}/v1", addr);
+
+    server.await?;
+    return Ok(())
+}
@@ -0,0 +1,255 @@
+//Fenced code blocks of answers, to run them or write them to files
+
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::PathBuf};
+
+use serde::Deserialize;
+
+
+///A fenced block, ```` ```rust src/main.rs ```` gives language "rust" and hint "src/main.rs"
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    pub language: String,
+    ///What follows the language on the opening fence, a file name usually
+    pub hint: Option<String>,
+    pub code: String,
+}
+
+impl CodeBlock {
+    ///The language, "text" for untagged blocks
+    pub fn language_name(&self) -> &str {
+        match self.language.as_str() {
+            "" => return "text",
+            language => return language,
+        }
+    }
+
+    ///"python, 3 lines"
+    pub fn describe(&self) -> String {
+        let language = self.language_name();
+        let lines = self.code.lines().count();
+        match &self.hint {
+            Some(hint) => return format!("{} {}, {} lines", language, hint, lines),
+            None => return format!("{}, {} lines", language, lines),
+        }
+    }
+
+    ///What the shell runs for the block: shell scripts as they are, the commands after
+    ///the `$ ` prompts of a terminal session. None for other languages
+    pub fn shell_code(&self) -> Option<String> {
+        let language = self.language.as_str();
+        if SHELL_LANGUAGES.contains(&language) {
+            return Some(self.code.clone())
+        }
+        if !SESSION_LANGUAGES.contains(&language) {
+            return None
+        }
+        //the other lines are what the commands printed
+        let commands: Vec<&str> = self.code
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix("$ "))
+            .collect();
+        match commands.is_empty() {
+            true => return None,
+            false => return Some(commands.join("\n")),
+        }
+    }
+
+    pub fn is_patch(&self) -> bool {
+        return matches!(self.language.as_str(), "diff" | "patch")
+            || self.code.starts_with("--- ")
+            || self.code.starts_with("diff --git")
+    }
+
+    ///The unified diff of a patch block, from its `---` line on
+    fn patch_text(&self) -> String {
+        let start = self.code.find("--- ").unwrap_or(0);
+        let mut text = self.code[start..].to_string();
+        text.push('\n');
+        return text
+    }
+
+    ///File the block is about: the fence hint, or the file a patch modifies
+    pub fn target(&self) -> Option<String> {
+        if let Some(hint) = &self.hint {
+            return Some(hint.clone())
+        }
+        if !self.is_patch() {
+            return None
+        }
+        let text = self.patch_text();
+        let patch = diffy::Patch::from_str(&text).ok()?;
+        let name = patch.modified().or(patch.original())?;
+        if name == "/dev/null" {
+            return None
+        }
+        return Some(name.strip_prefix("b/").unwrap_or(name).to_string())
+    }
+}
+
+///What writing a block to a file does
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileWrite {
+    pub path: PathBuf,
+    pub contents: String,
+    ///Unified diff from what the file holds now
+    pub diff: String,
+}
+
+///Plans writing `block` to `path`: a patch is applied to the file, other blocks replace it
+pub fn plan_write(block: &CodeBlock, path: &str) -> Result<FileWrite, String> {
+    let current = match fs::read_to_string(path) {
+        Ok(current) => current,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(format!("couldn't read {}: {}", path, err)),
+    };
+
+    let contents = match block.is_patch() {
+        true => {
+            let text = block.patch_text();
+            if text.matches("\n+++ ").count() > 1 {
+                return Err("the patch changes several files, only single file patches apply".to_string())
+            }
+            let patch = match diffy::Patch::from_str(&text) {
+                Ok(patch) => patch,
+                Err(err) => return Err(format!("not a patch gpterm can read: {}", err)),
+            };
+            match diffy::apply(&current, &patch) {
+                Ok(contents) => contents,
+                Err(err) => return Err(format!("the patch doesn't apply to {}: {}", path, err)),
+            }
+        }
+        false => format!("{}\n", block.code.trim_end_matches('\n')),
+    };
+    if contents == current {
+        return Err(format!("{} already holds that", path))
+    }
+
+    let diff = diffy::DiffOptions::new()
+        .set_original_filename(format!("a/{}", path))
+        .set_modified_filename(format!("b/{}", path))
+        .create_patch(&current, &contents)
+        .to_string();
+    return Ok(FileWrite { path: PathBuf::from(path), contents, diff })
+}
+
+///Languages run with the shell itself
+const SHELL_LANGUAGES: [&str; 4] = ["sh", "bash", "zsh", "shell"];
+
+///Terminal sessions, commands behind `$ ` prompts mixed with their output
+const SESSION_LANGUAGES: [&str; 2] = ["console", "shell-session"];
+
+///Code blocks of `text` in order, an unclosed one runs to the end
+pub fn extract(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines();
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim_start();
+        let fence_len = trimmed.chars().take_while(|&c| c == '`').count();
+        if fence_len < 3 {
+            continue;
+        }
+        let fence = &trimmed[..fence_len];
+        let mut info = trimmed[fence_len..].split_whitespace();
+        let language = info.next().unwrap_or("").to_lowercase();
+        let hint = info.next().map(String::from);
+
+        let mut code: Vec<&str> = Vec::new();
+        for line in lines.by_ref() {
+            let closing = line.trim();
+            if closing.starts_with(fence) && closing.chars().all(|c| c == '`') {
+                break;
+            }
+            code.push(line);
+        }
+        blocks.push(CodeBlock { language, hint, code: code.join("\n") });
+    }
+    return blocks
+}
+
+
+///Settings of the `[run]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RunConfig {
+    ///Program running blocks of each language, given the block as a file.
+    ///Shell blocks always run with the `[shell]` program
+    pub interpreters: BTreeMap<String, String>,
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        let interpreters = [("python", "python3"), ("py", "python3"), ("javascript", "node"), ("js", "node")];
+        RunConfig {
+            interpreters: interpreters
+                .iter()
+                .map(|(language, program)| (language.to_string(), program.to_string()))
+                .collect(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("gpterm-blocks-{}-{}.rs", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        return path.display().to_string()
+    }
+
+    #[test]
+    fn blocks_replace_files_with_a_diff_preview() {
+        let path = scratch_file("replace", "fn main() {}\n");
+        let block = CodeBlock { language: "rust".to_string(), hint: None, code: "fn main() {\n    run();\n}".to_string() };
+
+        let write = plan_write(&block, &path).unwrap();
+        assert_eq!(write.contents, "fn main() {\n    run();\n}\n");
+        assert!(write.diff.contains("-fn main() {}\n+fn main() {\n+    run();\n+}"));
+
+        fs::write(&path, &write.contents).unwrap();
+        assert!(plan_write(&block, &path).unwrap_err().contains("already holds that"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn patches_apply_to_the_file_they_name() {
+        let path = scratch_file("patch", "one\ntwo\nthree\n");
+        let block = extract(&format!(
+            "```diff\n--- a/{0}\n+++ b/{0}\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n```",
+            path.trim_start_matches('/')
+        )).remove(0);
+
+        assert_eq!(block.target().as_deref(), Some(path.trim_start_matches('/')));
+        assert_eq!(plan_write(&block, &path).unwrap().contents, "one\n2\nthree\n");
+
+        fs::write(&path, "something else\n").unwrap();
+        assert!(plan_write(&block, &path).unwrap_err().contains("doesn't apply"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn terminal_sessions_run_only_their_commands() {
+        let blocks = extract("```console\n$ ls\nCargo.toml  src\n  $ cargo build\n   Compiling gpterm\n```\n```shell-session\nno prompt here\n```");
+        assert_eq!(blocks[0].shell_code().as_deref(), Some("ls\ncargo build"));
+        assert_eq!(blocks[1].shell_code(), None);
+    }
+
+    #[test]
+    fn blocks_are_extracted_with_language_and_hint() {
+        let answer = "Try this:\n```bash\nls -la\ncd /tmp\n```\nthen\n````rust src/main.rs\nfn main() {\n    let s = \"```\";\n}\n````\n```\nunclosed";
+        let blocks = extract(answer);
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], CodeBlock { language: "bash".to_string(), hint: None, code: "ls -la\ncd /tmp".to_string() });
+        assert_eq!(blocks[1].hint.as_deref(), Some("src/main.rs"));
+        assert_eq!(blocks[1].code, "fn main() {\n    let s = \"```\";\n}");
+        assert_eq!(blocks[2].code, "unclosed");
+        assert_eq!(blocks[0].shell_code().as_deref(), Some("ls -la\ncd /tmp"));
+        assert_eq!(blocks[1].shell_code(), None);
+        assert_eq!(blocks[2].shell_code(), None);
+        assert_eq!(blocks[1].describe(), "rust src/main.rs, 3 lines");
+    }
+}
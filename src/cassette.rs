@@ -0,0 +1,292 @@
+use log::{debug, info};
+
+use std::{collections::BTreeMap, error::Error, fs, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiError;
+
+
+///Headers never written to a cassette
+const SECRET_HEADERS: [&str; 3] = ["authorization", "api-key", "proxy-authorization"];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    ///Requests go to the server, nothing is kept
+    Off,
+    ///Requests go to the server and are written to the cassette
+    Record,
+    ///Requests are answered from the cassette, nothing is sent
+    Replay,
+}
+
+///Settings of the `[cassette]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    pub path: Option<PathBuf>,
+}
+
+impl Default for CassetteConfig {
+    fn default() -> CassetteConfig {
+        CassetteConfig { mode: CassetteMode::Off, path: None }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Tape {
+    interactions: Vec<Interaction>,
+    ///Interactions already replayed
+    #[serde(skip)]
+    played: Vec<bool>,
+}
+
+fn recorded_headers(headers: &reqwest::header::HeaderMap) -> BTreeMap<String, String> {
+    return headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match SECRET_HEADERS.contains(&name.as_str()) {
+                true => "[scrubbed]".to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).to_string(),
+            };
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+///What a request is matched by on replay, the host may differ
+fn route(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => match url.query() {
+            Some(query) => return format!("{}?{}", url.path(), query),
+            None => return url.path().to_string(),
+        },
+        Err(_) => return url.to_string(),
+    }
+}
+
+impl RecordedRequest {
+    pub fn from(request: &reqwest::Request) -> RecordedRequest {
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        return RecordedRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            headers: recorded_headers(request.headers()),
+            body: String::from_utf8_lossy(body).to_string(),
+        }
+    }
+
+    fn matches(&self, other: &RecordedRequest) -> bool {
+        return self.method == other.method
+            && route(&self.url) == route(&other.url)
+            && self.body == other.body
+    }
+}
+
+impl RecordedResponse {
+    fn to_response(&self) -> Result<reqwest::Response, ApiError> {
+        let mut response = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            response = response.header(name, value);
+        }
+        match response.body(self.body.clone()) {
+            Ok(response) => return Ok(reqwest::Response::from(response)),
+            Err(err) => return Err(ApiError::Cassette(format!("broken recorded response: {}", err))),
+        }
+    }
+}
+
+
+///Request/response pairs recorded from, or replayed instead of, the server
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    tape: Mutex<Tape>,
+}
+
+impl Cassette {
+    ///The cassette `config` asks for, None when it is off
+    pub fn open(config: &CassetteConfig) -> Result<Option<Cassette>, Box<dyn Error>> {
+        let path = match (config.mode, &config.path) {
+            (CassetteMode::Off, _) => return Ok(None),
+            (_, Some(path)) => path.clone(),
+            (_, None) => return Err("the cassette needs a path".into()),
+        };
+
+        let tape = match config.mode {
+            CassetteMode::Replay => {
+                let contents = match fs::read_to_string(&path) {
+                    Ok(contents) => contents,
+                    Err(err) => {
+                        return Err(format!("couldn't read cassette {}: {}", path.display(), err).into())
+                    }
+                };
+                let mut tape = serde_json::from_str::<Tape>(&contents)?;
+                tape.played = vec![false; tape.interactions.len()];
+                info!("Replaying {} interactions from {}", tape.interactions.len(), path.display());
+                tape
+            }
+            _ => {
+                info!("Recording to {}", path.display());
+                Tape::default()
+            }
+        };
+
+        return Ok(Some(Cassette { mode: config.mode, path, tape: Mutex::new(tape) }))
+    }
+
+    pub fn replaying(&self) -> bool {
+        return self.mode == CassetteMode::Replay
+    }
+
+    ///Answers `request` with the first recording of it not played yet
+    pub fn replay(&self, request: &reqwest::Request) -> Result<reqwest::Response, ApiError> {
+        let request = RecordedRequest::from(request);
+        let mut tape = self.tape.lock().unwrap();
+
+        let found = (0..tape.interactions.len())
+            .find(|&index| !tape.played[index] && tape.interactions[index].request.matches(&request));
+        match found {
+            Some(index) => {
+                tape.played[index] = true;
+                debug!("Replaying interaction {} for {} {}", index, request.method, request.url);
+                return tape.interactions[index].response.to_response()
+            }
+            None => {
+                return Err(ApiError::Cassette(format!(
+                    "nothing recorded for {} {}", request.method, route(&request.url)
+                )))
+            }
+        }
+    }
+
+    ///Adds an interaction and writes the cassette out, so it survives a crash.
+    ///The body was read to record it, the response is handed back rebuilt
+    pub fn record(
+        &self,
+        request: RecordedRequest,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: &[u8],
+    ) -> Result<reqwest::Response, ApiError> {
+        let response = RecordedResponse {
+            status: status.as_u16(),
+            headers: recorded_headers(headers),
+            body: String::from_utf8_lossy(body).to_string(),
+        };
+        let mut tape = self.tape.lock().unwrap();
+        tape.interactions.push(Interaction { request, response: response.clone() });
+
+        let written = serde_json::to_string_pretty(&*tape)
+            .map_err(|err| err.to_string())
+            .and_then(|json| fs::write(&self.path, json).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            return Err(ApiError::Cassette(format!("couldn't write {}: {}", self.path.display(), err)))
+        }
+        return response.to_response()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::{ApiHandler, RetryPolicy};
+    use crate::config::Config;
+    use crate::params::Params;
+    use crate::provider::Endpoint;
+
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn handler_for(base_url: String, stream: bool, cassette: CassetteConfig) -> ApiHandler {
+        let config = Config {
+            endpoint: Endpoint { base_url: Some(base_url), stream, ..Endpoint::default() },
+            retry: RetryPolicy { initial_backoff_ms: 1, jitter: false, ..RetryPolicy::default() },
+            cassette,
+            ..Config::default()
+        };
+        return ApiHandler::new("secret".to_string(), &config).unwrap()
+    }
+
+    async fn ask(handler: &mut ApiHandler, prompt: &str) -> String {
+        let answer = handler.answer_from("text-davinci-003".to_string(), prompt.to_string(), Params { max_tokens: 7, ..Params::default() }, None).await;
+        return answer.get_body().clone()
+    }
+
+    #[tokio::test]
+    async fn recordings_are_scrubbed_and_replay_offline() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "model": "text-davinci-003",
+                "choices": [{"text": "recorded answer"}]
+            })))
+            .mount(&server)
+            .await;
+
+        let path = std::env::temp_dir().join(format!("gpterm-cassette-{}.json", std::process::id()));
+        let mut recorder = handler_for(format!("{}/v1", server.uri()), false, CassetteConfig {
+            mode: CassetteMode::Record,
+            path: Some(path.clone()),
+        });
+        assert_eq!(ask(&mut recorder, "hi").await, "recorded answer");
+
+        let recorded = fs::read_to_string(&path).unwrap();
+        assert!(!recorded.contains("secret"));
+        assert!(recorded.contains("[scrubbed]"));
+
+        //nothing listens there, answers can only come from the cassette
+        let mut player = handler_for("http://127.0.0.1:9/v1".to_string(), false, CassetteConfig {
+            mode: CassetteMode::Replay,
+            path: Some(path.clone()),
+        });
+        assert_eq!(ask(&mut player, "hi").await, "recorded answer");
+        assert!(ask(&mut player, "hi").await.contains("nothing recorded for POST /v1/completions"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_a_rate_limited_stream() {
+        let mut player = handler_for("https://api.openai.com/v1".to_string(), true, CassetteConfig {
+            mode: CassetteMode::Replay,
+            path: Some(PathBuf::from("tests/fixtures/cassettes/rate-limited-stream.json")),
+        });
+
+        assert_eq!(ask(&mut player, "say hello").await, "\n\nHello!");
+    }
+
+    #[test]
+    fn cassettes_need_a_path_unless_off() {
+        let config = CassetteConfig { mode: CassetteMode::Record, path: None };
+        assert!(Cassette::open(&config).is_err());
+        assert!(Cassette::open(&CassetteConfig::default()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::cassette::CassetteMode;
+use crate::config::Config;
+use crate::provider::{Endpoint, Flavour};
+use crate::template::{self, Template};
+
+
+///A terminal application to interact with the openAI API
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    ///Model the session starts with
+    #[arg(short, long)]
+    pub model: Option<String>,
+    ///Answer offline with the built-in mock backend, echoing prompts
+    #[arg(long)]
+    pub mock: bool,
+    ///Mock answers read from `<prompt>.txt` files in this directory
+    #[arg(long, value_name = "DIR")]
+    pub mock_fixtures: Option<PathBuf>,
+    ///Mock answers played in order from a toml file of `[[reply]]` tables
+    #[arg(long, value_name = "FILE")]
+    pub mock_script: Option<PathBuf>,
+    ///Write every request and its response to this cassette
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    ///Answer requests from this cassette instead of the server
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    ///Asks one question and prints the answer, without the interface
+    Ask(Ask),
+    ///Finds the messages of the saved sessions having every word given
+    Search(Search),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Search {
+    ///Words to look for, a word also matches longer ones it starts
+    #[arg(required = true)]
+    pub words: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Ask {
+    ///Template the question is made from
+    #[arg(short, long)]
+    pub template: Option<String>,
+    ///Value of a template variable, asked for on stdin when missing
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = template::parse_var)]
+    pub vars: Vec<(String, String)>,
+    ///The question, or the `input` variable of the template
+    pub query: Vec<String>,
+}
+
+impl Ask {
+    ///The question to send, filling the template in when there is one
+    pub fn query(&self, templates_dir: &Path) -> Result<String, Box<dyn Error>> {
+        let query = self.query.join(" ");
+        let name = match &self.template {
+            Some(name) => name,
+            None if query.is_empty() => return Err("nothing to ask".into()),
+            None => return Ok(query),
+        };
+
+        let template = Template::load(templates_dir, name)?;
+        let mut vars: BTreeMap<String, String> = self.vars.iter().cloned().collect();
+        if !query.is_empty() {
+            vars.insert("input".to_string(), query);
+        }
+        for name in template.missing(&vars) {
+            eprint!("{}: ", name);
+            io::stderr().flush()?;
+            let mut value = String::new();
+            io::stdin().lock().read_line(&mut value)?;
+            vars.insert(name, value.trim_end().to_string());
+        }
+        return Ok(template.render(&vars)?)
+    }
+}
+
+impl Cli {
+    ///Overrides the config file with the flags given
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(model) = &self.model {
+            config.model = Some(model.clone());
+        }
+        if let Some(path) = &self.record {
+            config.cassette.mode = CassetteMode::Record;
+            config.cassette.path = Some(path.clone());
+        }
+        if let Some(path) = &self.replay {
+            config.cassette.mode = CassetteMode::Replay;
+            config.cassette.path = Some(path.clone());
+        }
+
+        //starts the session on a "mock" provider
+        if !self.mock && self.mock_fixtures.is_none() && self.mock_script.is_none() {
+            return
+        }
+
+        config.providers.insert("mock".to_string(), Endpoint {
+            flavour: Flavour::Mock,
+            stream: true,
+            fixtures: self.mock_fixtures.clone(),
+            script: self.mock_script.clone(),
+            ..Endpoint::default()
+        });
+        config.provider = "mock".to_string();
+    }
+}
@@ -0,0 +1,122 @@
+//The same question sent to several models or profiles at once, answered side by side
+
+use log::error;
+
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use tokio::task::JoinHandle;
+
+use crate::api::ApiHandler;
+use crate::app::Message;
+use crate::provider::CompletionRequest;
+
+
+///Most answers shown side by side
+pub const MAX_TARGETS: usize = 4;
+
+///A model and the parameter profile it answers with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub model: String,
+    ///None for the model's own profile
+    pub profile: Option<String>,
+}
+
+impl Target {
+    ///"gpt-4", "gpt-4@precise", or "@precise" for `model` with that profile
+    pub fn parse(text: &str, model: &str) -> Result<Target, String> {
+        let (name, profile) = match text.rsplit_once('@') {
+            Some((name, profile)) if !profile.is_empty() => (name, Some(profile.to_string())),
+            Some(_) => return Err(format!("{} names no profile after @", text)),
+            None => (text, None),
+        };
+        let model = match name {
+            "" => model.to_string(),
+            name => name.to_string(),
+        };
+        return Ok(Target { model, profile })
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.profile {
+            Some(profile) => return format!("{}@{}", self.model, profile),
+            None => return self.model.clone(),
+        }
+    }
+}
+
+///One target's answer, None while it is on its way
+pub struct Column {
+    pub target: Target,
+    pub answer: Option<Message>,
+    ///From sending the request to the whole answer
+    pub latency: Option<Duration>,
+    task: Option<JoinHandle<(Message, Duration)>>,
+}
+
+impl Column {
+    ///Sends `request` with its own client, so columns don't wait on each other
+    pub fn spawn(target: Target, mut handler: ApiHandler, request: CompletionRequest) -> Column {
+        let task = tokio::spawn(async move {
+            let started = Instant::now();
+            let answer = handler.answer(request).await;
+            (answer, started.elapsed())
+        });
+        return Column { target, answer: None, latency: None, task: Some(task) }
+    }
+}
+
+///A question and the answers of every target
+pub struct Comparison {
+    pub query: String,
+    pub columns: Vec<Column>,
+}
+
+impl Comparison {
+    pub fn is_waiting(&self) -> bool {
+        return self.columns.iter().any(|column| column.task.is_some())
+    }
+
+    ///Picks up the answers that came in, true when there was one
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for column in self.columns.iter_mut() {
+            let finished = match column.task.as_mut() {
+                Some(task) => task.now_or_never(),
+                None => continue,
+            };
+            match finished {
+                Some(Ok((answer, latency))) => {
+                    column.task = None;
+                    column.answer = Some(answer);
+                    column.latency = Some(latency);
+                    changed = true;
+                }
+                Some(Err(err)) => {
+                    column.task = None;
+                    error!("Comparison with {} failed: {}", column.target.describe(), err);
+                }
+                None => {}
+            }
+        }
+        return changed
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_name_a_model_a_profile_or_both() {
+        assert_eq!(
+            Target::parse("gpt-4@precise", "mock").unwrap(),
+            Target { model: "gpt-4".to_string(), profile: Some("precise".to_string()) }
+        );
+        assert_eq!(Target::parse("@creative", "mock").unwrap().describe(), "mock@creative");
+        assert_eq!(Target::parse("meta-llama/llama-2", "mock").unwrap().profile, None);
+        assert!(Target::parse("gpt-4@", "mock").is_err());
+    }
+}
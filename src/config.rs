@@ -0,0 +1,137 @@
+use log::{info, error};
+
+use std::{collections::BTreeMap, error::Error, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::api::{Network, RetryPolicy, Timeouts};
+use crate::attach::AttachConfig;
+use crate::blocks::RunConfig;
+use crate::cassette::CassetteConfig;
+use crate::context::ContextConfig;
+use crate::models::ModelsConfig;
+use crate::params::ParamsConfig;
+use crate::pricing::{self, Price};
+use crate::provider::Endpoint;
+use crate::session::Persona;
+use crate::shell::ShellConfig;
+use crate::tools::ToolsConfig;
+
+
+///Settings read from the gpterm.toml file, every section is optional
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    ///Provider a session starts with, "default" or a key of `providers`
+    pub provider: String,
+    ///Model a session starts with
+    pub model: Option<String>,
+    ///The default provider
+    pub endpoint: Endpoint,
+    ///More providers, switchable with `:provider <name>`
+    pub providers: BTreeMap<String, Endpoint>,
+    ///How failed requests are retried
+    pub retry: RetryPolicy,
+    ///How long requests may take
+    pub timeouts: Timeouts,
+    ///Proxy and TLS settings
+    pub network: Network,
+    ///Recording and replaying of requests
+    pub cassette: CassetteConfig,
+    ///Price per model name prefix, to estimate what a session costs
+    pub prices: BTreeMap<String, Price>,
+    ///Context window sizes and what to do when a request won't fit
+    pub context: ContextConfig,
+    ///Size limits of files attached with `@path`
+    pub attachments: AttachConfig,
+    ///How `:!` runs commands and whether their output is sent
+    pub shell: ShellConfig,
+    ///Interpreters code blocks of answers are run with
+    pub run: RunConfig,
+    ///Local tools the model may call
+    pub tools: ToolsConfig,
+    ///How long the models a provider serves are cached
+    pub models: ModelsConfig,
+    ///Sampling parameter profiles and the models using them
+    pub params: ParamsConfig,
+    ///Persona new sessions start with
+    pub persona: Option<String>,
+    ///System prompts by name, switchable with `:persona <name>`
+    pub personas: BTreeMap<String, Persona>,
+    ///Where `:save` writes sessions, next to the config file by default
+    pub sessions_dir: Option<PathBuf>,
+    ///Where `:template` and `gpterm ask -t` find templates, next to the config file by default
+    pub templates_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            provider: "default".to_string(),
+            model: None,
+            endpoint: Endpoint::default(),
+            providers: BTreeMap::new(),
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            network: Network::default(),
+            cassette: CassetteConfig::default(),
+            prices: pricing::default_prices(),
+            context: ContextConfig::default(),
+            attachments: AttachConfig::default(),
+            shell: ShellConfig::default(),
+            run: RunConfig::default(),
+            tools: ToolsConfig::default(),
+            models: ModelsConfig::default(),
+            params: ParamsConfig::default(),
+            persona: None,
+            personas: BTreeMap::new(),
+            sessions_dir: None,
+            templates_dir: None,
+        }
+    }
+}
+
+impl Config {
+
+    pub fn endpoint_named(&self, name: &str) -> Option<&Endpoint> {
+        if name == "default" {
+            return Some(&self.endpoint)
+        }
+        return self.providers.get(name)
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        let mut names = vec!["default".to_string()];
+        names.extend(self.providers.keys().cloned());
+        return names
+    }
+
+    ///Reads the config at `path`, falling back to defaults when there is none
+    pub fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("No config file at {}, using defaults", path);
+                return Ok(Config::default())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        match toml::from_str::<Config>(&contents) {
+            Ok(mut config) => {
+                //a [prices] table adds to the known prices instead of replacing them
+                let mut prices = pricing::default_prices();
+                prices.append(&mut config.prices);
+                config.prices = prices;
+                if let Err(err) = config.params.check() {
+                    return Err(format!("bad [params] in {}: {}", path, err).into())
+                }
+                return Ok(config)
+            }
+            Err(err) => {
+                error!("Couldn't parse config file {}", path);
+                return Err(err.into())
+            }
+        }
+    }
+}
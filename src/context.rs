@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+
+///What happens to a request that doesn't fit the context window
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    ///Send it anyway, the counter turns red
+    Warn,
+    ///Refuse to send it
+    Block,
+}
+
+///Which part of the conversation goes with a question
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    ///All of it
+    Full,
+    ///The last `turns` questions and what came after them
+    Window,
+    ///The most recent messages that fit the token budget
+    Budget,
+}
+
+///Settings of the `[context]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ContextConfig {
+    pub overflow: Overflow,
+    ///Context window per model name prefix, for models gpterm doesn't know
+    pub windows: BTreeMap<String, usize>,
+    pub strategy: Strategy,
+    ///Earlier questions the window strategy keeps, with what came after them
+    pub turns: usize,
+    ///Tokens the budget strategy may use, by default what the window leaves
+    pub budget: Option<usize>,
+    ///Have the model summarize what is left out, the summary is sent instead
+    pub summarize: bool,
+    ///Context length of models as their provider lists them, by exact name
+    #[serde(skip)]
+    pub reported: BTreeMap<String, usize>,
+}
+
+impl Default for ContextConfig {
+    fn default() -> ContextConfig {
+        ContextConfig {
+            overflow: Overflow::Warn,
+            windows: BTreeMap::new(),
+            strategy: Strategy::Full,
+            turns: 10,
+            budget: None,
+            summarize: false,
+            reported: BTreeMap::new(),
+        }
+    }
+}
+
+impl ContextConfig {
+    ///Tokens `model` can take in, prompt and answer together.
+    ///The longest configured prefix wins over what the provider reported,
+    ///which wins over what tiktoken knows
+    pub fn window(&self, model: &str) -> usize {
+        let configured = self.windows
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len());
+        match (configured, self.reported.get(model)) {
+            (Some((_, window)), _) => return *window,
+            (None, Some(window)) => return *window,
+            (None, None) => return tiktoken_rs::model::get_context_size(model),
+        }
+    }
+
+    ///Which of `entries` (oldest first) are sent, pinned ones always are.
+    ///`room` is what the context window leaves for them
+    pub fn select(&self, entries: &[Entry], room: usize) -> Vec<bool> {
+        match self.strategy {
+            Strategy::Full => return vec![true; entries.len()],
+            Strategy::Window => {
+                let queries: Vec<usize> = (0..entries.len())
+                    .filter(|&index| entries[index].query)
+                    .collect();
+                let start = match self.turns {
+                    0 => entries.len(),
+                    turns => queries.len().checked_sub(turns).map_or(0, |first| queries[first]),
+                };
+                return entries
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| index >= start || entry.pinned)
+                    .collect()
+            }
+            Strategy::Budget => {
+                let room = self.budget.unwrap_or(room);
+                let mut used: usize = entries
+                    .iter()
+                    .filter(|entry| entry.pinned)
+                    .map(|entry| entry.tokens)
+                    .sum();
+
+                let mut kept: Vec<bool> = entries.iter().map(|entry| entry.pinned).collect();
+                //newest first, stopping at the first one that doesn't fit
+                for (index, entry) in entries.iter().enumerate().rev() {
+                    if entry.pinned {
+                        continue;
+                    }
+                    if used + entry.tokens > room {
+                        break;
+                    }
+                    used += entry.tokens;
+                    kept[index] = true;
+                }
+                return kept
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let strategy = match self.strategy {
+            Strategy::Full => "full conversation".to_string(),
+            Strategy::Window => format!("last {} questions", self.turns),
+            Strategy::Budget => match self.budget {
+                Some(budget) => format!("last {} tokens", budget),
+                None => "as much as fits".to_string(),
+            },
+        };
+        match self.summarize {
+            true => return format!("{}, older turns summarized", strategy),
+            false => return strategy,
+        }
+    }
+}
+
+
+///A message of the conversation, as far as picking the context goes
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub tokens: usize,
+    pub pinned: bool,
+    ///Whether the user said it, a turn starts with it
+    pub query: bool,
+}
+
+
+///Stands in for the messages left out of the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    ///Indices in the transcript of the messages summarized
+    pub covers: Vec<usize>,
+    pub text: String,
+}
+
+///Tokens the model may use to summarize
+pub const SUMMARY_MAX_TOKENS: u32 = 256;
+
+///Asks for a summary of `turns`, continuing `previous` when there is one
+pub fn summary_prompt(previous: Option<&str>, turns: &str) -> String {
+    let previous = match previous {
+        Some(previous) => format!("Summary so far: {}\n\n", previous),
+        None => String::new(),
+    };
+    return format!(
+        "Summarize the conversation below in a few sentences, \
+        keep names, decisions and code identifiers.\n\n{}{}Summary:",
+        previous, turns
+    )
+}
+
+///How a summary starts the prompt
+pub fn summary_turn(summary: &str) -> String {
+    return format!("Summary of the earlier conversation: {}\n\n", summary)
+}
+
+
+///How much of the context window a request uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    ///Tokens of the prompt
+    pub prompt: usize,
+    ///Tokens the answer may take
+    pub max_tokens: usize,
+    pub window: usize,
+}
+
+impl Budget {
+    pub fn fits(&self) -> bool {
+        return self.prompt + self.max_tokens <= self.window
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(queries: &[bool]) -> Vec<Entry> {
+        return queries.iter().map(|&query| Entry { tokens: 10, pinned: false, query }).collect()
+    }
+
+    #[test]
+    fn window_keeps_the_last_turns_and_pins() {
+        let config = ContextConfig { strategy: Strategy::Window, turns: 2, ..ContextConfig::default() };
+        let mut conversation = entries(&[true, false, true, false, true, false]);
+        conversation[0].pinned = true;
+
+        assert_eq!(config.select(&conversation, usize::MAX), vec![true, false, true, true, true, true]);
+        assert_eq!(config.select(&conversation[..2], usize::MAX), vec![true, true]);
+    }
+
+    #[test]
+    fn budget_keeps_what_fits_newest_first() {
+        let config = ContextConfig { strategy: Strategy::Budget, ..ContextConfig::default() };
+        let mut conversation = entries(&[true, false, true, false]);
+        conversation[0].pinned = true;
+        conversation[2].tokens = 50;
+
+        //the pinned question takes 10, the last answer 10, the big question doesn't fit
+        assert_eq!(config.select(&conversation, 40), vec![true, false, false, true]);
+
+        let config = ContextConfig { budget: Some(100), ..config };
+        assert_eq!(config.select(&conversation, 40), vec![true, true, true, true]);
+    }
+
+    #[test]
+    fn configured_windows_win() {
+        let mut config = ContextConfig::default();
+        assert_eq!(config.window("text-davinci-003"), 4097);
+        assert_eq!(config.window("gpt-4-32k-0613"), 32_768);
+
+        config.windows.insert("llama-2".to_string(), 4096);
+        config.windows.insert("llama-2-70b-32k".to_string(), 32_000);
+        assert_eq!(config.window("llama-2-7b"), 4096);
+        assert_eq!(config.window("llama-2-70b-32k-instruct"), 32_000);
+    }
+}
@@ -0,0 +1,893 @@
+//Runs the app headless against the mock server: keys go in,
+//the rendered screen comes out as text
+
+use std::time::{Duration, Instant};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tui::{backend::TestBackend, Terminal};
+
+use crate::app::App;
+use crate::config::Config;
+use crate::context::{ContextConfig, Overflow, Strategy};
+use crate::mock_server::{self, Settings};
+use crate::provider::{Endpoint, Flavour};
+use crate::params::ParamsConfig;
+use crate::session::Persona;
+use crate::tools::ToolsConfig;
+
+
+///Scripts written so far, tests run in parallel
+static SCRIPTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+pub struct Harness {
+    app: App,
+    terminal: Terminal<TestBackend>,
+}
+
+impl Harness {
+    ///An app talking to a fresh mock server
+    pub fn start(settings: Settings, stream: bool) -> Harness {
+        let config = Config {
+            endpoint: Endpoint { stream, ..Endpoint::default() },
+            ..Config::default()
+        };
+        return Harness::with_config(settings, config)
+    }
+
+    ///Like `start`, the endpoint's base url and the retry delays are replaced
+    pub fn with_config(settings: Settings, mut config: Config) -> Harness {
+        config.endpoint.base_url = Some(mock_server::spawn(settings));
+        config.retry = crate::api::RetryPolicy { initial_backoff_ms: 1, jitter: false, ..Default::default() };
+
+        let mut app = App::default();
+        app.set_handler(String::new(), &config).unwrap();
+        app.set_username("tester".to_string());
+
+        let terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        return Harness { app, terminal }
+    }
+
+    ///An app answering with the in-process mock, playing `script` ([[reply]] tables)
+    pub fn scripted(script: &str) -> Harness {
+        return Harness::scripted_with(script, Config::default())
+    }
+
+    ///Like `scripted`, with the rest of the settings from `config`
+    pub fn scripted_with(script: &str, mut config: Config) -> Harness {
+        let path = std::env::temp_dir().join(format!(
+            "gpterm-harness-script-{}-{}.toml",
+            std::process::id(),
+            SCRIPTS.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        ));
+        std::fs::write(&path, script).unwrap();
+
+        config.provider = "mock".to_string();
+        config.providers.insert("mock".to_string(), Endpoint {
+            flavour: Flavour::Mock,
+            script: Some(path.clone()),
+            ..Endpoint::default()
+        });
+        //the script is read once the provider is built
+        let harness = Harness::with_config(Settings::default(), config);
+        std::fs::remove_file(path).unwrap();
+        return harness
+    }
+
+    ///Presses `code`, true when the app wants to quit
+    pub fn press(&mut self, code: KeyCode) -> bool {
+        return crate::handle_key(&mut self.app, KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.chars() {
+            self.press(KeyCode::Char(c));
+        }
+    }
+
+    ///Types `query` in insert mode and sends it
+    pub fn ask(&mut self, query: &str) {
+        self.press(KeyCode::Char('i'));
+        self.type_text(query);
+        self.press(KeyCode::Enter);
+    }
+
+    ///Lets the request in flight finish, like the main loop does
+    pub async fn settle(&mut self) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if self.app.poll_answer() {
+                self.app.scroll_to_bottom();
+            }
+            if !self.app.is_waiting() && !self.app.is_running() {
+                return
+            }
+            assert!(Instant::now() < deadline, "request didn't finish:\n{}", self.screen());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    ///Draws the app and returns the screen, one line per row
+    pub fn screen(&mut self) -> String {
+        self.terminal.draw(|f| crate::render::ui(f, &self.app)).unwrap();
+
+        let buffer = self.terminal.backend().buffer();
+        let mut screen = String::new();
+        for y in 0..buffer.area.height {
+            for x in 0..buffer.area.width {
+                screen.push_str(&buffer.get(x, y).symbol);
+            }
+            screen.push('\n');
+        }
+        return screen
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::{Message, MessageType};
+    use crate::session::Session;
+
+    #[tokio::test]
+    async fn answers_show_up_in_the_transcript() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.ask("hello there");
+
+        assert!(harness.screen().contains("waiting for text-davinci-003"));
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(screen.contains("tester"));
+        //the mock server echoes the whole prompt
+        assert!(screen.contains("echo: tester: hello there"));
+        assert!(screen.contains("INSERT"));
+    }
+
+    #[tokio::test]
+    async fn usage_and_cost_are_shown_and_exported() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.ask("hello there");
+        harness.settle().await;
+
+        //the mock server counts words, text-davinci-003 costs $0.02 per 1000 tokens
+        let screen = harness.screen();
+        assert!(screen.contains("4+4 tokens, $0.0002"));
+        assert!(screen.contains("Σ 8 tokens $0.0002"));
+
+        let path = std::env::temp_dir().join(format!("gpterm-export-{}.md", std::process::id()));
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Char(':'));
+        harness.type_text(&format!("export {}", path.display()));
+        harness.press(KeyCode::Enter);
+
+        let exported = std::fs::read_to_string(&path).unwrap();
+        assert!(exported.contains("**text-davinci-003** _(4+4 tokens, $0.0002)_"));
+        assert!(exported.contains("session: 4 prompt + 4 completion = 8 tokens, about $0.0002"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_answers_show_up() {
+        let mut harness = Harness::start(Settings { chunk_delay_ms: 5, ..Default::default() }, true);
+        harness.ask("one two three");
+        harness.settle().await;
+
+        assert!(harness.screen().contains("echo: tester: one two three"));
+    }
+
+    #[tokio::test]
+    async fn the_conversation_is_sent_along() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.ask("first");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+        harness.ask("second");
+        harness.settle().await;
+
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        assert!(prompt.starts_with("tester: first\n\ntext-davinci-003: echo: tester: first"));
+        assert!(prompt.ends_with("tester: second\n\ntext-davinci-003:"));
+    }
+
+    fn command(harness: &mut Harness, command: &str) {
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Char(':'));
+        harness.type_text(command);
+        harness.press(KeyCode::Enter);
+    }
+
+    #[tokio::test]
+    async fn the_window_keeps_recent_turns_and_pinned_messages() {
+        let mut harness = Harness::start(Settings::default(), false);
+        for query in ["first", "second", "third"] {
+            harness.ask(query);
+            harness.settle().await;
+            harness.press(KeyCode::Esc);
+        }
+        command(&mut harness, "context window 1");
+        //the first question, counting back over three answers and two questions
+        command(&mut harness, "pin 6");
+        assert!(harness.screen().contains("pinned tester's message"));
+
+        harness.ask("fourth");
+        harness.settle().await;
+
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        //the first answer and the second turn are left out
+        assert!(prompt.starts_with("tester: first\n\ntester: third\n\ntext-davinci-003: echo: tester: first"));
+    }
+
+    #[tokio::test]
+    async fn turns_left_out_are_summarized() {
+        let context = ContextConfig { strategy: Strategy::Window, turns: 1, summarize: true, ..ContextConfig::default() };
+        let mut harness = Harness::with_config(Settings::default(), Config { context, ..Config::default() });
+        for query in ["first", "second", "third"] {
+            harness.ask(query);
+            harness.settle().await;
+            harness.press(KeyCode::Esc);
+        }
+
+        //the mock server echoes the summary request
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        assert!(prompt.starts_with("Summary of the earlier conversation: echo: Summarize the conversation"));
+        assert!(prompt.contains("\n\ntester: third\n\ntext-davinci-003:"));
+    }
+
+    #[tokio::test]
+    async fn personas_are_sent_shown_and_saved_with_the_session() {
+        let dir = std::env::temp_dir().join(format!("gpterm-harness-sessions-{}", std::process::id()));
+        let mut config = Config { sessions_dir: Some(dir.clone()), ..Config::default() };
+        config.personas.insert("rust-reviewer".to_string(), Persona { system: "Review rust.".to_string() });
+        let mut harness = Harness::with_config(Settings::default(), config);
+
+        command(&mut harness, "persona rust-reviewer");
+        command(&mut harness, "save review");
+        harness.ask("hi");
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(screen.contains("gpTerm · review · rust-reviewer"));
+        //completions get the system prompt ahead of the conversation
+        assert!(screen.contains("echo: Review rust."));
+
+        command(&mut harness, "persona none");
+        command(&mut harness, "open review");
+        assert!(harness.screen().contains("gpTerm · review · rust-reviewer"));
+        assert_eq!(harness.app.get_call().unwrap().system.as_deref(), Some("Review rust."));
+
+        //pins are saved like the other message changes
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('p'));
+        let saved = std::fs::read_to_string(dir.join("review.json")).unwrap();
+        assert!(saved.contains("\"pinned\": true"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_sessions_are_searched_and_opened_at_the_match() {
+        let dir = std::env::temp_dir().join(format!("gpterm-harness-find-{}", std::process::id()));
+        let bodies = (0..40).map(|index| match index {
+            8 => "the borrow checker and lifetimes".to_string(),
+            index => format!("message number {}", index),
+        });
+        let messages = bodies.map(|body| Message::from("tester".to_string(), body, MessageType::Query)).collect();
+        Session { messages, ..Session::default() }.save(&dir, "rust").unwrap();
+        let pasta = vec![Message::from("tester".to_string(), "a lifetime of pasta".to_string(), MessageType::Query)];
+        Session { messages: pasta, ..Session::default() }.save(&dir, "cooking").unwrap();
+
+        let config = Config { sessions_dir: Some(dir.clone()), ..Config::default() };
+        let mut harness = Harness::with_config(Settings::default(), config);
+        //the output of a command running would land in the session opened
+        command(&mut harness, "!sleep 0.2");
+        command(&mut harness, "open rust");
+        assert!(harness.app.get_command().contains("wait for the current request"));
+        harness.settle().await;
+
+        command(&mut harness, "find lifetime borrow");
+        let screen = harness.screen();
+        assert!(screen.contains("sessions with lifetime borrow: 1 found"));
+        harness.press(KeyCode::Esc);
+
+        command(&mut harness, "find lifetime");
+        let screen = harness.screen();
+        assert!(screen.contains("rust #9  today"));
+        assert!(screen.contains("  the borrow checker and lifetimes"));
+        assert!(screen.contains("cooking #1"));
+        let first = harness.app.get_finder().unwrap().hits[0].session.clone();
+        if first == "cooking" {
+            harness.press(KeyCode::Down);
+        }
+        harness.press(KeyCode::Enter);
+
+        assert!(harness.app.get_finder().is_none());
+        let screen = harness.screen();
+        assert!(screen.contains("gpTerm · rust"));
+        assert!(screen.contains("the borrow checker and lifetimes"));
+        assert!(!screen.contains("message number 39"));
+        assert_eq!(harness.app.search_matches()[harness.app.get_search().unwrap().current].message, 8);
+
+        command(&mut harness, "find nothing-like-this");
+        assert!(harness.app.get_command().contains("no saved session matches"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn templates_ask_for_missing_variables() {
+        let dir = std::env::temp_dir().join(format!("gpterm-harness-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("review.md"), "Review this {{lang}} code for {{focus}}").unwrap();
+        let config = Config { templates_dir: Some(dir.clone()), ..Config::default() };
+        let mut harness = Harness::with_config(Settings::default(), config);
+
+        command(&mut harness, "template review focus=errors");
+        assert!(harness.screen().contains("lang (review)"));
+
+        harness.type_text("rust");
+        harness.press(KeyCode::Enter);
+        assert!(harness.screen().contains("Review this rust code for errors"));
+
+        harness.press(KeyCode::Enter);
+        harness.settle().await;
+        assert!(harness.app.get_call().unwrap().prompt.contains("tester: Review this rust code for errors"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn referenced_files_are_previewed_and_inlined() {
+        let dir = std::env::temp_dir().join(format!("gpterm-harness-attach-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.rs"), "pub fn answer() -> u8 { 42 }\n").unwrap();
+        let mut harness = Harness::start(Settings::default(), false);
+
+        harness.press(KeyCode::Char('i'));
+        harness.type_text(&format!("explain @{}/li", dir.display()));
+        harness.press(KeyCode::Tab);
+        let screen = harness.screen();
+        assert!(screen.contains("Attached"));
+        assert!(screen.contains("lib.rs  29 B"));
+
+        harness.press(KeyCode::Enter);
+        harness.settle().await;
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        assert!(prompt.contains("lib.rs:\n```rs\npub fn answer() -> u8 { 42 }\n```"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shell_output_goes_along_with_the_next_question_only() {
+        let mut harness = Harness::start(Settings::default(), false);
+        command(&mut harness, "!echo from the shell; exit 2");
+        harness.settle().await;
+        assert!(harness.screen().contains("$ echo from the shell; exit 2 (exit 2)"));
+
+        harness.ask("what happened");
+        harness.settle().await;
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        assert!(prompt.starts_with("shell: $ echo from the shell; exit 2\nfrom the shell\n(exit 2)\n\ntester: what happened"));
+
+        harness.press(KeyCode::Esc);
+        harness.ask("!echo typed");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+        harness.ask("and now");
+        harness.settle().await;
+        let prompt = &harness.app.get_call().unwrap().prompt;
+        //the first output was for the first question
+        assert!(prompt.starts_with("tester: what happened"));
+        assert!(prompt.contains("shell: $ echo typed\ntyped\n(exit 0)\n\ntester: and now"));
+    }
+
+    #[tokio::test]
+    async fn code_blocks_run_once_confirmed() {
+        let mut harness = Harness::scripted("[[reply]]\ntext = \"try:\\n```sh\\necho ran it\\n```\"");
+        harness.ask("how");
+        harness.settle().await;
+
+        command(&mut harness, "blocks");
+        assert!(harness.screen().contains("1: sh, 1 lines"));
+
+        command(&mut harness, "run");
+        assert!(harness.screen().contains("y: yes   n: no"));
+        harness.press(KeyCode::Char('n'));
+        assert!(!harness.app.is_running());
+
+        command(&mut harness, "run 1");
+        harness.press(KeyCode::Char('y'));
+        harness.settle().await;
+        let screen = harness.screen();
+        assert!(screen.contains("$ echo ran it (exit 0)"));
+        assert!(!screen.contains("y: yes"));
+    }
+
+    #[tokio::test]
+    async fn code_blocks_come_from_the_answer_selected() {
+        let path = std::env::temp_dir().join(format!("gpterm-harness-selected-{}.txt", std::process::id()));
+        let mut harness = Harness::scripted(
+            "[[reply]]\ntext = \"```sh\\necho first\\n```\\n```text\\nfirst file\\n```\"\n[[reply]]\ntext = \"```sh\\necho second\\n```\""
+        );
+        harness.ask("one");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+        harness.ask("two");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+
+        //typed straight away, the command helper's esc would drop the selection
+        let typed = |harness: &mut Harness, command: &str| {
+            harness.press(KeyCode::Char(':'));
+            harness.type_text(command);
+            harness.press(KeyCode::Enter);
+        };
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('K'));
+        typed(&mut harness, "blocks");
+        assert!(harness.app.get_command().contains("message 3 isn't an answer"));
+
+        harness.press(KeyCode::Char('K'));
+        typed(&mut harness, "blocks");
+        assert_eq!(harness.app.get_command(), "in message 2: 1: sh, 1 lines · 2: text, 1 lines");
+        typed(&mut harness, "run 1");
+        harness.press(KeyCode::Char('y'));
+        harness.settle().await;
+        assert!(harness.screen().contains("$ echo first (exit 0)"));
+
+        typed(&mut harness, &format!("write 2 {}", path.display()));
+        harness.press(KeyCode::Char('y'));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first file\n");
+        std::fs::remove_file(path).unwrap();
+
+        command(&mut harness, "blocks");
+        assert_eq!(harness.app.get_command(), "in the last answer: 1: sh, 1 lines");
+    }
+
+    #[tokio::test]
+    async fn code_blocks_are_written_after_a_diff_preview() {
+        let path = std::env::temp_dir().join(format!("gpterm-harness-write-{}.txt", std::process::id()));
+        std::fs::write(&path, "old line\n").unwrap();
+        let mut harness = Harness::scripted("[[reply]]\ntext = \"```text\\nnew line\\n```\"");
+        harness.ask("rewrite it");
+        harness.settle().await;
+
+        //no file name in the fence, the path is asked for
+        command(&mut harness, "write");
+        assert!(harness.screen().contains(":write "));
+        harness.type_text(&path.display().to_string());
+        harness.press(KeyCode::Enter);
+
+        let screen = harness.screen();
+        assert!(screen.contains("overwrite"));
+        assert!(screen.contains("-old line"));
+        assert!(screen.contains("+new line"));
+
+        harness.press(KeyCode::Char('y'));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new line\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tool_calls_are_approved_one_by_one_and_fed_back() {
+        let script = r#"
+            [[reply]]
+            [[reply.tool_call]]
+            name = "read_file"
+            arguments = '{"path": "Cargo.toml"}'
+            [[reply.tool_call]]
+            name = "run_command"
+            arguments = '{"command": "rm -rf /"}'
+            [[reply]]
+            text = "it's gpterm"
+        "#;
+        let tools = ToolsConfig {
+            enabled: vec!["read_file".to_string(), "run_command".to_string()],
+            ..ToolsConfig::default()
+        };
+        let mut harness = Harness::scripted_with(script, Config { tools, ..Config::default() });
+        harness.ask("what crate is this?");
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(screen.contains("call read_file?"));
+        assert!(screen.contains("\"path\": \"Cargo.toml\""));
+        harness.press(KeyCode::Char('y'));
+        harness.settle().await;
+
+        assert!(harness.screen().contains("call run_command?"));
+        harness.press(KeyCode::Char('n'));
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(screen.contains("tool run_command rm -rf /"));
+        assert!(screen.contains("the user didn't allow this call"));
+        assert_eq!(harness.app.last_message().unwrap().get_body(), "it's gpterm");
+
+        let rounds = &harness.app.get_call().unwrap().tool_rounds;
+        assert_eq!(rounds.len(), 1);
+        assert_eq!(rounds[0].calls[0].name, "read_file");
+        assert!(rounds[0].results[0].contains("[package]"));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_context_window_can_be_blocked() {
+        let mut context = ContextConfig { overflow: Overflow::Block, ..ContextConfig::default() };
+        context.windows.insert("text-davinci".to_string(), 1024);
+        let mut harness = Harness::with_config(Settings::default(), Config { context, ..Config::default() });
+
+        harness.press(KeyCode::Char('i'));
+        harness.type_text("hello");
+        //"tester: hello\n\ntext-davinci-003:" takes 14 tokens, answers up to 1000
+        assert!(harness.screen().contains("14+1000/1024 ctx"));
+
+        harness.type_text(" there, how are you doing today my dear friend? all good?");
+        harness.press(KeyCode::Enter);
+
+        let screen = harness.screen();
+        assert!(screen.contains("+1000/1024 ctx"));
+        assert!(screen.contains("not sent"));
+        assert!(!harness.app.is_waiting());
+
+        //compared answers are held to the window of each target
+        command(&mut harness, "compare gpt-4 text-davinci-003");
+        harness.press(KeyCode::Char('i'));
+        harness.press(KeyCode::Enter);
+        assert_eq!(harness.app.get_command(), "Error: text-davinci-003: over the context window, not sent");
+        assert!(harness.app.get_comparison().is_none());
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_in_the_transcript() {
+        let mut harness = Harness::start(Settings { fail_every: 1, fail_status: 500, ..Default::default() }, false);
+        harness.ask("hi");
+        harness.settle().await;
+
+        assert!(harness.screen().contains("something went wrong in the request"));
+    }
+
+    #[tokio::test]
+    async fn models_command_lists_the_served_models() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.press(KeyCode::Char(':'));
+        harness.type_text("models");
+        harness.press(KeyCode::Enter);
+        harness.settle().await;
+
+        let screen = harness.screen();
+        for model in mock_server::MODELS {
+            assert!(screen.contains(model));
+        }
+    }
+
+    #[tokio::test]
+    async fn models_are_picked_from_a_popup_and_completed() {
+        let mut harness = Harness::start(Settings::default(), false);
+        command(&mut harness, "model");
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(screen.contains("mock-chat        2048 ctx  gpterm"));
+        assert!(screen.contains("mock-completion  2048 ctx  gpterm"));
+
+        harness.type_text("compl");
+        assert!(!harness.screen().contains("mock-chat"));
+        harness.press(KeyCode::Enter);
+        assert_eq!(harness.app.get_selected_model(), "mock-completion");
+        assert!(harness.screen().contains("using mock-completion, 2048 tokens of context"));
+
+        //the list is cached, completion needs no request
+        harness.press(KeyCode::Char(':'));
+        harness.type_text("model mock-c");
+        harness.press(KeyCode::Tab);
+        assert_eq!(harness.app.get_command(), "model mock-c");
+        harness.type_text("h");
+        harness.press(KeyCode::Tab);
+        assert_eq!(harness.app.get_command(), "model mock-chat");
+        harness.press(KeyCode::Enter);
+        assert_eq!(harness.app.get_selected_model(), "mock-chat");
+    }
+
+    #[tokio::test]
+    async fn parameters_come_from_profiles_and_are_validated() {
+        let params = toml::from_str::<ParamsConfig>(r#"
+            [profiles.precise]
+            temperature = 0.2
+            [profiles.creative]
+            temperature = 1.2
+            [models]
+            "text-davinci" = "precise"
+        "#).unwrap();
+        let mut harness = Harness::scripted_with("[[reply]]\ntext = \"ok\"", Config { params, ..Config::default() });
+
+        command(&mut harness, "params");
+        let screen = harness.screen();
+        assert!(screen.contains("parameters · profile precise"));
+        assert!(screen.contains("temperature       0.2"));
+
+        //an edit out of range is refused and shown
+        harness.press(KeyCode::Enter);
+        harness.press(KeyCode::Backspace);
+        harness.press(KeyCode::Backspace);
+        harness.type_text("3");
+        harness.press(KeyCode::Enter);
+        assert!(harness.screen().contains("temperature 3 is out of 0 to 2"));
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Down);
+        harness.press(KeyCode::Enter);
+        harness.type_text("0.9");
+        harness.press(KeyCode::Enter);
+        assert!(harness.screen().contains("0.9  set by hand"));
+        harness.press(KeyCode::Esc);
+        assert!(harness.app.get_params_panel().is_none());
+
+        command(&mut harness, "set max_tokens 0");
+        assert!(harness.screen().contains("max_tokens must be at least 1"));
+        command(&mut harness, "profile creative");
+        command(&mut harness, "set stop ###,\\n\\n");
+        harness.ask("hi");
+        harness.settle().await;
+
+        let params = &harness.app.get_call().unwrap().params;
+        assert_eq!((params.temperature, params.top_p, params.max_tokens), (1.2, Some(0.9), 1000));
+        assert_eq!(params.stop, vec!["###", "\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn compared_answers_show_side_by_side_and_one_is_kept() {
+        let params = toml::from_str::<ParamsConfig>(r#"
+            [profiles.creative]
+            temperature = 1.2
+        "#).unwrap();
+        let settings = Settings { latency_ms: 100, ..Settings::default() };
+        let mut harness = Harness::with_config(settings, Config { params, ..Config::default() });
+
+        command(&mut harness, "compare mock-chat");
+        assert!(harness.app.get_command().contains("at least two"));
+        command(&mut harness, "compare mock-chat @nope");
+        assert!(harness.app.get_command().contains("no profile nope"));
+        command(&mut harness, "compare mock-chat @creative");
+        harness.ask("hello both");
+        let screen = harness.screen();
+        assert!(screen.contains("compare: hello both"));
+        assert!(screen.contains("1 mock-chat"));
+        assert!(screen.contains("2 text-davinci-003@creative"));
+        assert!(screen.contains("waiting..."));
+        harness.settle().await;
+
+        let screen = harness.screen();
+        assert!(!screen.contains("waiting..."));
+        assert!(screen.contains("echo: tester: hello both"));
+        assert!(screen.contains("tokens"));
+        assert!(harness.app.get_comparison().unwrap().columns.iter().all(|column| column.latency.is_some()));
+        //nothing joins the transcript until an answer is kept
+        assert!(harness.app.last_message().is_none());
+
+        command(&mut harness, "keep 3");
+        assert!(harness.app.get_command().contains("from 1 to 2"));
+        //esc would go back to the transcript
+        harness.press(KeyCode::Char(':'));
+        harness.type_text("keep 2");
+        harness.press(KeyCode::Enter);
+        assert!(harness.app.get_comparison().is_none());
+        assert!(harness.app.last_message().unwrap().get_body().contains("echo: tester: hello both"));
+        assert!(harness.app.get_command().contains("kept the answer of text-davinci-003@creative"));
+
+        //queries go to every target until comparing is turned off
+        command(&mut harness, "compare off");
+        harness.ask("just one");
+        harness.settle().await;
+        assert_eq!(harness.app.get_call().unwrap().query, "just one");
+    }
+
+    #[tokio::test]
+    async fn tabs_keep_their_own_conversation_and_answer_in_the_background() {
+        let settings = Settings { latency_ms: 150, ..Settings::default() };
+        let mut harness = Harness::start(settings, false);
+        harness.ask("first question");
+        assert!(!harness.screen().contains(" 1 first question"));
+
+        command(&mut harness, "tabnew");
+        let screen = harness.screen();
+        assert!(screen.lines().next().unwrap().contains(" 1 first question … "));
+        assert!(screen.lines().next().unwrap().contains(" 2 new "));
+        //the first tab's request doesn't hold this one up
+        assert!(!harness.app.is_waiting());
+        command(&mut harness, "model mock-chat");
+        harness.ask("second question");
+        harness.settle().await;
+        assert_eq!(harness.app.get_call().unwrap().model, "mock-chat");
+        assert!(harness.app.last_message().unwrap().get_body().contains("second question"));
+        assert!(!harness.app.last_message().unwrap().get_body().contains("first question"));
+
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Tab);
+        harness.settle().await;
+        assert_eq!(harness.app.get_selected_model(), "text-davinci-003");
+        assert!(harness.app.last_message().unwrap().get_body().contains("echo: tester: first question"));
+        let screen = harness.screen();
+        assert!(screen.lines().next().unwrap().contains(" 2 second question "));
+        assert!(!screen.lines().skip(1).any(|line| line.contains("second question")));
+
+        command(&mut harness, "tab 2");
+        assert_eq!(harness.app.active_tab(), 1);
+        command(&mut harness, "tabclose");
+        assert_eq!(harness.app.get_tabs().len(), 1);
+        assert_eq!(harness.app.get_selected_model(), "text-davinci-003");
+        command(&mut harness, "tabclose");
+        assert!(harness.app.get_command().contains("last tab"));
+    }
+
+    #[tokio::test]
+    async fn background_tabs_open_no_popups_over_the_tab_shown() {
+        let settings = Settings { latency_ms: 100, ..Settings::default() };
+        let mut harness = Harness::start(settings, false);
+        command(&mut harness, "model");
+        command(&mut harness, "tabnew");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while harness.app.get_tabs()[0].1 {
+            assert!(Instant::now() < deadline, "models weren't listed");
+            harness.app.poll_answer();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(harness.app.get_picker().is_none());
+
+        harness.press(KeyCode::Tab);
+        assert_eq!(harness.app.get_status(), "models listed, :model picks one");
+        assert_eq!(harness.app.get_selected_model(), "text-davinci-003");
+    }
+
+    #[tokio::test]
+    async fn slash_searches_the_transcript_and_n_jumps_between_matches() {
+        let mut harness = Harness::start(Settings::default(), false);
+        for index in 0..40 {
+            let body = match index {
+                5 => "the first Needle is here".to_string(),
+                20 => "another needle, and needles".to_string(),
+                index => format!("message number {}", index),
+            };
+            harness.app.push_content("tester".to_string(), MessageType::Query, body);
+        }
+        harness.app.scroll_to_bottom();
+        let bottom = harness.app.get_scroll();
+        assert!(!harness.screen().contains("Needle"));
+
+        harness.press(KeyCode::Char('/'));
+        harness.type_text("(need");
+        assert!(harness.screen().contains("/(need  unclosed group"));
+        harness.press(KeyCode::Esc);
+        assert_eq!(harness.app.get_scroll(), bottom);
+
+        //no match below the bottom, the search wraps around to the first
+        harness.press(KeyCode::Char('/'));
+        harness.type_text("needle");
+        let screen = harness.screen();
+        assert!(screen.contains("/needle  3 matches"));
+        assert!(screen.contains("the first Needle is here"));
+        harness.press(KeyCode::Enter);
+        assert_eq!(harness.app.search_matches()[0].message, 5);
+
+        harness.press(KeyCode::Char('n'));
+        let screen = harness.screen();
+        assert!(screen.contains("another needle, and needles"));
+        assert!(!screen.contains("the first Needle"));
+        assert!(screen.contains("match 2 of 3"));
+        harness.press(KeyCode::Char('n'));
+        harness.press(KeyCode::Char('n'));
+        assert!(harness.screen().contains("match 1 of 3"));
+        harness.press(KeyCode::Char('N'));
+        assert!(harness.screen().contains("match 3 of 3"));
+
+        //capitals make the search case sensitive
+        harness.press(KeyCode::Char('/'));
+        harness.type_text("Needle|number 3[0-9]");
+        harness.press(KeyCode::Enter);
+        assert_eq!(harness.app.search_matches().len(), 11);
+        harness.press(KeyCode::Esc);
+        assert!(harness.app.search_matches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_message_cursor_copies_pins_edits_regenerates_and_deletes() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.ask("first question");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+        harness.ask("second question");
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+
+        harness.press(KeyCode::Char('K'));
+        let screen = harness.screen();
+        assert!(screen.contains("▶ text-davinci-003:"));
+        assert!(screen.contains("message 4 of 4"));
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('y'));
+        assert_eq!(harness.app.take_clipboard().as_deref(), Some("second question"));
+        harness.press(KeyCode::Char('p'));
+        assert!(harness.screen().contains("▶ tester: [pinned]"));
+
+        //an edited question is asked again
+        harness.press(KeyCode::Char('e'));
+        assert!(harness.screen().contains("editing message 3"));
+        harness.type_text(", edited");
+        harness.press(KeyCode::Enter);
+        harness.settle().await;
+        assert_eq!(harness.app.get_call().unwrap().query, "second question, edited");
+        assert!(harness.app.last_message().unwrap().get_body().contains("tester: second question, edited"));
+        assert!(harness.app.selected_message().is_none());
+
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('r'));
+        assert!(harness.screen().contains("waiting for text-davinci-003"));
+        harness.settle().await;
+        assert_eq!(harness.app.get_call().unwrap().query, "second question, edited");
+
+        //the first answer goes once confirmed
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('d'));
+        assert!(harness.screen().contains("delete text-davinci-003's message?"));
+        harness.press(KeyCode::Char('y'));
+        assert_eq!(harness.app.selected_message(), Some(1));
+        harness.press(KeyCode::Char('J'));
+        harness.press(KeyCode::Char('J'));
+        //the cursor stops at the last message
+        assert_eq!(harness.app.selected_message(), Some(2));
+
+        harness.press(KeyCode::Char('>'));
+        let quoted = harness.app.get_display_input();
+        assert!(quoted.starts_with("> echo: tester: first question\n"));
+        assert!(quoted.trim_end().lines().all(|line| line.starts_with('>')));
+        assert!(quoted.ends_with("\n\n"));
+    }
+
+    #[tokio::test]
+    async fn regenerating_reads_the_files_again_and_keeps_the_transcript_when_not_sent() {
+        let path = std::env::temp_dir().join(format!("gpterm-harness-regenerate-{}.txt", std::process::id()));
+        std::fs::write(&path, "version one").unwrap();
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.ask(&format!("what does @{} say?", path.display()));
+        harness.settle().await;
+        harness.press(KeyCode::Esc);
+        harness.press(KeyCode::Char('i'));
+        harness.type_text("a draft");
+        harness.press(KeyCode::Esc);
+
+        std::fs::write(&path, "version two").unwrap();
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('r'));
+        harness.settle().await;
+        let query = harness.app.get_call().unwrap().query.clone();
+        assert!(query.contains("version two") && !query.contains("version one"));
+        assert_eq!(harness.app.get_display_input(), "a draft");
+
+        //the question and its answer stay when the file is gone
+        std::fs::remove_file(&path).unwrap();
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('r'));
+        assert!(harness.app.get_status().contains("not sent: can't attach"));
+        assert!(!harness.app.is_waiting());
+        assert!(harness.app.last_message().unwrap().get_body().contains("version two"));
+        assert_eq!(harness.app.get_display_input(), "a draft");
+
+        harness.press(KeyCode::Char('K'));
+        harness.press(KeyCode::Char('e'));
+        assert_eq!(harness.app.get_display_input(), format!("what does @{} say?", path.display()));
+        harness.press(KeyCode::Enter);
+        assert!(harness.app.get_status().contains("not sent: can't attach"));
+        assert!(harness.app.last_message().unwrap().get_body().contains("version two"));
+    }
+
+    #[tokio::test]
+    async fn q_quits_from_normal_mode_only() {
+        let mut harness = Harness::start(Settings::default(), false);
+        harness.press(KeyCode::Char('i'));
+        assert!(!harness.press(KeyCode::Char('q')));
+        harness.press(KeyCode::Esc);
+        assert!(harness.press(KeyCode::Char('q')));
+    }
+}
@@ -0,0 +1,330 @@
+//Full-text search over the saved sessions, through an index kept next to them
+
+use log::{info, warn};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::{self, Session};
+
+
+///Where the index is kept in the sessions directory, dot files aren't sessions
+pub const INDEX_FILE: &str = ".index.json";
+
+///Most hits a search returns
+pub const MAX_HITS: usize = 50;
+
+///Characters of a message shown around what matched
+const SNIPPET_CHARS: usize = 80;
+
+///Characters of a snippet before the first match
+const SNIPPET_LEAD: usize = 20;
+
+///A message of a saved session matching a search
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub session: String,
+    ///Index of the message in the session
+    pub message: usize,
+    pub snippet: String,
+    ///When the session was saved, in seconds since the epoch
+    pub saved: u64,
+}
+
+///Words of `text` as they are indexed and looked up, lowercase
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    return text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+///Modification time of `path` in nanoseconds, sessions are saved after every answer
+fn modified(path: &Path) -> u64 {
+    return fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+///Which messages of which sessions each word is in
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SearchIndex {
+    ///Modification time of each session file when it was indexed
+    sessions: BTreeMap<String, u64>,
+    words: BTreeMap<String, BTreeMap<String, BTreeSet<usize>>>,
+}
+
+impl SearchIndex {
+    ///The index of the sessions in `dir`, brought up to date and saved when it changed
+    pub fn open(dir: &Path) -> SearchIndex {
+        let path = dir.join(INDEX_FILE);
+        let mut index = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<SearchIndex>(&contents).unwrap_or_else(|err| {
+                warn!("Rebuilding the session index at {}: {}", path.display(), err);
+                SearchIndex::default()
+            }),
+            Err(_) => SearchIndex::default(),
+        };
+        if index.update(dir) {
+            if let Err(err) = index.save(&path) {
+                warn!("Couldn't save the session index: {}", err);
+            }
+        }
+        return index
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        info!("Saved the session index to {}", path.display());
+        return Ok(())
+    }
+
+    ///Indexes the sessions saved since the last time and forgets the deleted ones,
+    ///true when something changed
+    fn update(&mut self, dir: &Path) -> bool {
+        let names = session::list(dir);
+        let gone: Vec<String> = self.sessions
+            .keys()
+            .filter(|name| !names.contains(name))
+            .cloned()
+            .collect();
+        let mut changed = !gone.is_empty();
+        for name in gone {
+            self.remove(&name);
+        }
+
+        for name in names {
+            let modified = modified(&dir.join(format!("{}.json", name)));
+            if self.sessions.get(&name) == Some(&modified) {
+                continue;
+            }
+            match Session::load(dir, &name) {
+                Ok(session) => {
+                    self.remove(&name);
+                    self.add(&name, &session);
+                    self.sessions.insert(name, modified);
+                    changed = true;
+                }
+                Err(err) => warn!("Not indexing session {}: {}", name, err),
+            }
+        }
+        return changed
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.sessions.remove(name);
+        self.words.retain(|_, sessions| {
+            sessions.remove(name);
+            !sessions.is_empty()
+        });
+    }
+
+    fn add(&mut self, name: &str, session: &Session) {
+        for (index, message) in session.messages.iter().enumerate() {
+            for word in words(message.get_body()) {
+                self.words
+                    .entry(word)
+                    .or_default()
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(index);
+            }
+        }
+    }
+
+    ///Messages having every word of `query`, a word also matches longer ones it starts
+    fn lookup(&self, query: &str) -> BTreeSet<(String, usize)> {
+        let mut found: Option<BTreeSet<(String, usize)>> = None;
+        for term in words(query) {
+            let with_term: BTreeSet<(String, usize)> = self.words
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(&term))
+                .flat_map(|(_, sessions)| sessions.iter())
+                .flat_map(|(name, messages)| messages.iter().map(move |&message| (name.clone(), message)))
+                .collect();
+            found = match found {
+                None => Some(with_term),
+                Some(found) => Some(found.intersection(&with_term).cloned().collect()),
+            };
+        }
+        return found.unwrap_or_default()
+    }
+
+    ///Messages matching `query`, the sessions saved last first
+    pub fn search(&self, dir: &Path, query: &str) -> Vec<Hit> {
+        let saved = |name: &str| self.sessions.get(name).copied().unwrap_or(0);
+        let mut found: Vec<(String, usize)> = self.lookup(query).into_iter().collect();
+        found.sort_by(|a, b| saved(&b.0).cmp(&saved(&a.0)).then(a.cmp(b)));
+        found.truncate(MAX_HITS);
+
+        let terms: Vec<String> = words(query).collect();
+        let mut loaded: BTreeMap<String, Session> = BTreeMap::new();
+        let mut hits = Vec::new();
+        for (name, message) in found {
+            if !loaded.contains_key(&name) {
+                match Session::load(dir, &name) {
+                    Ok(session) => {
+                        loaded.insert(name.clone(), session);
+                    }
+                    Err(err) => {
+                        warn!("Couldn't read session {} for a snippet: {}", name, err);
+                        continue;
+                    }
+                }
+            }
+            let body = match loaded[&name].messages.get(message) {
+                Some(body) => body.get_body(),
+                None => continue,
+            };
+            hits.push(Hit {
+                snippet: snippet(body, &terms),
+                saved: saved(&name) / 1_000_000_000,
+                session: name,
+                message,
+            });
+        }
+        return hits
+    }
+}
+
+///The line of `body` a term first shows in, cut around it
+fn snippet(body: &str, terms: &[String]) -> String {
+    let line = body
+        .lines()
+        .find(|line| {
+            let lower = line.to_lowercase();
+            terms.iter().any(|term| lower.contains(term.as_str()))
+        })
+        .unwrap_or_else(|| body.lines().next().unwrap_or(""))
+        .trim();
+
+    let lower = line.to_lowercase();
+    let at = terms
+        .iter()
+        .filter_map(|term| lower.find(term.as_str()))
+        .min()
+        .map(|byte| lower[..byte].chars().count())
+        .unwrap_or(0);
+    let count = line.chars().count();
+    //lines short enough are shown whole
+    let start = at.saturating_sub(SNIPPET_LEAD).min(count.saturating_sub(SNIPPET_CHARS));
+
+    let mut snippet: String = line.chars().skip(start).take(SNIPPET_CHARS).collect();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if start + SNIPPET_CHARS < count {
+        snippet.push('…');
+    }
+    return snippet
+}
+
+///"today", "3 days ago", "5 weeks ago"... since `saved`
+pub fn age(saved: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let days = now.saturating_sub(saved) / (24 * 3600);
+    match days {
+        0 => return "today".to_string(),
+        1 => return "yesterday".to_string(),
+        2..=13 => return format!("{} days ago", days),
+        14..=59 => return format!("{} weeks ago", days / 7),
+        _ => return format!("{} months ago", days / 30),
+    }
+}
+
+
+///Popup listing what a search over the sessions found
+pub struct Finder {
+    pub query: String,
+    pub hits: Vec<Hit>,
+    ///Index in `hits`
+    selected: usize,
+}
+
+impl Finder {
+    pub fn new(query: String, hits: Vec<Hit>) -> Finder {
+        return Finder { query, hits, selected: 0 }
+    }
+
+    pub fn selected(&self) -> usize {
+        return self.selected
+    }
+
+    pub fn choice(&self) -> Option<&Hit> {
+        return self.hits.get(self.selected)
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.hits.len() {
+            self.selected += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::{Message, MessageType};
+
+    fn save(dir: &Path, name: &str, bodies: &[&str]) {
+        let messages = bodies
+            .iter()
+            .map(|body| Message::from("tester".to_string(), body.to_string(), MessageType::Query))
+            .collect();
+        Session { messages, ..Session::default() }.save(dir, name).unwrap();
+    }
+
+    #[test]
+    fn sessions_are_indexed_again_once_changed() {
+        let dir = std::env::temp_dir().join(format!("gpterm-index-{}", std::process::id()));
+        save(&dir, "rust", &["how do lifetimes work?", "Lifetimes make sure references stay valid"]);
+        save(&dir, "cooking", &["a lifetime of pasta"]);
+
+        let index = SearchIndex::open(&dir);
+        let hits = index.search(&dir, "lifetime");
+        assert_eq!(hits.len(), 3);
+        let both = index.search(&dir, "LIFETIMES references");
+        assert_eq!((both[0].session.as_str(), both[0].message), ("rust", 1));
+        assert_eq!(both[0].snippet, "Lifetimes make sure references stay valid");
+        assert!(index.search(&dir, "lifetimes pasta").is_empty());
+        assert!(dir.join(INDEX_FILE).exists());
+        assert_eq!(session::list(&dir), vec!["cooking", "rust"]);
+
+        save(&dir, "cooking", &["risotto"]);
+        fs::remove_file(dir.join("rust.json")).unwrap();
+        let index = SearchIndex::open(&dir);
+        assert!(index.search(&dir, "lifetime").is_empty());
+        assert_eq!(index.search(&dir, "risotto")[0].session, "cooking");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snippets_are_cut_around_the_match() {
+        let body = format!("intro\n{} the needle {}", "a".repeat(100), "b".repeat(100));
+        let snippet = snippet(&body, &["needle".to_string()]);
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains("the needle"));
+        assert_eq!(snippet.chars().count(), SNIPPET_CHARS + 2);
+    }
+}
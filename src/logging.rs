@@ -1,4 +1,4 @@
-use log::{debug, error, info, trace, warn, LevelFilter, SetLoggerError};
+use log::LevelFilter;
 
 use log4rs::{
     append::file::FileAppender,
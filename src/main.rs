@@ -1,8 +1,11 @@
+//the codebase prefers explicit returns
+#![allow(clippy::needless_return)]
+
 //logging
-use log::{debug, error, info, trace, warn, LevelFilter, SetLoggerError};
+use log::warn;
 
 //std
-use std::{error::Error, io::{self, Write, Read}, fs::File};
+use std::{io::{self, Write}, fs, time::Duration};
 
 //tui
 use tui::{
@@ -12,7 +15,7 @@ use tui::{
 
 
 use crossterm:: {
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
     terminal::{disable_raw_mode,
         enable_raw_mode,
@@ -22,19 +25,46 @@ use crossterm:: {
 };
 
 
-use app::{App, InputMode, MessageType};
+use app::{App, InputMode};
+use base64::Engine;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 
 mod logging;
 mod api;
 mod app;
+mod attach;
+mod blocks;
+mod cassette;
+mod cli;
+mod compare;
+mod config;
+mod context;
+#[cfg(test)]
+mod harness;
+mod index;
+#[cfg(test)]
+mod mock_server;
+mod models;
+mod params;
+mod pricing;
+mod provider;
 mod render;
+mod search;
+mod session;
+mod shell;
+mod template;
+mod tokenizer;
+mod tools;
 
 
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
-    
+    let cli = Cli::parse();
+
     //setup logging
 
     let log_file = "./log/logfile";
@@ -44,35 +74,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     let _handle = logging::set_logging(log_file, request_file);
 
 
-    //setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
     //reading api token
     let user = std::env::var("USER").expect("Can access user environment variable");
 
-    let mut config_file = File::open(
+    //local servers don't need one
+    let token = match fs::read_to_string(
         format!("/home/{}/.config/.gpterm/gpterm.conf",
-                    user.as_str()))
-        .unwrap();
-
-    let mut token = String::new();
-
-    config_file.read_to_string(&mut token).unwrap();
-    token = token.trim().to_string();
+                    user.as_str())) {
+        Ok(token) => token.trim().to_string(),
+        Err(err) => {
+            warn!("Couldn't read api token ({}), sending requests without one", err);
+            String::new()
+        }
+    };
+
+    let mut config = Config::load(
+        format!("/home/{}/.config/.gpterm/gpterm.toml",
+                    user.as_str()).as_str())?;
+    cli.apply(&mut config);
+    if config.sessions_dir.is_none() {
+        config.sessions_dir = Some(format!("/home/{}/.config/.gpterm/sessions", user).into());
+    }
+    if config.templates_dir.is_none() {
+        config.templates_dir = Some(format!("/home/{}/.config/.gpterm/templates", user).into());
+    }
+    if config.models.cache_file.is_none() {
+        config.models.cache_file = Some(format!("/home/{}/.config/.gpterm/models.json", user).into());
+    }
 
     //create app and run it -> Singleton
+    //before touching the terminal so config errors print normally
     let mut app = App::default();
-    app.set_handler(token);
+    app.set_handler(token, &config)?;
     app.set_username(user);
 
+    if let Some(Command::Ask(ask)) = &cli.command {
+        let templates_dir = config.templates_dir.clone().unwrap_or_default();
+        return run_ask(app, ask.query(&templates_dir)?).await
+    }
+    if let Some(Command::Search(search)) = &cli.command {
+        let sessions_dir = config.sessions_dir.clone().unwrap_or_default();
+        return run_search(&sessions_dir, &search.words.join(" "))
+    }
+
+    //setup terminal
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
     let res = run_app(&mut terminal, app);
 
-    futures::join!(res);
+    let (res,) = futures::join!(res);
 
     //restore terminal
     disable_raw_mode()?;
@@ -83,12 +138,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
     )?;
     terminal.show_cursor()?;
 
-    // if let Err(err.await) = res {
-    //     println!("{:?}", err)
-    // }
+    if let Err(err) = res {
+        println!("{:?}", err)
+    }
     Ok(())
 }
 
+///Sends `query` like the input box would and prints the answer
+async fn run_ask(mut app: App, query: String) -> Result<(), Box<dyn std::error::Error>> {
+    app.set_display_input(query);
+    app.send_input();
+    if !app.is_waiting() {
+        return Err(app.get_status().clone().into())
+    }
+    while app.is_waiting() {
+        app.poll_answer();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    match app.last_message() {
+        Some(answer) if !answer.is_system() => {
+            println!("{}", answer.get_body().trim());
+            return Ok(())
+        }
+        Some(answer) => return Err(answer.get_body().clone().into()),
+        None => return Err("no answer".into()),
+    }
+}
+
+///Prints the messages of the saved sessions matching `query`
+fn run_search(sessions_dir: &std::path::Path, query: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let hits = index::SearchIndex::open(sessions_dir).search(sessions_dir, query);
+    if hits.is_empty() {
+        return Err(format!("no saved session matches {}", query).into())
+    }
+    for hit in &hits {
+        println!("{} #{} ({}): {}", hit.session, hit.message + 1, index::age(hit.saved), hit.snippet);
+    }
+    if hits.len() == index::MAX_HITS {
+        println!("only the first {} shown, add words to narrow it down", index::MAX_HITS);
+    }
+    return Ok(())
+}
+
 async fn run_app<B: Backend>(terminal : &mut Terminal<B>, mut app: App) -> io::Result<()> {
 
     // let mut file = File::create("request.txt")?;
@@ -96,94 +188,225 @@ async fn run_app<B: Backend>(terminal : &mut Terminal<B>, mut app: App) -> io::R
 
         terminal.draw(|f| render::ui(f, &app))?;
 
+        if app.poll_answer() {
+            app.scroll_to_bottom();
+        }
+
+        //keep redrawing while waiting so request progress shows up
+        if !event::poll(Duration::from_millis(100))? {
+            continue;
+        }
+
         match event::read()? {
             Event::Resize(_, _) => {
                 app.update_size()
             }
             Event::Key(key) => {
+                let quit = handle_key(&mut app, key);
+                if quit {
+                    return Ok(());
+                }
+                if let Some(text) = app.take_clipboard() {
+                    copy_to_clipboard(&text)?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
 
-                match app.input_mode() {
-                    InputMode::Normal => match key.code {
-                        KeyCode::Char(':') => {
-                            app.set_input_mode(InputMode::Command);
-                        }
-                        KeyCode::Char('i') => {
-                            app.set_input_mode(InputMode::Insert);
-                        }
-                        KeyCode::Char('k') => {
-                            app.scroll_up();
-                        }
-                        KeyCode::Char('j') => {
-                            app.scroll_down();
-                        }
-                        KeyCode::Char('q') => {
-                            return Ok(());
-                        }
-                        _ => {}
-                    },
-                    InputMode::Insert => {
-                        match key.code {
-                            KeyCode::Enter => {
-                                app.push_content(
-                                    app.get_username(),
-                                    MessageType::Query,
-                                    app.get_display_input().drain(..).collect()
-                                );
-
-                                terminal.draw(|f| render::ui(f, &app))?;
-                                app.update_input();
-                                terminal.draw(|f| render::ui(f, &app))?;
-
-                                app.answer().await;
-
-                                // let thing = app.get_call();
-
-                                // file.write_all(format!("{:#?}",thing).as_bytes())?;
-
-                                app.scroll_to_bottom();
-                            }
-                            KeyCode::Char(c) => {
-                                app.push_input(c);
-                            }
-                            KeyCode::Backspace => {
-                                app.pop_input();
-                            }
-                            KeyCode::Esc => {
-                                app.set_input_mode(InputMode::Normal);
-                            }
-                            _ => {}
-                        }
 
+///Asks the terminal to put `text` in the clipboard (OSC 52), which works over ssh too
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+    return stdout.flush()
+}
+
+///Reacts to a key press, true when the app should quit
+fn handle_key(app: &mut App, key: KeyEvent) -> bool {
+    //a popup waiting for an answer takes every key
+    if app.get_confirm().is_some() {
+        match key.code {
+            KeyCode::Char('y') => app.accept_confirm(),
+            KeyCode::Char('n') | KeyCode::Esc => app.cancel_confirm(),
+            _ => {}
+        }
+        return false;
+    }
+    //and the parameters panel, enter edits the selected one
+    if let Some(panel) = app.get_params_panel() {
+        let editing = panel.editing.is_some();
+        match (key.code, editing) {
+            (KeyCode::Enter, false) => app.edit_param(),
+            (KeyCode::Enter, true) => app.apply_param(),
+            (KeyCode::Esc, false) => app.close_params(),
+            (code, _) => if let Some(panel) = app.params_panel_mut() {
+                match (code, panel.editing.as_mut()) {
+                    (KeyCode::Esc, Some(_)) => panel.editing = None,
+                    (KeyCode::Char(c), Some(value)) => value.push(c),
+                    (KeyCode::Backspace, Some(value)) => {
+                        value.pop();
                     }
-                    InputMode::Command => {
-                        match key.code {
-
-                            KeyCode::Enter => {
-                                app.send_command();
-                            }
-                            KeyCode::Char(c) => {
-                                app.push_command(c);
-                            }
-                            KeyCode::Backspace => {
-                                app.pop_command();
-                            }
-                            KeyCode::Esc => {
-                                app.reset_command();
-                                app.set_input_mode(InputMode::Normal);
-                            }
-                            _ => {}
-                        }
-                    }
+                    (KeyCode::Up, None) => panel.up(),
+                    (KeyCode::Down, None) => panel.down(),
+                    _ => {}
                 }
+            },
+        }
+        return false;
+    }
+    //so does the model picker, what is typed narrows it down
+    if app.get_picker().is_some() {
+        match key.code {
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => app.refresh_models(),
+            KeyCode::Enter => app.choose_model(),
+            KeyCode::Esc => app.close_picker(),
+            code => if let Some(picker) = app.picker_mut() {
+                match code {
+                    KeyCode::Up => picker.up(),
+                    KeyCode::Down => picker.down(),
+                    KeyCode::Backspace => picker.pop(),
+                    KeyCode::Char(c) => picker.push(c),
+                    _ => {}
+                }
+            },
+        }
+        return false;
+    }
 
-            }
-            _ => {}
+    //and the sessions found, enter opens one
+    if app.get_finder().is_some() {
+        match key.code {
+            KeyCode::Enter => app.open_hit(),
+            KeyCode::Esc => app.close_finder(),
+            code => if let Some(finder) = app.finder_mut() {
+                match code {
+                    KeyCode::Up => finder.up(),
+                    KeyCode::Down => finder.down(),
+                    _ => {}
+                }
+            },
         }
+        return false;
     }
-}
 
+    match app.input_mode() {
+        InputMode::Normal => match key.code {
+            KeyCode::Char(':') => {
+                app.set_input_mode(InputMode::Command);
+            }
+            KeyCode::Char('i') => {
+                app.set_input_mode(InputMode::Insert);
+            }
+            KeyCode::Char('k') => {
+                app.scroll_up();
+            }
+            KeyCode::Char('j') => {
+                app.scroll_down();
+            }
+            KeyCode::Char('q') => {
+                return true;
+            }
+            KeyCode::Esc => {
+                app.close_comparison();
+                app.clear_search();
+                app.clear_cursor();
+            }
+            KeyCode::Char('J') => {
+                app.move_cursor(true);
+            }
+            KeyCode::Char('K') => {
+                app.move_cursor(false);
+            }
+            KeyCode::Char(c @ ('y' | 'd' | 'e' | 'r' | 'p' | '>')) => {
+                app.message_action(c);
+            }
+            KeyCode::Char('/') => {
+                app.start_search();
+            }
+            KeyCode::Char('n') => {
+                app.next_match(true);
+            }
+            KeyCode::Char('N') => {
+                app.next_match(false);
+            }
+            KeyCode::Tab => {
+                app.next_tab();
+            }
+            KeyCode::BackTab => {
+                app.previous_tab();
+            }
+            _ => {}
+        },
+        InputMode::Insert => {
+            match key.code {
+                //one request at a time
+                KeyCode::Enter if !app.is_waiting() => {
+                    app.send_input();
 
+                    // let thing = app.get_call();
 
+                    // file.write_all(format!("{:#?}",thing).as_bytes())?;
 
+                    app.scroll_to_bottom();
+                }
+                KeyCode::Tab => {
+                    app.complete_input();
+                }
+                KeyCode::Char(c) => {
+                    app.push_input(c);
+                }
+                KeyCode::Backspace => {
+                    app.pop_input();
+                }
+                KeyCode::Esc => {
+                    app.set_input_mode(InputMode::Normal);
+                }
+                _ => {}
+            }
 
+        }
+        InputMode::Search => {
+            match key.code {
+                KeyCode::Enter => {
+                    app.finish_search();
+                }
+                KeyCode::Char(c) => {
+                    app.push_search(c);
+                }
+                KeyCode::Backspace => {
+                    app.pop_search();
+                }
+                KeyCode::Esc => {
+                    app.cancel_search();
+                }
+                _ => {}
+            }
+        }
+        InputMode::Command => {
+            match key.code {
 
+                KeyCode::Enter => {
+                    app.send_command();
+                }
+                KeyCode::Tab => {
+                    app.complete_command();
+                }
+                KeyCode::Char(c) => {
+                    app.push_command(c);
+                }
+                KeyCode::Backspace => {
+                    app.pop_command();
+                }
+                KeyCode::Esc => {
+                    app.reset_command();
+                    app.set_input_mode(InputMode::Normal);
+                }
+                _ => {}
+            }
+        }
+    }
+    return false;
+}
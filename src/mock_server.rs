@@ -0,0 +1,359 @@
+//OpenAI compatible server answering with deterministic text,
+//shared by the `gpterm-mock-server` binary and the tests
+
+use std::{
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU32, Ordering}, Arc},
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+
+///How the server misbehaves
+#[derive(clap::Args, Debug, Clone)]
+pub struct Settings {
+    ///Wait before answering each request
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub latency_ms: u64,
+    ///Wait between streamed chunks
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub chunk_delay_ms: u64,
+    ///Fail the first N requests
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub fail_first: u32,
+    ///Fail every Nth request, 0 never does
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub fail_every: u32,
+    ///Status failed requests answer with
+    #[arg(long, value_name = "STATUS", default_value_t = 503)]
+    pub fail_status: u16,
+    ///Retry-After sent with failures, in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub retry_after: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            latency_ms: 0,
+            chunk_delay_ms: 0,
+            fail_first: 0,
+            fail_every: 0,
+            fail_status: 503,
+            retry_after: None,
+        }
+    }
+}
+
+impl Settings {
+    ///Whether the `number`th request (counting from 1) fails
+    fn fails(&self, number: u32) -> bool {
+        return number <= self.fail_first
+            || (self.fail_every > 0 && number.is_multiple_of(self.fail_every))
+    }
+}
+
+struct State {
+    settings: Settings,
+    ///Requests received so far
+    requests: AtomicU32,
+}
+
+
+#[derive(Deserialize)]
+struct CompletionCall {
+    model: String,
+    #[serde(default)]
+    prompt: String,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ChatCall {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+
+///Models `/models` lists
+pub const MODELS: [&str; 2] = ["mock-completion", "mock-chat"];
+
+///The answer to `prompt`, split in the pieces it is streamed in
+pub fn answer_to(prompt: &str) -> Vec<String> {
+    return format!("echo: {}", prompt)
+        .split_inclusive(' ')
+        .map(String::from)
+        .collect()
+}
+
+fn usage(prompt: &str, answer: &[String]) -> Value {
+    let prompt_tokens = prompt.split_whitespace().count();
+    return json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": answer.len(),
+        "total_tokens": prompt_tokens + answer.len(),
+    })
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert("Content-Type", "application/json".parse().unwrap());
+    return response
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    return json_response(status, json!({
+        "error": {"message": message, "type": "mock_error"}
+    }))
+}
+
+///Sends `events` as server sent events, each one after the chunk delay
+fn event_stream(settings: &Settings, events: Vec<Value>) -> Response<Body> {
+    let delay = Duration::from_millis(settings.chunk_delay_ms);
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let lines = events
+            .iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .chain(std::iter::once("data: [DONE]\n\n".to_string()));
+        for line in lines {
+            tokio::time::sleep(delay).await;
+            //the client went away
+            if sender.send_data(line.into()).await.is_err() {
+                return
+            }
+        }
+    });
+
+    let mut response = Response::new(body);
+    response.headers_mut().insert("Content-Type", "text/event-stream".parse().unwrap());
+    return response
+}
+
+fn completions(settings: &Settings, number: u32, call: CompletionCall) -> Response<Body> {
+    let answer = answer_to(&call.prompt);
+    let id = format!("cmpl-mock-{}", number);
+
+    if call.stream {
+        let events = answer.iter().map(|piece| json!({
+            "id": id,
+            "object": "text_completion",
+            "created": 0,
+            "model": call.model,
+            "choices": [{"text": piece, "index": 0, "logprobs": null, "finish_reason": null}],
+        })).collect();
+        return event_stream(settings, events)
+    }
+
+    return json_response(StatusCode::OK, json!({
+        "id": id,
+        "object": "text_completion",
+        "created": 0,
+        "model": call.model,
+        "choices": [{"text": answer.concat(), "index": 0, "logprobs": null, "finish_reason": "stop"}],
+        "usage": usage(&call.prompt, &answer),
+    }))
+}
+
+fn chat_completions(settings: &Settings, number: u32, call: ChatCall) -> Response<Body> {
+    //answers the last thing the user said
+    let prompt = match call.messages.iter().rev().find(|message| message.role == "user") {
+        Some(message) => message.content.as_str(),
+        None => "",
+    };
+    let answer = answer_to(prompt);
+    let id = format!("chatcmpl-mock-{}", number);
+
+    if call.stream {
+        let role = json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": call.model,
+            "choices": [{"index": 0, "delta": {"role": "assistant"}, "finish_reason": null}],
+        });
+        let events = std::iter::once(role).chain(answer.iter().map(|piece| json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": call.model,
+            "choices": [{"index": 0, "delta": {"content": piece}, "finish_reason": null}],
+        }))).collect();
+        return event_stream(settings, events)
+    }
+
+    return json_response(StatusCode::OK, json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": 0,
+        "model": call.model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": answer.concat()},
+            "finish_reason": "stop",
+        }],
+        "usage": usage(prompt, &answer),
+    }))
+}
+
+fn models() -> Response<Body> {
+    let data: Vec<Value> = MODELS
+        .iter()
+        .map(|id| json!({"id": id, "object": "model", "created": 0, "owned_by": "gpterm", "max_model_len": 2048}))
+        .collect();
+    return json_response(StatusCode::OK, json!({"object": "list", "data": data}))
+}
+
+async fn handle(state: Arc<State>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let settings = &state.settings;
+    let number = state.requests.fetch_add(1, Ordering::SeqCst) + 1;
+    tokio::time::sleep(Duration::from_millis(settings.latency_ms)).await;
+
+    if settings.fails(number) {
+        let status = StatusCode::from_u16(settings.fail_status)
+            .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        let mut response = error_response(status, &format!("injected failure of request {}", number));
+        if let Some(seconds) = settings.retry_after {
+            response.headers_mut().insert("Retry-After", seconds.into());
+        }
+        return Ok(response)
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    //any prefix works, `/v1/completions` as well as azure deployment paths
+    let response = match method {
+        Method::GET if path.ends_with("/models") => models(),
+        Method::POST if path.ends_with("/chat/completions") => {
+            match serde_json::from_slice::<ChatCall>(&body) {
+                Ok(call) => chat_completions(settings, number, call),
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        }
+        Method::POST if path.ends_with("/completions") => {
+            match serde_json::from_slice::<CompletionCall>(&body) {
+                Ok(call) => completions(settings, number, call),
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, &format!("no route for {} {}", method, path)),
+    };
+    return Ok(response)
+}
+
+
+///Binds `addr`, the server runs when the returned future is polled
+pub fn serve(
+    settings: Settings,
+    addr: SocketAddr,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), Box<dyn Error>> {
+    let state = Arc::new(State { settings, requests: AtomicU32::new(0) });
+
+    let make_service = make_service_fn(move |_connection| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request)))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    return Ok((server.local_addr(), server))
+}
+
+///Runs a server on a free local port in the background, returns its `/v1` url
+#[cfg(test)]
+pub fn spawn(settings: Settings) -> String {
+    let (addr, server) = serve(settings, ([127, 0, 0, 1], 0).into()).unwrap();
+    tokio::spawn(server);
+    return format!("http://{}/v1", addr)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn post(url: String, body: Value) -> reqwest::Response {
+        return reqwest::Client::new().post(url).json(&body).send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn chat_completions_answer_the_last_user_message() {
+        let base_url = spawn(Settings::default());
+        let response = post(format!("{}/chat/completions", base_url), json!({
+            "model": "mock-chat",
+            "messages": [
+                {"role": "system", "content": "be nice"},
+                {"role": "user", "content": "hello there"}
+            ]
+        })).await;
+
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "echo: hello there");
+        assert_eq!(body["usage"]["total_tokens"], 5);
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stream_deltas() {
+        let base_url = spawn(Settings::default());
+        let response = post(format!("{}/chat/completions", base_url), json!({
+            "model": "mock-chat",
+            "stream": true,
+            "messages": [{"role": "user", "content": "hi"}]
+        })).await;
+
+        let body = response.text().await.unwrap();
+        let events: Vec<&str> = body.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(events.len(), 4);
+        assert!(events[1].contains(r#""content":"echo: ""#));
+        assert_eq!(events[3], "data: [DONE]");
+    }
+
+    #[tokio::test]
+    async fn failures_are_injected() {
+        let base_url = spawn(Settings { fail_every: 2, fail_status: 429, retry_after: Some(3), ..Settings::default() });
+        let call = json!({"model": "mock-completion", "prompt": "hi"});
+
+        let first = post(format!("{}/completions", base_url), call.clone()).await;
+        let second = post(format!("{}/completions", base_url), call).await;
+
+        assert_eq!(first.status(), 200);
+        assert_eq!(second.status(), 429);
+        assert_eq!(second.headers()["Retry-After"], "3");
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_bad_bodies_are_rejected() {
+        let base_url = spawn(Settings::default());
+        let missing = reqwest::get(format!("{}/engines", base_url)).await.unwrap();
+        let bad = post(format!("{}/completions", base_url), json!({"prompt": "no model"})).await;
+
+        assert_eq!(missing.status(), 404);
+        assert_eq!(bad.status(), 400);
+    }
+}
@@ -0,0 +1,211 @@
+//Models a provider serves, cached between runs, and the picker choosing one
+
+use log::{info, warn};
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::provider::ModelInfo;
+
+
+///Settings of the `[models]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ModelsConfig {
+    ///How long a fetched list is used before the provider is asked again
+    pub cache_hours: u64,
+    ///Where lists are kept between runs, next to the config file by default
+    pub cache_file: Option<PathBuf>,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> ModelsConfig {
+        ModelsConfig { cache_hours: 24, cache_file: None }
+    }
+}
+
+impl ModelsConfig {
+    pub fn max_age(&self) -> Duration {
+        return Duration::from_secs(self.cache_hours * 3600)
+    }
+}
+
+///A list of models and when it was fetched, in seconds since the epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedList {
+    fetched: u64,
+    models: Vec<ModelInfo>,
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+///Lists fetched so far, by the base url of the provider serving them
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ModelCache {
+    lists: BTreeMap<String, CachedList>,
+}
+
+impl ModelCache {
+    ///The cache at `path`, empty when there is none or it can't be read
+    pub fn load(path: &Path) -> ModelCache {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return ModelCache::default(),
+        };
+        match serde_json::from_str::<ModelCache>(&contents) {
+            Ok(cache) => return cache,
+            Err(err) => {
+                warn!("Ignoring the model cache at {}: {}", path.display(), err);
+                return ModelCache::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        info!("Saved the model cache to {}", path.display());
+        return Ok(())
+    }
+
+    ///Models of `key`, whatever their age
+    pub fn get(&self, key: &str) -> Option<&Vec<ModelInfo>> {
+        return self.lists.get(key).map(|list| &list.models)
+    }
+
+    ///Models of `key` fetched less than `max_age` ago
+    pub fn fresh(&self, key: &str, max_age: Duration) -> Option<&Vec<ModelInfo>> {
+        let list = self.lists.get(key)?;
+        if now().saturating_sub(list.fetched) > max_age.as_secs() {
+            return None
+        }
+        return Some(&list.models)
+    }
+
+    pub fn insert(&mut self, key: &str, models: Vec<ModelInfo>) {
+        self.lists.insert(key.to_string(), CachedList { fetched: now(), models });
+    }
+}
+
+///Ids of `models` starting with `partial`, sorted
+pub fn complete(models: &[ModelInfo], partial: &str) -> Vec<String> {
+    let mut ids: Vec<String> = models
+        .iter()
+        .filter(|model| model.id.starts_with(partial))
+        .map(|model| model.id.clone())
+        .collect();
+    ids.sort();
+    return ids
+}
+
+
+///Popup listing the models, narrowed down by what is typed
+pub struct ModelPicker {
+    models: Vec<ModelInfo>,
+    pub filter: String,
+    ///Index in `visible`
+    selected: usize,
+}
+
+impl ModelPicker {
+    ///Models sorted by id, the one in use selected
+    pub fn new(mut models: Vec<ModelInfo>, current: &str) -> ModelPicker {
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        let selected = models.iter().position(|model| model.id == current).unwrap_or(0);
+        return ModelPicker { models, filter: String::new(), selected }
+    }
+
+    ///Models whose id contains the filter, ignoring case
+    pub fn visible(&self) -> Vec<&ModelInfo> {
+        let filter = self.filter.to_lowercase();
+        return self.models
+            .iter()
+            .filter(|model| model.id.to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    pub fn selected(&self) -> usize {
+        return self.selected
+    }
+
+    pub fn choice(&self) -> Option<&ModelInfo> {
+        return self.visible().get(self.selected).copied()
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.visible().len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.filter.push(c);
+        self.selected = 0;
+    }
+
+    pub fn pop(&mut self) {
+        self.filter.pop();
+        self.selected = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str) -> ModelInfo {
+        return ModelInfo { id: id.to_string(), owned_by: None, context_length: None }
+    }
+
+    #[test]
+    fn lists_expire_after_max_age() {
+        let path = std::env::temp_dir().join(format!("gpterm-models-{}.json", std::process::id()));
+        let mut cache = ModelCache::default();
+        cache.insert("http://localhost:8080/v1", vec![model("llama")]);
+        cache.save(&path).unwrap();
+
+        let mut cache = ModelCache::load(&path);
+        assert_eq!(cache.fresh("http://localhost:8080/v1", Duration::from_secs(60)).unwrap()[0].id, "llama");
+        cache.lists.get_mut("http://localhost:8080/v1").unwrap().fetched -= 120;
+        assert!(cache.fresh("http://localhost:8080/v1", Duration::from_secs(60)).is_none());
+        assert!(cache.get("http://localhost:8080/v1").is_some());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn the_picker_filters_and_keeps_the_selection_in_range() {
+        let mut picker = ModelPicker::new(vec![model("gpt-4"), model("gpt-3.5-turbo"), model("llama")], "gpt-4");
+        assert_eq!(picker.choice().unwrap().id, "gpt-4");
+
+        picker.push('G');
+        picker.down();
+        picker.down();
+        assert_eq!(picker.visible().len(), 2);
+        assert_eq!(picker.choice().unwrap().id, "gpt-4");
+
+        picker.push('x');
+        assert!(picker.choice().is_none());
+        assert_eq!(complete(&picker.models, "gpt"), vec!["gpt-3.5-turbo", "gpt-4"]);
+    }
+}
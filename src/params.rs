@@ -0,0 +1,343 @@
+//Sampling parameters of requests, grouped in named profiles
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+
+///Sampling settings sent with a request, unset options are left to the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Params {
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    ///Upper bound of tokens generated per answer
+    pub max_tokens: u32,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params {
+            temperature: 0.0,
+            top_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: Vec::new(),
+            seed: None,
+            max_tokens: 1000,
+        }
+    }
+}
+
+///Names of the parameters, in the order the panel lists them
+pub const FIELDS: [&str; 7] = [
+    "temperature", "top_p", "presence_penalty", "frequency_penalty", "stop", "seed", "max_tokens",
+];
+
+///Most stop sequences the API takes
+const MAX_STOP: usize = 4;
+
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), String> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => {
+            return Err(format!("{} {} is out of {} to {}", name, value, min, max))
+        }
+        _ => return Ok(()),
+    }
+}
+
+impl Params {
+    ///Whether the API would take these, checked before anything is sent
+    pub fn validate(&self) -> Result<(), String> {
+        check_range("temperature", Some(self.temperature), 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        if self.stop.len() > MAX_STOP {
+            return Err(format!("{} stop sequences, at most {} are allowed", self.stop.len(), MAX_STOP))
+        }
+        if self.stop.iter().any(|stop| stop.is_empty()) {
+            return Err("stop sequences can't be empty".to_string())
+        }
+        if self.max_tokens == 0 {
+            return Err("max_tokens must be at least 1".to_string())
+        }
+        return Ok(())
+    }
+
+    ///The value of `field` as the panel shows it, "-" when unset
+    pub fn get(&self, field: &str) -> String {
+        let unset = "-".to_string();
+        match field {
+            "temperature" => return self.temperature.to_string(),
+            "top_p" => return self.top_p.map_or(unset, |value| value.to_string()),
+            "presence_penalty" => return self.presence_penalty.map_or(unset, |value| value.to_string()),
+            "frequency_penalty" => return self.frequency_penalty.map_or(unset, |value| value.to_string()),
+            "stop" if self.stop.is_empty() => return unset,
+            "stop" => {
+                return self.stop
+                    .iter()
+                    .map(|stop| stop.replace('\n', "\\n"))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }
+            "seed" => return self.seed.map_or(unset, |value| value.to_string()),
+            "max_tokens" => return self.max_tokens.to_string(),
+            _ => return unset,
+        }
+    }
+}
+
+
+///Parameters a profile sets, the others are kept from what comes before it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub max_tokens: Option<u32>,
+}
+
+fn parse<T: std::str::FromStr>(field: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(value) => return Ok(value),
+        Err(_) => return Err(format!("{} isn't a valid {}", value, field)),
+    }
+}
+
+impl Profile {
+    pub fn apply(&self, params: &mut Params) {
+        if let Some(temperature) = self.temperature {
+            params.temperature = temperature;
+        }
+        if self.top_p.is_some() {
+            params.top_p = self.top_p;
+        }
+        if self.presence_penalty.is_some() {
+            params.presence_penalty = self.presence_penalty;
+        }
+        if self.frequency_penalty.is_some() {
+            params.frequency_penalty = self.frequency_penalty;
+        }
+        if let Some(stop) = &self.stop {
+            params.stop = stop.clone();
+        }
+        if self.seed.is_some() {
+            params.seed = self.seed;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params.max_tokens = max_tokens;
+        }
+    }
+
+    ///Sets `field` from what was typed, an empty value unsets it.
+    ///Stop sequences are separated by commas, `\n` is a line break
+    pub fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let set = !value.is_empty();
+        match field {
+            "temperature" => self.temperature = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            "top_p" => self.top_p = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            "presence_penalty" => self.presence_penalty = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            "frequency_penalty" => self.frequency_penalty = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            "stop" => self.stop = match set {
+                true => Some(value.split(',').map(|stop| stop.replace("\\n", "\n")).collect()),
+                false => None,
+            },
+            "seed" => self.seed = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            "max_tokens" => self.max_tokens = match set {
+                true => Some(parse(field, value)?),
+                false => None,
+            },
+            _ => return Err(format!("no parameter {}, there are {}", field, FIELDS.join(", "))),
+        }
+        return Ok(())
+    }
+
+    ///Whether the profile sets `field`
+    pub fn sets(&self, field: &str) -> bool {
+        match field {
+            "temperature" => return self.temperature.is_some(),
+            "top_p" => return self.top_p.is_some(),
+            "presence_penalty" => return self.presence_penalty.is_some(),
+            "frequency_penalty" => return self.frequency_penalty.is_some(),
+            "stop" => return self.stop.is_some(),
+            "seed" => return self.seed.is_some(),
+            "max_tokens" => return self.max_tokens.is_some(),
+            _ => return false,
+        }
+    }
+}
+
+
+///Settings of the `[params]` table
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ParamsConfig {
+    ///Profiles by name, the one named "default" applies to every model
+    pub profiles: BTreeMap<String, Profile>,
+    ///Profile used for each model name prefix, the longest prefix wins
+    pub models: BTreeMap<String, String>,
+}
+
+impl ParamsConfig {
+    ///Profile `model` uses unless another one is chosen
+    pub fn profile_for(&self, model: &str) -> Option<&str> {
+        return self.models
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, profile)| profile.as_str())
+    }
+
+    ///Parameters for `model`: the default profile, then the model's or `chosen`
+    pub fn resolve(&self, model: &str, chosen: Option<&str>) -> Result<Params, String> {
+        let mut params = Params::default();
+        if let Some(default) = self.profiles.get("default") {
+            default.apply(&mut params);
+        }
+        if let Some(name) = chosen.or(self.profile_for(model)) {
+            match self.profiles.get(name) {
+                Some(profile) => profile.apply(&mut params),
+                None => return Err(format!("no profile {}", name)),
+            }
+        }
+        return Ok(params)
+    }
+
+    ///Every profile must give valid parameters and every model a known profile
+    pub fn check(&self) -> Result<(), String> {
+        for (name, profile) in &self.profiles {
+            let mut params = Params::default();
+            profile.apply(&mut params);
+            if let Err(err) = params.validate() {
+                return Err(format!("profile {}: {}", name, err))
+            }
+        }
+        for (model, name) in &self.models {
+            if !self.profiles.contains_key(name) {
+                return Err(format!("{} uses profile {}, which isn't in [params.profiles]", model, name))
+            }
+        }
+        return Ok(())
+    }
+}
+
+
+///Popup listing the parameters, one of them selected and maybe being edited
+#[derive(Debug, Default)]
+pub struct ParamsPanel {
+    ///Index in `FIELDS`
+    pub selected: usize,
+    ///What is typed for the selected parameter
+    pub editing: Option<String>,
+    ///Why the last edit wasn't taken
+    pub error: Option<String>,
+}
+
+impl ParamsPanel {
+    pub fn field(&self) -> &'static str {
+        return FIELDS[self.selected]
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        self.selected = (self.selected + 1).min(FIELDS.len() - 1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ParamsConfig {
+        let toml = r#"
+            [profiles.default]
+            max_tokens = 500
+            [profiles.precise]
+            temperature = 0.2
+            top_p = 0.9
+            [profiles.creative]
+            temperature = 1.2
+            stop = ["\n\n"]
+            [models]
+            "gpt-4" = "precise"
+        "#;
+        return toml::from_str::<ParamsConfig>(toml).unwrap()
+    }
+
+    #[test]
+    fn profiles_apply_over_the_default_one() {
+        let config = config();
+        config.check().unwrap();
+
+        let gpt4 = config.resolve("gpt-4-0613", None).unwrap();
+        assert_eq!((gpt4.temperature, gpt4.top_p, gpt4.max_tokens), (0.2, Some(0.9), 500));
+        let creative = config.resolve("gpt-4", Some("creative")).unwrap();
+        assert_eq!((creative.temperature, creative.top_p), (1.2, None));
+        assert_eq!(config.resolve("llama", None).unwrap().temperature, 0.0);
+        assert!(config.resolve("llama", Some("nope")).is_err());
+    }
+
+    #[test]
+    fn values_out_of_range_are_refused() {
+        let mut profile = Profile::default();
+        profile.set("temperature", "2.5").unwrap();
+        let mut params = Params::default();
+        profile.apply(&mut params);
+        assert_eq!(params.validate().unwrap_err(), "temperature 2.5 is out of 0 to 2");
+
+        assert!(profile.set("seed", "-1").is_err());
+        profile.set("temperature", "").unwrap();
+        profile.set("stop", "a,b,c,d,e").unwrap();
+        let mut params = Params::default();
+        profile.apply(&mut params);
+        assert!(params.validate().unwrap_err().contains("at most 4"));
+
+        let mut config = config();
+        config.models.insert("llama".to_string(), "missing".to_string());
+        assert!(config.check().unwrap_err().contains("missing"));
+        assert!(toml::from_str::<Profile>("temprature = 1").is_err());
+    }
+
+    #[test]
+    fn only_set_options_are_sent() {
+        let params = Params { top_p: Some(0.5), ..Params::default() };
+        assert_eq!(
+            serde_json::to_string(&params).unwrap(),
+            r#"{"temperature":0.0,"top_p":0.5,"max_tokens":1000}"#
+        );
+        assert_eq!(params.get("stop"), "-");
+    }
+}
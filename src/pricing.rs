@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::provider::Usage;
+
+
+///Dollars per 1000 tokens
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Price {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        return (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion) / 1000.0
+    }
+}
+
+///OpenAI list prices, entries of the config file come on top
+pub fn default_prices() -> BTreeMap<String, Price> {
+    let prices = [
+        ("text-davinci-003", 0.02, 0.02),
+        ("gpt-3.5-turbo", 0.0015, 0.002),
+        ("gpt-3.5-turbo-16k", 0.003, 0.004),
+        ("gpt-3.5-turbo-instruct", 0.0015, 0.002),
+        ("gpt-4", 0.03, 0.06),
+        ("gpt-4-32k", 0.06, 0.12),
+    ];
+    return prices
+        .iter()
+        .map(|(model, prompt, completion)| {
+            (model.to_string(), Price { prompt: *prompt, completion: *completion })
+        })
+        .collect()
+}
+
+///Price of `model`, the longest matching prefix wins so "gpt-4" covers
+///"gpt-4-0613" but not "gpt-4-32k"
+pub fn price_for<'a>(prices: &'a BTreeMap<String, Price>, model: &str) -> Option<&'a Price> {
+    return prices
+        .iter()
+        .filter(|(name, _)| model.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let prices = default_prices();
+        assert_eq!(price_for(&prices, "gpt-4-0613"), prices.get("gpt-4"));
+        assert_eq!(price_for(&prices, "gpt-4-32k-0613"), prices.get("gpt-4-32k"));
+        assert_eq!(price_for(&prices, "llama-2-7b"), None);
+    }
+
+    #[test]
+    fn cost_is_per_thousand_tokens() {
+        let price = Price { prompt: 0.03, completion: 0.06 };
+        let cost = price.cost(&Usage::new(1000, 500));
+        assert!((cost - 0.06).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,334 @@
+use log::debug;
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::api::{ApiError, ApiEvent, TimeoutKind, Transport};
+use super::{Completion, CompletionRequest, ModelInfo, Provider, ToolCall, Usage};
+
+
+///One scripted answer, `{prompt}` in the text is replaced by the query
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Reply {
+    ///Whole answer, used when there are no chunks
+    pub text: String,
+    ///Pieces the answer is streamed in
+    pub chunks: Vec<String>,
+    ///Wait before answering and between chunks
+    pub delay_ms: u64,
+    ///Fail with this http status instead of answering
+    pub status: Option<u16>,
+    ///Fail as if the request ran out of time
+    pub timeout: bool,
+    ///Calls asked for instead of answering
+    #[serde(rename = "tool_call")]
+    pub tool_calls: Vec<ScriptedCall>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ScriptedCall {
+    pub name: String,
+    ///Json object as text
+    pub arguments: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Script {
+    reply: Vec<Reply>,
+}
+
+///Where the mock takes its answers from
+enum Source {
+    ///The query itself
+    Echo,
+    ///`<dir>/<query>.txt`, then `<dir>/default.txt`, then the query
+    Fixtures(PathBuf),
+    ///Replies in order, starting over after the last one
+    Script(Vec<Reply>),
+}
+
+///Offline backend answering in-process, no server needed
+pub struct Mock {
+    source: Source,
+    ///Next scripted reply, every attempt (retries too) takes one
+    next: AtomicUsize,
+}
+
+impl Mock {
+    pub fn echo() -> Mock {
+        return Mock { source: Source::Echo, next: AtomicUsize::new(0) }
+    }
+
+    pub fn fixtures(dir: PathBuf) -> Mock {
+        return Mock { source: Source::Fixtures(dir), next: AtomicUsize::new(0) }
+    }
+
+    pub fn scripted(replies: Vec<Reply>) -> Mock {
+        return Mock { source: Source::Script(replies), next: AtomicUsize::new(0) }
+    }
+
+    ///Reads a script file made of `[[reply]]` tables
+    pub fn from_script(path: &Path) -> Result<Mock, Box<dyn Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err).into()),
+        };
+        let script = toml::from_str::<Script>(&contents)?;
+        if script.reply.is_empty() {
+            return Err(format!("{} has no [[reply]]", path.display()).into())
+        }
+        return Ok(Mock::scripted(script.reply))
+    }
+
+    fn reply_for(&self, query: &str) -> Reply {
+        match &self.source {
+            Source::Echo => return Reply { text: query.to_string(), ..Reply::default() },
+            Source::Fixtures(dir) => {
+                let text = fixture(dir, query).unwrap_or_else(|| query.to_string());
+                return Reply { text, ..Reply::default() }
+            }
+            Source::Script(replies) => {
+                let next = self.next.fetch_add(1, Ordering::SeqCst);
+                return replies[next % replies.len()].clone()
+            }
+        }
+    }
+
+    ///Plays one reply, reporting chunks when `stream` is set
+    async fn play(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+        stream: bool,
+    ) -> Result<Completion, ApiError> {
+        let reply = self.reply_for(&request.query);
+        let delay = Duration::from_millis(reply.delay_ms);
+        tokio::time::sleep(delay).await;
+
+        if reply.timeout {
+            return Err(ApiError::Timeout { kind: TimeoutKind::Total, after: request.timeout })
+        }
+        if let Some(status) = reply.status {
+            let status = match reqwest::StatusCode::from_u16(status) {
+                Ok(status) => status,
+                Err(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return Err(ApiError::Status {
+                status,
+                retry_after: None,
+                body: reply.text.replace("{prompt}", &request.query),
+            })
+        }
+
+        let mut completion = Completion {
+            model: request.model.clone(),
+            text: String::new(),
+            usage: None,
+            tool_calls: Vec::new(),
+        };
+        //only when the request offers tools, the final answer comes from the next reply
+        if !reply.tool_calls.is_empty() && !request.tools.is_empty() {
+            completion.tool_calls = reply.tool_calls
+                .iter()
+                .enumerate()
+                .map(|(index, call)| ToolCall {
+                    id: format!("call_{}_{}", request.tool_rounds.len(), index),
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                })
+                .collect();
+            return Ok(completion)
+        }
+
+        let chunks = match reply.chunks.is_empty() {
+            true => vec![reply.text],
+            false => reply.chunks,
+        };
+        for (index, chunk) in chunks.iter().enumerate() {
+            if index > 0 {
+                tokio::time::sleep(delay).await;
+            }
+            let chunk = chunk.replace("{prompt}", &request.query);
+            completion.text.push_str(&chunk);
+            if stream {
+                transport.notify(ApiEvent::Chunk(chunk));
+            }
+        }
+        completion.usage = Some(Usage::new(
+            self.count_tokens(&request.model, &request.prompt) as u64,
+            self.count_tokens(&request.model, &completion.text) as u64,
+        ));
+        return Ok(completion)
+    }
+
+    ///Goes through the transport retries, bounded by the total timeout
+    async fn answer(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+        stream: bool,
+    ) -> Result<Completion, ApiError> {
+        let attempts = transport.retry(|| self.play(transport, request, stream));
+
+        match transport.total_timeout(request.timeout) {
+            Some(total) => match tokio::time::timeout(total, attempts).await {
+                Ok(answer) => return answer,
+                Err(_) => return Err(ApiError::Timeout { kind: TimeoutKind::Total, after: Some(total) }),
+            },
+            None => return attempts.await,
+        }
+    }
+}
+
+///File name a prompt is looked up by, lowercase words joined by dashes
+fn fixture_name(prompt: &str) -> String {
+    return prompt
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+fn fixture(dir: &Path, prompt: &str) -> Option<String> {
+    for name in [fixture_name(prompt), "default".to_string()] {
+        let path = dir.join(format!("{}.txt", name));
+        if let Ok(text) = fs::read_to_string(&path) {
+            debug!("Answering from fixture {}", path.display());
+            return Some(text)
+        }
+    }
+    return None
+}
+
+#[async_trait]
+impl Provider for Mock {
+
+    async fn send(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError> {
+        return self.answer(transport, request, false).await
+    }
+
+    async fn stream(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError> {
+        return self.answer(transport, request, true).await
+    }
+
+    async fn list_models(&self, _transport: &Transport) -> Result<Vec<ModelInfo>, ApiError> {
+        return Ok(vec![ModelInfo {
+            id: "mock".to_string(),
+            owned_by: Some("gpterm".to_string()),
+            context_length: Some(4096),
+        }])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::{ApiHandler, RetryPolicy};
+    use crate::config::Config;
+    use crate::params::Params;
+    use crate::provider::{Endpoint, Flavour};
+
+    fn handler_with(mock: Mock, stream: bool) -> ApiHandler {
+        let config = Config {
+            retry: RetryPolicy { initial_backoff_ms: 1, jitter: false, ..RetryPolicy::default() },
+            ..Config::default()
+        };
+        let mut handler = ApiHandler::new(String::new(), &config).unwrap();
+        handler.set_provider("mock", Box::new(mock), stream);
+        return handler
+    }
+
+    async fn ask(handler: &mut ApiHandler, prompt: &str) -> String {
+        let answer = handler.answer_from("mock".to_string(), prompt.to_string(), Params { max_tokens: 7, ..Params::default() }, None).await;
+        return answer.get_body().clone()
+    }
+
+    #[tokio::test]
+    async fn echoes_the_prompt() {
+        let config = Config {
+            endpoint: Endpoint { flavour: Flavour::Mock, ..Endpoint::default() },
+            ..Config::default()
+        };
+        let mut handler = ApiHandler::new(String::new(), &config).unwrap();
+        assert_eq!(ask(&mut handler, "hello there").await, "hello there");
+    }
+
+    #[tokio::test]
+    async fn answers_from_fixtures() {
+        let dir = std::env::temp_dir().join(format!("gpterm-fixtures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("what-is-rust.txt"), "a language").unwrap();
+        fs::write(dir.join("default.txt"), "no idea").unwrap();
+
+        let mut handler = handler_with(Mock::fixtures(dir.clone()), false);
+        assert_eq!(ask(&mut handler, "What is Rust?").await, "a language");
+        assert_eq!(ask(&mut handler, "something else").await, "no idea");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scripted_failures_are_retried() {
+        let script = r#"
+            [[reply]]
+            status = 429
+            [[reply]]
+            chunks = ["you said ", "{prompt}"]
+        "#;
+        let replies = toml::from_str::<Script>(script).unwrap().reply;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler_with(Mock::scripted(replies), true);
+        handler.set_event_sender(sender);
+
+        assert_eq!(ask(&mut handler, "hi").await, "you said hi");
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert!(matches!(events[0], ApiEvent::Retrying { attempt: 1, .. }));
+        assert!(matches!(&events[1], ApiEvent::Chunk(text) if text == "you said "));
+        assert!(matches!(&events[2], ApiEvent::Chunk(text) if text == "hi"));
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_reach_the_transcript() {
+        let replies = vec![
+            Reply { status: Some(400), text: "bad request".to_string(), ..Reply::default() },
+            Reply { timeout: true, ..Reply::default() },
+        ];
+        let mut handler = handler_with(Mock::scripted(replies), false);
+
+        assert!(ask(&mut handler, "hi").await.contains("bad request"));
+        assert!(ask(&mut handler, "hi").await.contains(":timeout"));
+    }
+
+    #[test]
+    fn fixture_names_are_slugs() {
+        assert_eq!(fixture_name("What is Rust?"), "what-is-rust");
+        assert_eq!(fixture_name("  a/b  c "), "a-b-c");
+    }
+}
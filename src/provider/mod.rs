@@ -0,0 +1,215 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ApiError, ApiEvent, Transport};
+use crate::params::Params;
+use crate::tokenizer;
+
+mod mock;
+mod openai;
+
+pub use mock::Mock;
+pub use openai::OpenAi;
+
+
+///Request/response shape spoken by the server behind an endpoint
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavour {
+    ///OpenAI and compatible servers (llama.cpp, vLLM, Ollama...)
+    OpenAi,
+    ///Azure OpenAI, the model is the deployment name
+    Azure,
+    ///Offline backend answering in-process
+    #[serde(alias = "dummy")]
+    Mock,
+}
+
+///Where requests are sent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Endpoint {
+    pub flavour: Flavour,
+    ///Root of the API, defaults to the official one for the flavour
+    pub base_url: Option<String>,
+    ///`api-version` query parameter, Azure only
+    pub api_version: String,
+    ///Key for this endpoint, the token file is used when unset
+    pub api_key: Option<String>,
+    ///Show answers while they are generated
+    pub stream: bool,
+    ///Mock only, directory of `<prompt>.txt` answers
+    pub fixtures: Option<PathBuf>,
+    ///Mock only, toml file of `[[reply]]` tables played in order
+    pub script: Option<PathBuf>,
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint {
+            flavour: Flavour::OpenAi,
+            base_url: None,
+            api_version: "2023-05-15".to_string(),
+            api_key: None,
+            stream: false,
+            fixtures: None,
+            script: None,
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn base_url(&self) -> Result<String, Box<dyn Error>> {
+        let base_url = match (&self.base_url, self.flavour) {
+            (Some(base_url), _) => base_url.as_str(),
+            (None, Flavour::OpenAi) => "https://api.openai.com/v1",
+            //answers without a server
+            (None, Flavour::Mock) => "",
+            (None, Flavour::Azure) => {
+                return Err("the azure flavour needs base_url".into())
+            }
+        };
+        return Ok(base_url.trim_end_matches('/').to_string())
+    }
+}
+
+
+///What is asked of a provider, independent of the wire format
+#[derive(Debug, Clone)]
+pub struct CompletionRequest {
+    pub model: String,
+    ///Instructions going before the conversation, from the session's persona
+    pub system: Option<String>,
+    ///What the model is given, the conversation so far included
+    pub prompt: String,
+    ///What the user asked last, the end of `prompt`
+    pub query: String,
+    ///Sampling settings, validated already
+    pub params: Params,
+    ///Replaces the configured total timeout for this request
+    pub timeout: Option<Duration>,
+    ///Functions the model may call instead of answering
+    pub tools: Vec<ToolSpec>,
+    ///Calls the model made so far for this question, with their results
+    pub tool_rounds: Vec<ToolRound>,
+}
+
+///A function the model may call, its arguments described by a json schema
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+///A call the model asked for, `arguments` is a json object as text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+///Calls made in one answer and what each returned, in the same order
+#[derive(Debug, Clone)]
+pub struct ToolRound {
+    pub calls: Vec<ToolCall>,
+    pub results: Vec<String>,
+}
+
+///Tokens a request took, as counted by the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        return Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+///What a provider answered
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub model: String,
+    pub text: String,
+    ///None when the server didn't say
+    pub usage: Option<Usage>,
+    ///Calls the model wants made before it answers, `text` is usually empty then
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub owned_by: Option<String>,
+    ///Tokens the model takes in, from servers that say (vLLM, OpenRouter...)
+    #[serde(default, alias = "max_model_len", alias = "context_window")]
+    pub context_length: Option<usize>,
+}
+
+
+///A backend able to answer prompts.
+///Requests go through the `Transport` so they share retries and timeouts
+#[async_trait]
+pub trait Provider: Send + Sync {
+
+    async fn send(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError>;
+
+    ///Like `send`, but text is reported as `ApiEvent::Chunk` while it arrives.
+    ///Providers that can't stream report the whole answer at once
+    async fn stream(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError> {
+        let completion = self.send(transport, request).await?;
+        transport.notify(ApiEvent::Chunk(completion.text.clone()));
+        return Ok(completion)
+    }
+
+    async fn list_models(&self, _transport: &Transport) -> Result<Vec<ModelInfo>, ApiError> {
+        return Err(ApiError::Unsupported("listing models"))
+    }
+
+    ///Number of tokens `text` takes for `model`, with tiktoken unless the provider knows better
+    fn count_tokens(&self, model: &str, text: &str) -> usize {
+        return tokenizer::count_tokens(model, text)
+    }
+}
+
+
+///Builds the provider talking to `endpoint`
+pub fn from_endpoint(endpoint: &Endpoint, token: &str) -> Result<Box<dyn Provider>, Box<dyn Error>> {
+    let base_url = endpoint.base_url()?;
+    let token = endpoint.api_key.clone().unwrap_or_else(|| token.to_string());
+
+    match endpoint.flavour {
+        Flavour::OpenAi => return Ok(Box::new(OpenAi::new(base_url, token))),
+        Flavour::Azure => {
+            return Ok(Box::new(OpenAi::azure(base_url, token, endpoint.api_version.clone())))
+        }
+        Flavour::Mock => match (&endpoint.script, &endpoint.fixtures) {
+            (Some(script), _) => return Ok(Box::new(Mock::from_script(script)?)),
+            (None, Some(fixtures)) => return Ok(Box::new(Mock::fixtures(fixtures.clone()))),
+            (None, None) => return Ok(Box::new(Mock::echo())),
+        },
+    }
+}
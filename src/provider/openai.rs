@@ -0,0 +1,598 @@
+//logging
+use log::{debug, error};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api::{ApiError, ApiEvent, Transport};
+use crate::params::Params;
+use super::{Completion, CompletionRequest, ModelInfo, Provider, ToolCall, Usage};
+
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Choices {
+    text: String,
+    // index: i32,
+    // logprobs: Option<String>,
+    // finish_reason: String
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiResponse {
+    // id: String,
+    // object: String,
+    // created: i32,
+    model: String,
+    choices: Vec<Choices>,
+    ///Missing from streamed pieces
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiCall {
+    model: String,
+    prompt: String,
+    #[serde(flatten)]
+    params: Params,
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    stream: bool,
+}
+
+impl ApiCall {
+    fn from(request: &CompletionRequest, stream: bool) -> ApiCall {
+        return ApiCall {
+            model: request.model.clone(),
+            //completions have no room for instructions but the prompt itself
+            prompt: match &request.system {
+                Some(system) => format!("{}\n\n{}", system, request.prompt),
+                None => request.prompt.clone(),
+            },
+            params: request.params.clone(),
+            stream,
+        }
+    }
+
+    fn get_model(&self) -> String {
+        return self.model.clone();
+    }
+}
+
+///Chat completions call, made instead when the request offers tools
+#[derive(Serialize, Debug)]
+struct ChatCall {
+    model: String,
+    messages: Vec<serde_json::Value>,
+    #[serde(flatten)]
+    params: Params,
+    tools: Vec<serde_json::Value>,
+}
+
+impl ChatCall {
+    fn from(request: &CompletionRequest) -> ChatCall {
+        let mut messages = Vec::new();
+        if let Some(system) = &request.system {
+            messages.push(json!({"role": "system", "content": system}));
+        }
+        messages.push(json!({"role": "user", "content": request.prompt}));
+        //every round is the answer asking for calls, then what each returned
+        for round in &request.tool_rounds {
+            let calls: Vec<serde_json::Value> = round.calls
+                .iter()
+                .map(|call| json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": {"name": call.name, "arguments": call.arguments}
+                }))
+                .collect();
+            messages.push(json!({"role": "assistant", "content": null, "tool_calls": calls}));
+            for (call, result) in round.calls.iter().zip(&round.results) {
+                messages.push(json!({"role": "tool", "tool_call_id": call.id, "content": result}));
+            }
+        }
+
+        return ChatCall {
+            model: request.model.clone(),
+            messages,
+            params: request.params.clone(),
+            tools: request.tools
+                .iter()
+                .map(|tool| json!({"type": "function", "function": tool}))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    model: String,
+    choices: Vec<ChatChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<WireToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WireToolCall {
+    id: String,
+    function: WireFunction,
+}
+
+#[derive(Deserialize, Debug)]
+struct WireFunction {
+    name: String,
+    arguments: String,
+}
+
+impl ChatResponse {
+    fn completion(self) -> Completion {
+        let mut completion = Completion { model: self.model, text: String::new(), usage: self.usage, tool_calls: Vec::new() };
+        if let Some(choice) = self.choices.into_iter().next() {
+            completion.text = choice.message.content.unwrap_or_default();
+            completion.tool_calls = choice.message.tool_calls
+                .into_iter()
+                .map(|call| ToolCall { id: call.id, name: call.function.name, arguments: call.function.arguments })
+                .collect();
+        }
+        return completion
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+
+///Parses a json body, logging what was received when it doesn't fit
+pub(super) fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, ApiError> {
+    match serde_json::from_slice::<T>(body) {
+        Ok(parsed) => return Ok(parsed),
+        Err(err) => {
+            error!("Couldn't parse received request");
+            debug!("RECEIVED: {}", String::from_utf8_lossy(body));
+            return Err(ApiError::Parse(err))
+        }
+    }
+}
+
+
+///OpenAI completions API, also spoken by Azure and most local servers
+pub struct OpenAi {
+    base_url: String,
+    token: String,
+    ///Set for Azure, which wants an `api-version` on every request
+    azure_api_version: Option<String>,
+}
+
+impl OpenAi {
+    pub fn new(base_url: String, token: String) -> OpenAi {
+        return OpenAi { base_url, token, azure_api_version: None }
+    }
+
+    pub fn azure(base_url: String, token: String, api_version: String) -> OpenAi {
+        return OpenAi { base_url, token, azure_api_version: Some(api_version) }
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.azure_api_version {
+            Some(api_version) => {
+                return request
+                    .query(&[("api-version", api_version.as_str())])
+                    .header("api-key", &self.token)
+            }
+            //local servers usually don't want a key
+            None if self.token.is_empty() => return request,
+            None => return request.bearer_auth(&self.token),
+        }
+    }
+
+    fn completions(&self, transport: &Transport, call: &ApiCall) -> reqwest::RequestBuilder {
+        // curl https://api.openai.com/v1/completions \
+        // -H "Content-Type: application/json" \
+        // -H "Authorization: Bearer YOUR_API_KEY" \
+        // -d '{"model": "text-davinci-003", "prompt": "Say this is a test", "temperature": 0, "max_tokens": 7}'
+        let url = match self.azure_api_version {
+            Some(_) => format!("{}/openai/deployments/{}/completions", self.base_url, call.get_model()),
+            None => format!("{}/completions", self.base_url),
+        };
+
+        return self.authorize(transport.client().post(url))
+            .header("Content-Type", "application/json")
+            .json(call)
+    }
+
+    fn chat_completions(&self, transport: &Transport, call: &ChatCall) -> reqwest::RequestBuilder {
+        let url = match self.azure_api_version {
+            Some(_) => format!("{}/openai/deployments/{}/chat/completions", self.base_url, call.model),
+            None => format!("{}/chat/completions", self.base_url),
+        };
+
+        return self.authorize(transport.client().post(url))
+            .header("Content-Type", "application/json")
+            .json(call)
+    }
+
+    ///Sends a request offering tools, through the chat api as completions have no tools
+    async fn send_chat(&self, transport: &Transport, request: &CompletionRequest) -> Result<Completion, ApiError> {
+        let call = ChatCall::from(request);
+        let body = transport.send_with_retry(
+            || self.chat_completions(transport, &call),
+            request.timeout,
+        ).await?;
+
+        return Ok(parse::<ChatResponse>(&body)?.completion())
+    }
+}
+
+///Payload of one line of an event stream, None for anything but data
+fn stream_data(line: &[u8]) -> Option<String> {
+    let line = String::from_utf8_lossy(line);
+    let data = line.trim().strip_prefix("data:")?;
+    return Some(data.trim().to_string())
+}
+
+#[async_trait]
+impl Provider for OpenAi {
+
+    async fn send(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError> {
+        if !request.tools.is_empty() {
+            return self.send_chat(transport, request).await
+        }
+        let call = ApiCall::from(request, false);
+        let body = transport.send_with_retry(
+            || self.completions(transport, &call),
+            request.timeout,
+        ).await?;
+
+        let response = parse::<ApiResponse>(&body)?;
+        return Ok(response.completion())
+    }
+
+    async fn stream(
+        &self,
+        transport: &Transport,
+        request: &CompletionRequest,
+    ) -> Result<Completion, ApiError> {
+        //calls come whole, only the final answer could be streamed
+        if !request.tools.is_empty() {
+            let completion = self.send_chat(transport, request).await?;
+            if !completion.text.is_empty() {
+                transport.notify(ApiEvent::Chunk(completion.text.clone()));
+            }
+            return Ok(completion)
+        }
+        let call = ApiCall::from(request, true);
+        let mut response = transport.open_with_retry(
+            || self.completions(transport, &call),
+            request.timeout,
+        ).await?;
+        let total = transport.total_timeout(request.timeout);
+
+        let mut completion = Completion { model: request.model.clone(), text: String::new(), usage: None, tool_calls: Vec::new() };
+        //bytes after the last full line, events can be split anywhere
+        let mut pending: Vec<u8> = Vec::new();
+
+        while let Some(bytes) = transport.next_chunk(&mut response, total).await? {
+            pending.extend_from_slice(&bytes);
+
+            while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let data = match stream_data(&line) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                if data == "[DONE]" {
+                    return Ok(completion)
+                }
+
+                let piece = parse::<ApiResponse>(data.as_bytes())?;
+                completion.model = piece.get_model();
+                //some servers send it with the last piece
+                if piece.usage.is_some() {
+                    completion.usage = piece.usage;
+                }
+                if let Some(choice) = piece.choices().first() {
+                    completion.text.push_str(&choice.text);
+                    transport.notify(ApiEvent::Chunk(choice.get_answer()));
+                }
+            }
+        }
+
+        return Ok(completion)
+    }
+
+    async fn list_models(&self, transport: &Transport) -> Result<Vec<ModelInfo>, ApiError> {
+        let url = match self.azure_api_version {
+            Some(_) => format!("{}/openai/models", self.base_url),
+            None => format!("{}/models", self.base_url),
+        };
+
+        let body = transport.send_with_retry(
+            || self.authorize(transport.client().get(url.as_str())),
+            None,
+        ).await?;
+
+        return Ok(parse::<ModelList>(&body)?.data)
+    }
+}
+
+
+impl ApiResponse {
+    pub fn choices(&self) -> &Vec<Choices> {
+        return &self.choices;
+    }
+    pub fn get_model(&self) -> String {
+        return String::from(&self.model);
+    }
+
+    pub fn completion(&self) -> Completion {
+        let text = match self.choices.first() {
+            Some(choice) => choice.get_answer(),
+            None => String::new(),
+        };
+        return Completion { model: self.get_model(), text, usage: self.usage, tool_calls: Vec::new() }
+    }
+}
+
+
+impl Choices {
+    pub fn get_answer(&self) -> String {
+        return String::from(&self.text);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::ApiHandler;
+    use crate::app::Message;
+    use crate::config::Config;
+    use crate::provider::{Endpoint, Flavour};
+
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn completion() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "model": "test-model",
+            "choices": [{"text": "hello"}]
+        }))
+    }
+
+    fn handler_for(endpoint: Endpoint, token: &str) -> ApiHandler {
+        let config = Config { endpoint, ..Config::default() };
+        return ApiHandler::new(token.to_string(), &config).unwrap()
+    }
+
+    async fn ask(handler: &mut ApiHandler, model: &str) -> Message {
+        return handler.answer_from(model.to_string(), "hi".to_string(), Params { max_tokens: 7, ..Params::default() }, None).await
+    }
+
+    #[tokio::test]
+    async fn openai_flavour_posts_to_the_base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .and(header("Authorization", "Bearer secret"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let mut handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1/", server.uri())),
+            ..Endpoint::default()
+        }, "secret");
+
+        assert_eq!(ask(&mut handler, "local-model").await.get_body(), "hello");
+    }
+
+    #[tokio::test]
+    async fn usage_is_kept_with_the_answer() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "model": "test-model",
+                "choices": [{"text": "hello"}],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6}
+            })))
+            .mount(&server)
+            .await;
+
+        let mut handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1", server.uri())),
+            ..Endpoint::default()
+        }, "");
+        ask(&mut handler, "test-model").await;
+
+        let usage = handler.response.unwrap().usage;
+        assert_eq!(usage, Some(Usage::new(5, 1)));
+    }
+
+    #[tokio::test]
+    async fn local_servers_get_no_auth_without_a_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let mut handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1", server.uri())),
+            ..Endpoint::default()
+        }, "");
+        ask(&mut handler, "local-model").await;
+
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests[0].headers.iter()
+            .all(|(name, _)| !name.as_str().eq_ignore_ascii_case("authorization")));
+    }
+
+    #[tokio::test]
+    async fn azure_flavour_targets_the_deployment() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/my-deployment/completions"))
+            .and(query_param("api-version", "2023-05-15"))
+            .and(header("api-key", "secret"))
+            .respond_with(completion())
+            .mount(&server)
+            .await;
+
+        let mut handler = handler_for(Endpoint {
+            flavour: Flavour::Azure,
+            base_url: Some(server.uri()),
+            ..Endpoint::default()
+        }, "secret");
+
+        assert_eq!(ask(&mut handler, "my-deployment").await.get_body(), "hello");
+    }
+
+    #[test]
+    fn azure_needs_a_base_url() {
+        let endpoint = Endpoint { flavour: Flavour::Azure, ..Endpoint::default() };
+        assert!(endpoint.base_url().is_err());
+        assert_eq!(Endpoint::default().base_url().unwrap(), "https://api.openai.com/v1");
+    }
+
+    #[tokio::test]
+    async fn streamed_text_is_reported_as_it_arrives() {
+        let server = MockServer::start().await;
+        let events = [
+            r#"data: {"model": "test-model", "choices": [{"text": "hel"}]}"#,
+            r#"data: {"model": "test-model", "choices": [{"text": "lo"}]}"#,
+            "data: [DONE]",
+        ];
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .and(body_partial_json(serde_json::json!({"stream": true})))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("Content-Type", "text/event-stream")
+                .set_body_string(events.join("\n\n") + "\n\n"))
+            .mount(&server)
+            .await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1", server.uri())),
+            stream: true,
+            ..Endpoint::default()
+        }, "");
+        handler.set_event_sender(sender);
+
+        assert_eq!(ask(&mut handler, "test-model").await.get_body(), "hello");
+
+        let mut chunks = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let ApiEvent::Chunk(text) = event {
+                chunks.push(text);
+            }
+        }
+        assert_eq!(chunks, vec!["hel", "lo"]);
+    }
+
+    #[tokio::test]
+    async fn models_are_listed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/models"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "data": [
+                    {"id": "gpt-3.5-turbo", "object": "model", "owned_by": "openai"},
+                    {"id": "llama-2-7b", "object": "model", "max_model_len": 4096}
+                ]
+            })))
+            .mount(&server)
+            .await;
+
+        let handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1", server.uri())),
+            ..Endpoint::default()
+        }, "");
+        let models = handler.list_models().await.unwrap();
+
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].id, "gpt-3.5-turbo");
+        assert_eq!(models[0].owned_by.as_deref(), Some("openai"));
+        assert_eq!(models[1].owned_by, None);
+        assert_eq!(models[1].context_length, Some(4096));
+    }
+
+    #[tokio::test]
+    async fn requests_with_tools_go_through_chat_with_the_results_so_far() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({
+                "tools": [{"type": "function", "function": {"name": "read_file"}}],
+                "messages": [
+                    {"role": "user", "content": "what is in a.txt?"},
+                    {"role": "assistant", "tool_calls": [{"id": "call_1", "function": {"name": "read_file"}}]},
+                    {"role": "tool", "tool_call_id": "call_1", "content": "hello"}
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "model": "test-model",
+                "choices": [{"message": {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "call_2", "type": "function", "function": {"name": "read_file", "arguments": "{\"path\":\"b.txt\"}"}}
+                ]}}]
+            })))
+            .mount(&server)
+            .await;
+
+        let handler = handler_for(Endpoint {
+            base_url: Some(format!("{}/v1", server.uri())),
+            ..Endpoint::default()
+        }, "");
+        let call = ToolCall { id: "call_1".to_string(), name: "read_file".to_string(), arguments: "{}".to_string() };
+        let request = CompletionRequest {
+            model: "test-model".to_string(),
+            system: None,
+            prompt: "what is in a.txt?".to_string(),
+            query: "what is in a.txt?".to_string(),
+            params: Params { max_tokens: 7, ..Params::default() },
+            timeout: None,
+            tools: vec![crate::provider::ToolSpec {
+                name: "read_file".to_string(),
+                description: "reads".to_string(),
+                parameters: serde_json::json!({"type": "object"}),
+            }],
+            tool_rounds: vec![crate::provider::ToolRound { calls: vec![call], results: vec!["hello".to_string()] }],
+        };
+
+        let completion = handler.complete(&request).await.unwrap();
+        assert_eq!(completion.text, "");
+        assert_eq!(completion.tool_calls[0].id, "call_2");
+        assert_eq!(completion.tool_calls[0].arguments, "{\"path\":\"b.txt\"}");
+    }
+
+    #[test]
+    fn stream_lines_carry_data() {
+        assert_eq!(stream_data(b"data: {}\n").as_deref(), Some("{}"));
+        assert_eq!(stream_data(b"data:[DONE]").as_deref(), Some("[DONE]"));
+        assert_eq!(stream_data(b": keep-alive\n"), None);
+        assert_eq!(stream_data(b"\n"), None);
+    }
+}
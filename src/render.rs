@@ -1 +1,428 @@
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Frame,
+};
 
+use unicode_width::UnicodeWidthStr;
+
+use crate::app::{App, CommandStatus, Confirm, InputMode};
+use crate::attach::{self, Attachment};
+use crate::compare::Comparison;
+use crate::index::{self, Finder};
+use crate::models::ModelPicker;
+use crate::params::{ParamsPanel, FIELDS};
+
+///Attached files listed above the input box, more are summed up
+const ATTACHMENT_LINES: usize = 4;
+
+pub fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
+    let attachments = app.get_attachments();
+    let attachment_lines = match &attachments {
+        Some(Ok(attached)) => attached.len().min(ATTACHMENT_LINES + 1),
+        Some(Err(_)) => 1,
+        None => 0,
+    };
+    let preview = match attachment_lines {
+        0 => 0,
+        lines => lines as u16 + 2,
+    };
+
+    let tabs = app.get_tabs();
+    let tab_bar = match tabs.len() {
+        1 => 0,
+        _ => 1,
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(tab_bar),
+                Constraint::Min(1),
+                Constraint::Length(preview),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    if tab_bar > 0 {
+        render_tabs(f, &tabs, app.active_tab(), chunks[0]);
+    }
+    match app.get_comparison() {
+        Some(comparison) => render_comparison(f, app, comparison, chunks[1]),
+        None => render_messages(f, app, chunks[1]),
+    }
+    if let Some(attachments) = attachments {
+        render_attachments(f, attachments, chunks[2]);
+    }
+    render_input(f, app, chunks[3]);
+    render_status_line(f, app, chunks[4]);
+
+    if let Some(confirm) = app.get_confirm() {
+        render_confirm(f, confirm);
+    }
+    if let Some(picker) = app.get_picker() {
+        render_picker(f, app, picker);
+    }
+    if let Some(panel) = app.get_params_panel() {
+        render_params(f, app, panel);
+    }
+    if let Some(finder) = app.get_finder() {
+        render_finder(f, finder);
+    }
+}
+
+///Open conversations, the one shown highlighted and the busy ones marked
+fn render_tabs<B: Backend>(f: &mut Frame<B>, tabs: &[(String, bool)], active: usize, area: Rect) {
+    let spans: Vec<Span> = tabs
+        .iter()
+        .enumerate()
+        .map(|(index, (label, busy))| {
+            let style = match index == active {
+                true => Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD),
+                false => Style::default().fg(Color::DarkGray),
+            };
+            let busy = match busy {
+                true => " …",
+                false => "",
+            };
+            Span::styled(format!(" {} {}{} ", index + 1, label, busy), style)
+        })
+        .collect();
+    f.render_widget(Paragraph::new(Spans::from(spans)), area);
+}
+
+///`percent_x` by `percent_y` of `area`, in its middle
+fn centered(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let width = area.width * percent_x / 100;
+    let height = area.height * percent_y / 100;
+    return Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+///What waits for y or n, over everything else
+fn render_confirm<B: Backend>(f: &mut Frame<B>, confirm: &Confirm) {
+    let area = centered(80, 60, f.size());
+    let mut lines: Vec<Spans> = confirm.preview
+        .lines()
+        .map(|line| {
+            let style = match (confirm.diff, line.chars().next()) {
+                (true, Some('+')) => Style::default().fg(Color::Green),
+                (true, Some('-')) => Style::default().fg(Color::Red),
+                (true, Some('@')) => Style::default().fg(Color::Cyan),
+                _ => Style::default(),
+            };
+            Spans::from(Span::styled(line, style))
+        })
+        .collect();
+    lines.push(Spans::from(""));
+    lines.push(Spans::from(Span::styled(
+        "y: yes   n: no",
+        Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+    )));
+
+    let popup = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(confirm.title.as_str()))
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+///Models to switch to with their context length, the one selected highlighted
+fn render_picker<B: Backend>(f: &mut Frame<B>, app: &App, picker: &ModelPicker) {
+    let area = centered(80, 60, f.size());
+    let visible = picker.visible();
+    //keeps the selection in view
+    let rows = area.height.saturating_sub(2) as usize;
+    let skip = (picker.selected() + 1).saturating_sub(rows);
+
+    let width = visible.iter().map(|model| model.id.width()).max().unwrap_or(0);
+    let mut lines: Vec<Spans> = visible
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(index, model)| {
+            let style = match index == picker.selected() {
+                true => Style::default().add_modifier(Modifier::REVERSED),
+                false => Style::default(),
+            };
+            let mut spans = vec![
+                Span::styled(format!("{:<width$}", model.id, width = width), style),
+                Span::styled(format!("  {} ctx", app.context_window(&model.id)), Style::default().fg(Color::DarkGray)),
+            ];
+            if let Some(owner) = &model.owned_by {
+                spans.push(Span::styled(format!("  {}", owner), Style::default().fg(Color::DarkGray)));
+            }
+            Spans::from(spans)
+        })
+        .collect();
+    if visible.is_empty() {
+        lines.push(Spans::from(Span::styled("no model matches", Style::default().fg(Color::DarkGray))));
+    }
+
+    let title = format!("model: {}_  (enter picks, ctrl-r refreshes, esc closes)", picker.filter);
+    let popup = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+///Messages of the saved sessions a search found, two lines each
+fn render_finder<B: Backend>(f: &mut Frame<B>, finder: &Finder) {
+    let area = centered(80, 70, f.size());
+    //keeps the selection in view
+    let rows = (area.height.saturating_sub(2) / 2) as usize;
+    let skip = (finder.selected() + 1).saturating_sub(rows);
+
+    let lines: Vec<Spans> = finder.hits
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .flat_map(|(index, hit)| {
+            let style = match index == finder.selected() {
+                true => Style::default().add_modifier(Modifier::REVERSED),
+                false => Style::default().add_modifier(Modifier::BOLD),
+            };
+            vec![
+                Spans::from(vec![
+                    Span::styled(format!("{} #{}", hit.session, hit.message + 1), style),
+                    Span::styled(format!("  {}", index::age(hit.saved)), Style::default().fg(Color::DarkGray)),
+                ]),
+                Spans::from(Span::raw(format!("  {}", hit.snippet))),
+            ]
+        })
+        .collect();
+
+    let title = format!("sessions with {}: {} found  (enter opens, esc closes)", finder.query, finder.hits.len());
+    let popup = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+///Parameters of the next request, the ones set by hand marked
+fn render_params<B: Backend>(f: &mut Frame<B>, app: &App, panel: &ParamsPanel) {
+    let area = centered(60, 60, f.size());
+    let params = app.params();
+    let mut lines: Vec<Spans> = FIELDS
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            let value = match (&panel.editing, &params) {
+                (Some(editing), _) if index == panel.selected => format!("{}_", editing),
+                (_, Ok(params)) => params.get(field),
+                (_, Err(_)) => "?".to_string(),
+            };
+            let style = match index == panel.selected {
+                true => Style::default().add_modifier(Modifier::REVERSED),
+                false => Style::default(),
+            };
+            let mut spans = vec![
+                Span::raw(format!("{:<18}", field)),
+                Span::styled(value, style),
+            ];
+            if app.is_overridden(field) {
+                spans.push(Span::styled("  set by hand", Style::default().fg(Color::DarkGray)));
+            }
+            Spans::from(spans)
+        })
+        .collect();
+
+    lines.push(Spans::from(""));
+    match (&panel.error, &params) {
+        (Some(err), _) | (None, Err(err)) => {
+            lines.push(Spans::from(Span::styled(err.as_str(), Style::default().fg(Color::Red))))
+        }
+        (None, Ok(_)) => lines.push(Spans::from(Span::styled(
+            "enter edits, an empty value goes back to the profile's, esc closes",
+            Style::default().fg(Color::DarkGray),
+        ))),
+    }
+
+    let title = match app.get_profile() {
+        Some(profile) => format!("parameters · profile {}", profile),
+        None => "parameters".to_string(),
+    };
+    let popup = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, area);
+    f.render_widget(popup, area);
+}
+
+///Files the input box attaches, or why they can't be
+fn render_attachments<B: Backend>(f: &mut Frame<B>, attachments: Result<Vec<Attachment>, String>, area: Rect) {
+    let lines: Vec<Spans> = match attachments {
+        Ok(attachments) => {
+            let total: u64 = attachments.iter().map(|attachment| attachment.bytes).sum();
+            let mut lines: Vec<Spans> = attachments
+                .iter()
+                .take(ATTACHMENT_LINES)
+                .map(|attachment| Spans::from(vec![
+                    Span::raw(attachment.path.display().to_string()),
+                    Span::styled(format!("  {}", attach::size(attachment.bytes)), Style::default().fg(Color::DarkGray)),
+                ]))
+                .collect();
+            if attachments.len() > ATTACHMENT_LINES {
+                lines.push(Spans::from(Span::styled(
+                    format!("...and {} more, {} in all", attachments.len() - ATTACHMENT_LINES, attach::size(total)),
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+            lines
+        }
+        Err(err) => vec![Spans::from(Span::styled(err, Style::default().fg(Color::Red)))],
+    };
+
+    let preview = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title("Attached"));
+    f.render_widget(preview, area);
+}
+
+fn render_messages<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let messages = Paragraph::new(app.get_content())
+        .block(Block::default().borders(Borders::ALL).title(app.get_title()))
+        .wrap(Wrap { trim: false })
+        .scroll((app.get_scroll() as u16, 0));
+
+    f.render_widget(messages, area);
+}
+
+///Answers compared side by side, numbered for `:keep`
+fn render_comparison<B: Backend>(f: &mut Frame<B>, app: &App, comparison: &Comparison, area: Rect) {
+    let outer = Block::default()
+        .borders(Borders::ALL)
+        .title(format!("compare: {}  (:keep <n> keeps one, esc goes back)", comparison.query.trim()));
+    let inner = outer.inner(area);
+    f.render_widget(outer, area);
+
+    let count = comparison.columns.len() as u32;
+    let constraints: Vec<Constraint> = comparison.columns.iter().map(|_| Constraint::Ratio(1, count)).collect();
+    let areas = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(constraints)
+        .split(inner);
+
+    for (index, (column, area)) in comparison.columns.iter().zip(areas).enumerate() {
+        let mut title = format!("{} {}", index + 1, column.target.describe());
+        if let Some(latency) = column.latency {
+            title.push_str(&format!(" · {:.1}s", latency.as_secs_f64()));
+        }
+        if let Some(note) = column.answer.as_ref().and_then(|answer| app.answer_note(answer)) {
+            title.push_str(&format!(" · {}", note));
+        }
+        let lines: Vec<Spans> = match &column.answer {
+            Some(answer) if answer.is_system() => {
+                vec![Spans::from(Span::styled(answer.get_body().as_str(), Style::default().fg(Color::Red)))]
+            }
+            Some(answer) => answer.get_body().trim().lines().map(Spans::from).collect(),
+            None => vec![Spans::from(Span::styled("waiting...", Style::default().fg(Color::DarkGray)))],
+        };
+        let paragraph = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .wrap(Wrap { trim: false });
+        f.render_widget(paragraph, area);
+    }
+}
+
+fn render_input<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    let input = app.get_display_input();
+
+    let style = match app.input_mode() {
+        InputMode::Insert => Style::default().fg(Color::Yellow),
+        _ => Style::default(),
+    };
+
+    let paragraph = Paragraph::new(input.as_str())
+        .style(style)
+        .block(Block::default().borders(Borders::ALL).title(app.get_input_title()));
+    f.render_widget(paragraph, area);
+
+    if let InputMode::Insert = app.input_mode() {
+        f.set_cursor(area.x + input.width() as u16 + 1, area.y + 1);
+    }
+}
+
+///Bottom line, shows the command being typed or what the app is doing
+fn render_status_line<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    if let (InputMode::Search, Some(search)) = (app.input_mode(), app.get_search()) {
+        let prompt = format!("/{}", search.pattern);
+        let note = match search.error() {
+            Some(err) => Span::styled(format!("  {}", err), Style::default().fg(Color::Red)),
+            None if search.pattern.is_empty() => Span::raw(""),
+            None => {
+                let count = app.search_matches().len();
+                Span::styled(format!("  {} matches", count), Style::default().fg(Color::DarkGray))
+            }
+        };
+        f.render_widget(Paragraph::new(Spans::from(vec![Span::raw(prompt.clone()), note])), area);
+        f.set_cursor(area.x + prompt.width() as u16, area.y);
+        return;
+    }
+    if app.command_active() {
+        let command = app.get_command();
+        let style = match app.command_status() {
+            CommandStatus::Okay => Style::default(),
+            CommandStatus::Error => Style::default().fg(Color::Red),
+        };
+
+        f.render_widget(Paragraph::new(Span::styled(command.clone(), style)), area);
+
+        if let InputMode::Command = app.input_mode() {
+            f.set_cursor(area.x + command.width() as u16, area.y);
+        }
+        return;
+    }
+
+    let mode = match app.input_mode() {
+        InputMode::Normal => "NORMAL",
+        InputMode::Insert => "INSERT",
+        InputMode::Command => "COMMAND",
+        InputMode::Search => "SEARCH",
+    };
+
+    let mut status = vec![
+        Span::styled(
+            format!(" {} ", mode),
+            Style::default().add_modifier(Modifier::BOLD).fg(Color::Black).bg(Color::Blue),
+        ),
+        Span::styled(
+            format!(" {} · {} ", app.get_provider_name(), app.get_selected_model()),
+            Style::default().fg(Color::Magenta),
+        ),
+    ];
+
+    if let Some(usage) = app.session_usage() {
+        status.push(Span::styled(format!("Σ {} tokens ", usage.total_tokens), Style::default().fg(Color::DarkGray)));
+    }
+    if let Some(cost) = app.session_cost() {
+        status.push(Span::styled(format!("${:.4} ", cost), Style::default().fg(Color::Green)));
+    }
+
+    if let InputMode::Insert = app.input_mode() {
+        let budget = app.budget();
+        let style = match budget.fits() {
+            true => Style::default().fg(Color::DarkGray),
+            false => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        };
+        status.push(Span::styled(
+            format!("{}+{}/{} ctx ", budget.prompt, budget.max_tokens, budget.window),
+            style,
+        ));
+    }
+
+    status.push(Span::styled(app.get_status().clone(), Style::default().fg(Color::Yellow)));
+    let status = Spans::from(status);
+
+    f.render_widget(Paragraph::new(status), area);
+}
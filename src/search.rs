@@ -0,0 +1,119 @@
+//Searching the transcript with a regex, matches highlighted and jumped between
+
+use regex::{Regex, RegexBuilder};
+
+
+///A match in the transcript: the message, the line of its body and the bytes in that line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Match {
+    pub message: usize,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+///What `/` is looking for
+pub struct Search {
+    pub pattern: String,
+    ///None while the pattern is empty
+    regex: Result<Option<Regex>, String>,
+    ///Index of the match jumped to
+    pub current: usize,
+    ///Scroll before searching, gone back to when the search is given up
+    pub origin: usize,
+}
+
+///The pattern ignores case unless it has capitals, like smartcase in vim
+fn compile(pattern: &str) -> Result<Option<Regex>, String> {
+    if pattern.is_empty() {
+        return Ok(None)
+    }
+    let result = RegexBuilder::new(pattern)
+        .case_insensitive(!pattern.chars().any(char::is_uppercase))
+        .build();
+    match result {
+        Ok(regex) => return Ok(Some(regex)),
+        //the last line says what is wrong, the others repeat the pattern
+        Err(err) => {
+            let reason = err.to_string().lines().last().unwrap_or("bad pattern").trim().to_string();
+            return Err(reason.trim_start_matches("error: ").to_string())
+        }
+    }
+}
+
+impl Search {
+    pub fn new(origin: usize) -> Search {
+        return Search { pattern: String::new(), regex: Ok(None), current: 0, origin }
+    }
+
+    ///A search for any of `words` as they are, not as a regex
+    pub fn for_words(words: &[&str], origin: usize) -> Search {
+        let pattern = words.iter().map(|word| regex::escape(word)).collect::<Vec<String>>().join("|");
+        let regex = compile(&pattern);
+        return Search { pattern, regex, current: 0, origin }
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.pattern.push(c);
+        self.regex = compile(&self.pattern);
+    }
+
+    pub fn pop(&mut self) {
+        self.pattern.pop();
+        self.regex = compile(&self.pattern);
+    }
+
+    ///Why the pattern isn't a regex, while it is being typed
+    pub fn error(&self) -> Option<&str> {
+        return self.regex.as_ref().err().map(|err| err.as_str())
+    }
+
+    ///Matches in the lines of `bodies`, in order. Empty matches are left out
+    pub fn find<'a>(&self, bodies: impl Iterator<Item = &'a str>) -> Vec<Match> {
+        let regex = match &self.regex {
+            Ok(Some(regex)) => regex,
+            _ => return Vec::new(),
+        };
+        let mut matches = Vec::new();
+        for (message, body) in bodies.enumerate() {
+            for (line, text) in body.split('\n').enumerate() {
+                matches.extend(regex
+                    .find_iter(text)
+                    .filter(|found| !found.as_str().is_empty())
+                    .map(|found| Match { message, line, start: found.start(), end: found.end() }));
+            }
+        }
+        return matches
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(pattern: &str) -> Search {
+        let mut search = Search::new(0);
+        pattern.chars().for_each(|c| search.push(c));
+        return search
+    }
+
+    #[test]
+    fn matches_are_found_per_line_ignoring_case_without_capitals() {
+        let bodies = ["Hello there", "first line\nsay hello\nhello hello"];
+        let found = search("hel+o").find(bodies.iter().copied());
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[1], Match { message: 1, line: 1, start: 4, end: 9 });
+        assert_eq!(search("Hello").find(bodies.iter().copied()).len(), 1);
+        assert!(search("x*").find(bodies.iter().copied()).is_empty());
+    }
+
+    #[test]
+    fn bad_patterns_say_why_and_match_nothing() {
+        let mut search = search("(hello");
+        assert!(search.error().unwrap().contains("unclosed group"));
+        assert!(search.find(["hello"].into_iter()).is_empty());
+        search.push(')');
+        assert!(search.error().is_none());
+    }
+}
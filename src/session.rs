@@ -0,0 +1,109 @@
+use log::info;
+
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::Message;
+use crate::params::Profile;
+
+
+///Instructions given to the model ahead of the conversation
+#[derive(Deserialize, Debug, Clone)]
+pub struct Persona {
+    pub system: String,
+}
+
+///A conversation as written to `<sessions dir>/<name>.json`
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Session {
+    ///Persona the session was using, when the system prompt came from one
+    pub persona: Option<String>,
+    pub system: Option<String>,
+    pub model: String,
+    ///Parameter profile chosen with `:profile`
+    pub profile: Option<String>,
+    ///Parameters set by hand, over the profile
+    pub params: Profile,
+    pub messages: Vec<Message>,
+}
+
+///Session names are file names, no paths
+fn session_path(dir: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("{} is not a session name", name).into())
+    }
+    return Ok(dir.join(format!("{}.json", name)))
+}
+
+impl Session {
+    pub fn save(&self, dir: &Path, name: &str) -> Result<(), Box<dyn Error>> {
+        let path = session_path(dir, name)?;
+        fs::create_dir_all(dir)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        info!("Saved session to {}", path.display());
+        return Ok(())
+    }
+
+    pub fn load(dir: &Path, name: &str) -> Result<Session, Box<dyn Error>> {
+        let path = session_path(dir, name)?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err).into()),
+        };
+        return Ok(serde_json::from_str::<Session>(&contents)?)
+    }
+}
+
+///Names of the sessions saved in `dir`, sorted. Dot files are gpterm's own, the search index
+pub fn list(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("json") => path.file_stem()?.to_str().map(String::from),
+                _ => None,
+            }
+        })
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    names.sort();
+    return names
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::MessageType;
+
+    #[test]
+    fn sessions_round_trip() {
+        let dir = std::env::temp_dir().join(format!("gpterm-sessions-{}", std::process::id()));
+        let session = Session {
+            persona: Some("rust-reviewer".to_string()),
+            system: Some("review rust".to_string()),
+            model: "text-davinci-003".to_string(),
+            profile: None,
+            params: Profile { temperature: Some(0.5), ..Profile::default() },
+            messages: vec![Message::from("tester".to_string(), "hi".to_string(), MessageType::Query)],
+        };
+        session.save(&dir, "review").unwrap();
+
+        let loaded = Session::load(&dir, "review").unwrap();
+        assert_eq!(loaded.persona.as_deref(), Some("rust-reviewer"));
+        assert_eq!(loaded.messages[0].get_body(), "hi");
+        assert_eq!(loaded.params.temperature, Some(0.5));
+        assert_eq!(list(&dir), vec!["review"]);
+        assert!(session.save(&dir, "../elsewhere").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use log::{debug, warn};
+
+use std::{path::Path, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+
+///Settings of the `[shell]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShellConfig {
+    ///Shell commands run with, `$SHELL` when unset and `sh` without it
+    pub program: Option<String>,
+    ///Output kept from a command, the rest is cut
+    pub max_output_bytes: usize,
+    ///Whether output goes along with the next question
+    pub context: bool,
+}
+
+impl Default for ShellConfig {
+    fn default() -> ShellConfig {
+        ShellConfig { program: None, max_output_bytes: 16 * 1024, context: true }
+    }
+}
+
+impl ShellConfig {
+    pub fn program(&self) -> String {
+        match &self.program {
+            Some(program) => return program.clone(),
+            None => return std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string()),
+        }
+    }
+}
+
+///What a command printed and how it ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    ///Stdout then stderr
+    pub text: String,
+    ///Exit code, None when killed by a signal or it couldn't start
+    pub code: Option<i32>,
+}
+
+///Cuts `text` to at most `limit` bytes, on a char boundary
+pub fn truncate(mut text: String, limit: usize) -> String {
+    if text.len() <= limit {
+        return text
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = text.len() - end;
+    text.truncate(end);
+    text.push_str(&format!("\n[{} more bytes cut]", cut));
+    return text
+}
+
+///Runs `command` with `program -c`, nothing comes from stdin
+pub async fn run(program: &str, command: &str, limit: usize) -> Output {
+    debug!("Running `{}` with {}", command, program);
+    let mut process = Command::new(program);
+    process.arg("-c").arg(command);
+    return capture(process, program, limit).await
+}
+
+///Runs `script` with `interpreter`, which may carry arguments of its own (`deno run`).
+///No shell in between, so the path is passed as it is
+pub async fn run_script(interpreter: &str, script: &Path, limit: usize) -> Output {
+    debug!("Running {} with {}", script.display(), interpreter);
+    let mut words = interpreter.split_whitespace();
+    let program = words.next().unwrap_or(interpreter);
+    let mut process = Command::new(program);
+    process.args(words).arg(script);
+    return capture(process, program, limit).await
+}
+
+async fn capture(mut process: Command, program: &str, limit: usize) -> Output {
+    let output = match process.stdin(Stdio::null()).output().await {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("Couldn't run {}: {}", program, err);
+            return Output { text: format!("couldn't run {}: {}", program, err), code: None }
+        }
+    };
+
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.is_empty() {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&stderr);
+    }
+    return Output { text: truncate(text.trim_end().to_string(), limit), code: output.status.code() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn output_and_exit_code_are_captured() {
+        let output = run("sh", "echo out; echo err >&2; exit 3", 1024).await;
+        assert_eq!(output, Output { text: "out\nerr".to_string(), code: Some(3) });
+
+        let output = run("sh", "printf 'ééé'", 3).await;
+        assert_eq!(output.text, "é\n[4 more bytes cut]");
+    }
+
+    #[tokio::test]
+    async fn scripts_run_without_a_shell() {
+        let dir = std::env::temp_dir().join(format!("gpterm shell $(echo x) {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("block.sh");
+        std::fs::write(&script, "echo \"$0\"").unwrap();
+
+        let output = run_script("sh", &script, 1024).await;
+        assert_eq!(output, Output { text: script.display().to_string(), code: Some(0) });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use log::{debug, warn};
+
+use std::{collections::BTreeMap, error::Error, fs, path::Path, process::Command};
+
+
+///Prompt skeleton read from `<templates dir>/<name>.md` or `<name>.txt`.
+///`{{name}}` is replaced by a variable, `{{file:path}}` by the file's contents
+///and `{{cmd:command}}` by what the command prints
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub text: String,
+}
+
+///What goes between `{{` and `}}`
+#[derive(Debug, PartialEq)]
+enum Placeholder<'a> {
+    Variable(&'a str),
+    File(&'a str),
+    Command(&'a str),
+}
+
+///Splits `text` in literal pieces and placeholders, unclosed braces stay literal
+fn pieces(text: &str) -> Vec<Result<&str, Placeholder<'_>>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        pieces.push(Ok(&rest[..start]));
+
+        let inner = rest[start + 2..end].trim();
+        let placeholder = match inner.split_once(':') {
+            Some(("file", path)) => Placeholder::File(path.trim()),
+            Some(("cmd", command)) => Placeholder::Command(command.trim()),
+            _ => Placeholder::Variable(inner),
+        };
+        pieces.push(Err(placeholder));
+        rest = &rest[end + 2..];
+    }
+    pieces.push(Ok(rest));
+    return pieces
+}
+
+fn run(command: &str) -> Result<String, String> {
+    debug!("Running `{}` for a template", command);
+    let output = match Command::new("sh").arg("-c").arg(command).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("couldn't run `{}`: {}", command, err)),
+    };
+    if !output.status.success() {
+        return Err(format!(
+            "`{}` failed ({}): {}",
+            command, output.status, String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+impl Template {
+    pub fn load(dir: &Path, name: &str) -> Result<Template, Box<dyn Error>> {
+        //names stay inside the templates directory, like session names
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(format!("{} is not a template name", name).into())
+        }
+        for extension in ["md", "txt"] {
+            let path = dir.join(format!("{}.{}", name, extension));
+            if let Ok(text) = fs::read_to_string(&path) {
+                return Ok(Template { name: name.to_string(), text })
+            }
+        }
+        return Err(format!("no template {} in {}", name, dir.display()).into())
+    }
+
+    ///Variables the template needs, in the order they first show up
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables: Vec<String> = Vec::new();
+        for piece in pieces(&self.text) {
+            if let Err(Placeholder::Variable(name)) = piece {
+                if !variables.iter().any(|known| known == name) {
+                    variables.push(name.to_string());
+                }
+            }
+        }
+        return variables
+    }
+
+    ///Variables the template needs and `vars` doesn't have
+    pub fn missing(&self, vars: &BTreeMap<String, String>) -> Vec<String> {
+        return self.variables()
+            .into_iter()
+            .filter(|name| !vars.contains_key(name))
+            .collect()
+    }
+
+    ///The query the template makes, files are read and commands run now
+    pub fn render(&self, vars: &BTreeMap<String, String>) -> Result<String, String> {
+        let mut query = String::new();
+        for piece in pieces(&self.text) {
+            match piece {
+                Ok(literal) => query.push_str(literal),
+                Err(Placeholder::Variable(name)) => match vars.get(name) {
+                    Some(value) => query.push_str(value),
+                    None => return Err(format!("{} needs a value for {}", self.name, name)),
+                },
+                Err(Placeholder::File(path)) => match fs::read_to_string(path) {
+                    Ok(contents) => query.push_str(contents.trim_end()),
+                    Err(err) => return Err(format!("couldn't read {}: {}", path, err)),
+                },
+                Err(Placeholder::Command(command)) => query.push_str(&run(command)?),
+            }
+        }
+        return Ok(query.trim().to_string())
+    }
+}
+
+///Names of the templates in `dir`, sorted
+pub fn list(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Couldn't list templates in {}: {}", dir.display(), err);
+            return Vec::new()
+        }
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("md") | Some("txt") => path.file_stem()?.to_str().map(String::from),
+                _ => None,
+            }
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    return names
+}
+
+///`name=value` pairs, as given to `--var` and `:template`
+pub fn parse_var(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((name, value)) if !name.is_empty() => return Ok((name.to_string(), value.to_string())),
+        _ => return Err(format!("expected name=value, got {}", pair)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(text: &str) -> Template {
+        return Template { name: "test".to_string(), text: text.to_string() }
+    }
+
+    #[test]
+    fn variables_are_found_once_in_order() {
+        let review = template("Review this {{ lang }} code for {{focus}}, idiomatic {{lang}}:\n{{file:src/x.rs}} {{oops");
+        assert_eq!(review.variables(), vec!["lang", "focus"]);
+
+        let vars = BTreeMap::from([("lang".to_string(), "rust".to_string())]);
+        assert_eq!(review.missing(&vars), vec!["focus"]);
+    }
+
+    #[test]
+    fn files_and_commands_are_included() {
+        let path = std::env::temp_dir().join(format!("gpterm-template-{}.rs", std::process::id()));
+        fs::write(&path, "fn main() {}\n").unwrap();
+
+        let review = template(&format!(
+            "Review {{{{lang}}}}:\n{{{{file:{}}}}}\n{{{{cmd: echo from the shell}}}}",
+            path.display()
+        ));
+        let vars = BTreeMap::from([("lang".to_string(), "rust".to_string())]);
+        assert_eq!(review.render(&vars).unwrap(), "Review rust:\nfn main() {}\nfrom the shell");
+        assert!(review.render(&BTreeMap::new()).unwrap_err().contains("needs a value for lang"));
+        assert!(template("{{cmd: exit 3}}").render(&vars).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn names_stay_in_the_templates_directory() {
+        let dir = std::env::temp_dir().join(format!("gpterm-templates-{}", std::process::id()));
+        fs::create_dir_all(dir.join("inner")).unwrap();
+        fs::write(dir.join("outside.md"), "out of reach").unwrap();
+        fs::write(dir.join("inner").join("review.md"), "Review {{lang}}").unwrap();
+
+        let templates = dir.join("inner");
+        assert_eq!(Template::load(&templates, "review").unwrap().text, "Review {{lang}}");
+        for name in ["../outside", "..", "sub/review", "..\\outside", ""] {
+            assert!(Template::load(&templates, name).unwrap_err().to_string().contains("not a template name"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn vars_are_name_value_pairs() {
+        assert_eq!(parse_var("lang=rust=2021").unwrap(), ("lang".to_string(), "rust=2021".to_string()));
+        assert!(parse_var("lang").is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+
+
+///Tokens `text` takes for `model`, counted with the BPE of its family.
+///Models tiktoken doesn't know are counted like the chat models
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        Some(Tokenizer::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+        Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+        Some(Tokenizer::Cl100kBase) | None => tiktoken_rs::cl100k_base_singleton(),
+    };
+    let count = bpe.lock().encode_with_special_tokens(text).len();
+    return count
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_with_the_model_family() {
+        assert_eq!(count_tokens("text-davinci-003", "hello world"), 2);
+        assert_eq!(count_tokens("gpt-4", "hello world"), 2);
+        //p50k has tokens for runs of spaces, cl100k merges them differently
+        let indented = "        let x = 1;";
+        assert_ne!(count_tokens("text-davinci-003", indented), count_tokens("gpt2", indented));
+        assert_eq!(count_tokens("llama-2-7b", ""), 0);
+    }
+}
@@ -0,0 +1,329 @@
+//Local tools the model may call, each call approved by the user first
+
+use log::debug;
+
+use std::{fs, path::{Component, Path, PathBuf}, process::{Command, Stdio}};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::provider::{ToolCall, ToolSpec};
+
+
+///Settings of the `[tools]` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ToolsConfig {
+    ///Tools offered to the model, none by default.
+    ///`read_file`, `list_directory`, `run_command` and `search_text`
+    pub enabled: Vec<String>,
+    ///Programs `run_command` may start
+    pub allowed_commands: Vec<String>,
+    ///Answers made of calls in a row before gpterm stops feeding results back
+    pub max_rounds: usize,
+    ///Output kept from a call, the rest is cut
+    pub max_output_bytes: usize,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> ToolsConfig {
+        ToolsConfig {
+            enabled: Vec::new(),
+            allowed_commands: Vec::new(),
+            max_rounds: 8,
+            max_output_bytes: 16 * 1024,
+        }
+    }
+}
+
+///Tools gpterm knows, see `run`
+pub const TOOLS: [&str; 4] = ["read_file", "list_directory", "run_command", "search_text"];
+
+fn spec(name: &str) -> Option<ToolSpec> {
+    let (description, parameters) = match name {
+        "read_file" => (
+            "Reads a text file under the working directory",
+            json!({
+                "type": "object",
+                "properties": {"path": {"type": "string", "description": "Relative path of the file"}},
+                "required": ["path"]
+            }),
+        ),
+        "list_directory" => (
+            "Lists the entries of a directory under the working directory, directories end with a slash",
+            json!({
+                "type": "object",
+                "properties": {"path": {"type": "string", "description": "Relative path, . by default"}}
+            }),
+        ),
+        "run_command" => (
+            "Runs a program with arguments, no shell, and returns what it printed",
+            json!({
+                "type": "object",
+                "properties": {"command": {"type": "string", "description": "Program and arguments"}},
+                "required": ["command"]
+            }),
+        ),
+        "search_text" => (
+            "Finds the lines containing some text in the files under a directory",
+            json!({
+                "type": "object",
+                "properties": {
+                    "text": {"type": "string", "description": "Text to look for"},
+                    "path": {"type": "string", "description": "Relative directory, . by default"}
+                },
+                "required": ["text"]
+            }),
+        ),
+        _ => return None,
+    };
+    return Some(ToolSpec { name: name.to_string(), description: description.to_string(), parameters })
+}
+
+///Specs of the enabled tools, unknown names are an error
+pub fn specs(config: &ToolsConfig) -> Result<Vec<ToolSpec>, String> {
+    return config.enabled
+        .iter()
+        .map(|name| spec(name).ok_or_else(|| format!("unknown tool {}, gpterm has {}", name, TOOLS.join(", "))))
+        .collect()
+}
+
+fn arguments(call: &ToolCall) -> Result<Value, String> {
+    match serde_json::from_str::<Value>(&call.arguments) {
+        Ok(arguments) if arguments.is_object() => return Ok(arguments),
+        Ok(_) => return Err("arguments aren't a json object".to_string()),
+        Err(err) => return Err(format!("arguments aren't json: {}", err)),
+    }
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+    match arguments[name].as_str() {
+        Some(value) => return Ok(value),
+        None => return Err(format!("missing {}", name)),
+    }
+}
+
+///"read_file src/main.rs", how a call is shown
+pub fn describe(call: &ToolCall) -> String {
+    let arguments = match arguments(call) {
+        Ok(arguments) => arguments,
+        Err(_) => return format!("{} {}", call.name, call.arguments),
+    };
+    let values: Vec<String> = arguments
+        .as_object()
+        .into_iter()
+        .flat_map(|object| object.values())
+        .map(|value| match value.as_str() {
+            Some(text) => text.to_string(),
+            None => value.to_string(),
+        })
+        .collect();
+    return format!("{} {}", call.name, values.join(" ")).trim_end().to_string()
+}
+
+///`path` when it stays under the working directory, symlinks on the way followed
+fn confined(path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    let escapes = path.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(format!("{} is outside the working directory", path.display()))
+    }
+    let inside = match (path.canonicalize(), Path::new(".").canonicalize()) {
+        (Ok(resolved), Ok(cwd)) => resolved.starts_with(cwd),
+        (Err(err), _) | (_, Err(err)) => return Err(format!("couldn't reach {}: {}", path.display(), err)),
+    };
+    if !inside {
+        return Err(format!("{} is outside the working directory", path.display()))
+    }
+    return Ok(path.to_path_buf())
+}
+
+fn read_file(arguments: &Value) -> Result<String, String> {
+    let path = confined(string_argument(arguments, "path")?)?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => return Ok(contents),
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    }
+}
+
+fn list_directory(arguments: &Value) -> Result<String, String> {
+    let path = confined(arguments["path"].as_str().unwrap_or("."))?;
+    let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(err) => return Err(format!("couldn't list {}: {}", path.display(), err)),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.path().is_dir() {
+            true => format!("{}/", entry.file_name().to_string_lossy()),
+            false => entry.file_name().to_string_lossy().to_string(),
+        })
+        .collect();
+    names.sort();
+    return Ok(names.join("\n"))
+}
+
+fn run_command(arguments: &Value, config: &ToolsConfig) -> Result<String, String> {
+    let command = string_argument(arguments, "command")?;
+    let mut words = command.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return Err("empty command".to_string()),
+    };
+    if !config.allowed_commands.iter().any(|allowed| allowed == program) {
+        return Err(format!("{} isn't allowed, add it to [tools] allowed_commands", program))
+    }
+
+    debug!("Running `{}` for the model", command);
+    let output = match Command::new(program).args(words).stdin(Stdio::null()).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("couldn't run {}: {}", program, err)),
+    };
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    return Ok(format!("{}\n({})", text.trim_end(), output.status))
+}
+
+///Lines containing the text, as `path:line: text`, hidden files left out
+fn search_text(arguments: &Value, config: &ToolsConfig) -> Result<String, String> {
+    let text = string_argument(arguments, "text")?;
+    let root = confined(arguments["path"].as_str().unwrap_or("."))?;
+
+    let mut found: Vec<String> = Vec::new();
+    let mut size = 0;
+    let mut dirs = vec![root];
+    while let Some(dir) = dirs.pop() {
+        let mut entries: Vec<(PathBuf, fs::FileType)> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| Some((entry.path(), entry.file_type().ok()?)))
+                .collect(),
+            Err(err) => return Err(format!("couldn't list {}: {}", dir.display(), err)),
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, file_type) in entries {
+            let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            //symlinks may loop or point out of the working directory
+            if hidden || file_type.is_symlink() {
+                continue;
+            }
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            //binary files don't read as text
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            for (number, line) in contents.lines().enumerate() {
+                if !line.contains(text) {
+                    continue;
+                }
+                let hit = format!("{}:{}: {}", path.display(), number + 1, line.trim());
+                size += hit.len() + 1;
+                found.push(hit);
+                if size > config.max_output_bytes {
+                    return Ok(found.join("\n"))
+                }
+            }
+        }
+    }
+    if found.is_empty() {
+        return Ok(format!("no line contains {}", text))
+    }
+    return Ok(found.join("\n"))
+}
+
+///Runs `call` and returns what goes back to the model, failures included
+pub fn run(call: &ToolCall, config: &ToolsConfig) -> String {
+    let result = arguments(call).and_then(|arguments| {
+        if !config.enabled.contains(&call.name) {
+            return Err(format!("{} isn't an enabled tool", call.name))
+        }
+        match call.name.as_str() {
+            "read_file" => return read_file(&arguments),
+            "list_directory" => return list_directory(&arguments),
+            "run_command" => return run_command(&arguments, config),
+            "search_text" => return search_text(&arguments, config),
+            name => return Err(format!("unknown tool {}", name)),
+        }
+    });
+    let text = match result {
+        Ok(text) => text,
+        Err(err) => format!("error: {}", err),
+    };
+    return crate::shell::truncate(text, config.max_output_bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        return ToolCall { id: "call_0".to_string(), name: name.to_string(), arguments: arguments.to_string() }
+    }
+
+    fn config() -> ToolsConfig {
+        return ToolsConfig {
+            enabled: TOOLS.iter().map(|name| name.to_string()).collect(),
+            allowed_commands: vec!["echo".to_string()],
+            ..ToolsConfig::default()
+        }
+    }
+
+    #[test]
+    fn paths_stay_under_the_working_directory() {
+        let config = config();
+        assert!(run(&call("read_file", json!({"path": "Cargo.toml"})), &config).contains("[package]"));
+        assert!(run(&call("read_file", json!({"path": "/etc/passwd"})), &config).contains("outside the working directory"));
+        assert!(run(&call("list_directory", json!({"path": "src/../.."})), &config).starts_with("error:"));
+        assert!(run(&call("list_directory", json!({})), &config).contains("src/"));
+    }
+
+    #[test]
+    fn only_allowed_commands_run() {
+        let config = config();
+        assert_eq!(run(&call("run_command", json!({"command": "echo hi; rm -rf x"})), &config), "hi; rm -rf x\n(exit status: 0)");
+        assert!(run(&call("run_command", json!({"command": "rm -rf x"})), &config).contains("rm isn't allowed"));
+
+        let disabled = ToolsConfig { enabled: vec!["read_file".to_string()], ..config };
+        assert!(run(&call("run_command", json!({"command": "echo hi"})), &disabled).contains("isn't an enabled tool"));
+    }
+
+    #[test]
+    fn text_is_searched_with_line_numbers() {
+        let found = run(&call("search_text", json!({"text": "TOOLS: [&str", "path": "src"})), &config());
+        assert!(found.starts_with("src/tools.rs:"));
+        assert_eq!(describe(&call("search_text", json!({"text": "fn main"}))), "search_text fn main");
+        assert!(specs(&ToolsConfig { enabled: vec!["nope".to_string()], ..ToolsConfig::default() }).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_lead_nowhere_outside() {
+        let dir = PathBuf::from(format!("target/gpterm-tools-{}", std::process::id()));
+        let outside = std::env::temp_dir().join(format!("gpterm-tools-outside-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(dir.join("inside.txt"), "needle inside").unwrap();
+        fs::write(outside.join("secret.txt"), "needle outside").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("loop")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+
+        let path = dir.display().to_string();
+        let found = run(&call("search_text", json!({"text": "needle", "path": path})), &config());
+        assert_eq!(found, format!("{}/inside.txt:1: needle inside", path));
+        //symlinks pointing out are refused by every tool
+        let through = format!("{}/out", path);
+        for (tool, path) in [("search_text", through.clone()), ("list_directory", through.clone()), ("read_file", format!("{}/secret.txt", through))] {
+            let found = run(&call(tool, json!({"text": "needle", "path": path})), &config());
+            assert!(found.contains("outside the working directory"), "{}: {}", tool, found);
+        }
+        assert_eq!(run(&call("read_file", json!({"path": format!("{}/inside.txt", path)})), &config()), "needle inside");
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+}